@@ -3,17 +3,36 @@ PROJ_ROOT="$(dirname $(dirname ${BASH_SOURCE:-$0}))"
 cd "${PROJ_ROOT}"
 
 PATH_TO_EFI="$1"
+shift
+QEMU_CPU="${QEMU_CPU:-qemu64}"
+# Options passed after the EFI binary (e.g. cargo run -- --pcid):
+#   --pcid: use -cpu max, since qemu64 supports neither PCID nor INVPCID
+#   --la57: use -cpu max,+la57 to run the kernel with 5-level paging
+for arg in "$@"; do
+  case "${arg}" in
+    --pcid) QEMU_CPU=max ;;
+    --la57) QEMU_CPU=max,+la57 ;;
+    *) echo "Unknown option: ${arg}" >&2; exit 1 ;;
+  esac
+done
+# e.g. SWAP_IMG=swap.img to use a virtio-blk device as the swap area
+SWAP_ARGS=()
+if [ -n "${SWAP_IMG}" ]; then
+  SWAP_ARGS=(-drive if=none,format=raw,file=${SWAP_IMG},id=swap -device virtio-blk-pci,drive=swap)
+fi
 rm -rf mnt
 mkdir -p mnt/EFI/BOOT/
 cp ${PATH_TO_EFI} mnt/EFI/BOOT/BOOTX64.EFI
 set +e
 qemu-system-x86_64 \
   -m 4G \
+  -cpu ${QEMU_CPU} \
   -bios third_party/ovmf/RELEASEX64_OVMF.fd \
   -drive format=raw,file=fat:rw:mnt \
   -chardev stdio,id=char_com1,mux=on,logfile=log/com1.txt \
   -serial chardev:char_com1 \
-  -device isa-debug-exit,iobase=0xf4,iosize=0x01
+  -device isa-debug-exit,iobase=0xf4,iosize=0x01 \
+  "${SWAP_ARGS[@]}"
 RETCODE=$?
 set -e
 if [ $RETCODE -eq 0 ]; then
@@ -0,0 +1,154 @@
+extern crate alloc;
+
+use crate::info;
+use crate::result::Result;
+use crate::x86::current_page_table;
+use crate::x86::flush_tlb_page;
+use crate::x86::register_exception_handler;
+use crate::x86::ExceptionInfo;
+use crate::x86::ATTR_PRESENT;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PF_ERROR_PRESENT;
+use crate::x86::PF_ERROR_WRITE;
+use crate::x86::RFLAGS_TF;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ops::Range;
+
+// ページアクセストレーサ（Linuxのmmiotraceのようなもの）
+// 対象のページをわざと非presentにしておき、#PFでアクセスを記録したあと
+// 一時的にマップし直してTFで1命令だけ実行させ、#DBで再び非presentに戻す
+
+// Number of events kept for take_trace_events()
+const MAX_EVENTS: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub addr: u64,
+    pub rip: u64,
+    pub write: bool,
+}
+
+struct AccessTracer {
+    // Original entries of the traced pages
+    pages: BTreeMap<u64, u64>,
+    // Pages mapped temporarily for the instruction being single-stepped
+    stepping: Vec<u64>,
+    events: Vec<TraceEvent>,
+    handlers_registered: bool,
+}
+
+impl AccessTracer {
+    fn set_present(&self, virt: u64, present: bool) -> Result<()> {
+        let pte = self.pages.get(&virt).ok_or("Page is not traced")?;
+        let value = if present { *pte } else { pte & !ATTR_PRESENT };
+        unsafe { (*current_page_table()).write_pte(virt, value)? };
+        flush_tlb_page(virt);
+        Ok(())
+    }
+}
+
+struct AccessTracerCell {
+    inner: RefCell<AccessTracer>,
+}
+
+unsafe impl Sync for AccessTracerCell {}
+
+static TRACER: AccessTracerCell = AccessTracerCell {
+    inner: RefCell::new(AccessTracer {
+        pages: BTreeMap::new(),
+        stepping: Vec::new(),
+        events: Vec::new(),
+        handlers_registered: false,
+    }),
+};
+
+fn trace_page_fault_handler(e: &mut ExceptionInfo) -> bool {
+    if e.error_code & PF_ERROR_PRESENT != 0 {
+        return false;
+    }
+    let Ok(mut tracer) = TRACER.inner.try_borrow_mut() else {
+        return false;
+    };
+    let virt = e.fault_addr & !(PAGE_SIZE as u64 - 1);
+    if !tracer.pages.contains_key(&virt) || tracer.set_present(virt, true).is_err() {
+        return false;
+    }
+    let event = TraceEvent {
+        addr: e.fault_addr,
+        rip: e.ctx.rip,
+        write: e.error_code & PF_ERROR_WRITE != 0,
+    };
+    info!(
+        "trace: {} {:#018X} @ RIP {:#018X}",
+        if event.write { "W" } else { "R" },
+        event.addr,
+        event.rip
+    );
+    if tracer.events.len() < MAX_EVENTS {
+        tracer.events.push(event);
+    }
+    // An instruction may touch multiple traced pages before it completes
+    tracer.stepping.push(virt);
+    e.ctx.rflags |= RFLAGS_TF;
+    true
+}
+
+fn trace_debug_handler(e: &mut ExceptionInfo) -> bool {
+    let Ok(mut tracer) = TRACER.inner.try_borrow_mut() else {
+        return false;
+    };
+    if tracer.stepping.is_empty() {
+        return false;
+    }
+    for virt in core::mem::take(&mut tracer.stepping) {
+        // The page may be untraced while stepping
+        let _ = tracer.set_present(virt, false);
+    }
+    e.ctx.rflags &= !RFLAGS_TF;
+    true
+}
+
+/// Starts tracing accesses to the pages in range, which should be mapped with
+/// 4KiB pages in the current page table. Should be called after
+/// init_exceptions(). Pages used by the exception handlers (e.g. the heap and
+/// stacks) can not be traced.
+pub fn trace_range(range: Range<u64>) -> Result<()> {
+    let mut tracer = TRACER.inner.borrow_mut();
+    if !tracer.handlers_registered {
+        register_exception_handler(14, trace_page_fault_handler)?;
+        register_exception_handler(1, trace_debug_handler)?;
+        tracer.handlers_registered = true;
+    }
+    let table = unsafe { &mut *current_page_table() };
+    let start = range.start & !(PAGE_SIZE as u64 - 1);
+    for virt in (start..range.end).step_by(PAGE_SIZE) {
+        if tracer.pages.contains_key(&virt) {
+            continue;
+        }
+        let pte = table.read_pte(virt)?;
+        if pte & ATTR_PRESENT == 0 {
+            return Err("trace_range: page is not mapped");
+        }
+        tracer.pages.insert(virt, pte);
+        tracer.set_present(virt, false)?;
+    }
+    Ok(())
+}
+
+/// Stops tracing all the pages and restores their mappings.
+pub fn untrace_all() -> Result<()> {
+    let mut tracer = TRACER.inner.borrow_mut();
+    let pages: Vec<u64> = tracer.pages.keys().copied().collect();
+    for virt in pages {
+        tracer.set_present(virt, true)?;
+        tracer.pages.remove(&virt);
+    }
+    Ok(())
+}
+
+/// Returns the recorded accesses and clears them.
+pub fn take_trace_events() -> Vec<TraceEvent> {
+    core::mem::take(&mut TRACER.inner.borrow_mut().events)
+}
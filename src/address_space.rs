@@ -0,0 +1,333 @@
+extern crate alloc;
+
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::kpti::free_user_table;
+use crate::kpti::kpti_cr3;
+use crate::kpti::new_user_table;
+use crate::kpti::set_kpti_cr3;
+use crate::kpti::USER_PML4_INDICES;
+use crate::result::Result;
+use crate::rmap::rmap_forget_root;
+use crate::thp::num_huge_pages;
+use crate::x86::cr3_value_with_pcid;
+use crate::x86::flush_tlb_all;
+use crate::x86::flush_tlb_for_pcid;
+use crate::x86::flush_tlb_page;
+use crate::x86::flush_tlb_page_for_pcid;
+use crate::x86::is_la57_enabled;
+use crate::x86::is_pcid_enabled;
+use crate::x86::read_cr3;
+use crate::x86::write_cr3_with_pcid;
+use crate::x86::PageMapper;
+use crate::x86::ENTRY_ADDR_MASK;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use crate::x86::PML5;
+use alloc::boxed::Box;
+use core::cell::RefCell;
+use core::ops::Range;
+
+// アドレス空間（PML4）とそのPCIDの管理
+// PCIDが有効な場合、CR3の切り替え時にTLBをフラッシュせずに済む
+// KPTIで分離されたアドレス空間は、ユーザーモード用のPML4と、そのPCIDも持つ
+// RAMの恒等マッピング（PML4エントリ0）とカーネル空間の下位のテーブルはカーネルの
+// ページテーブルと共有する。ユーザー空間（USER_PML4_INDICES）のエントリは作成時に
+// 空にするので、その下のテーブルはすべてそのアドレス空間が作ったもので、破棄時に解放する
+// 5レベルページングでは、PML5エントリ0の下のPML4が恒等マッピングとユーザー空間の
+// 両方をマップするので、このPML4も複製してアドレス空間ごとに持つ
+
+// Under 5-level paging, the user space is the PML5 entries 1..256 and the
+// entries of the PML4 under the PML5 entry 0 except the identity mapping
+const LA57_USER_PML5_INDICES: Range<usize> = 1..256;
+const LA57_USER_PML4_INDICES: Range<usize> = 1..512;
+
+// PCID 0 is used by the kernel page table created in init_paging()
+const NUM_PCIDS: usize = 4096;
+
+// Flushing more (page, PCID) pairs than this one by one is slower than
+// flushing the whole TLB
+const FLUSH_ALL_THRESHOLD: u64 = 64;
+
+struct PcidBitmap {
+    used: [u64; NUM_PCIDS / 64],
+}
+
+pub struct PcidAllocator {
+    inner: RefCell<PcidBitmap>,
+}
+
+unsafe impl Sync for PcidAllocator {}
+
+static PCID_ALLOCATOR: PcidAllocator = PcidAllocator {
+    inner: RefCell::new(PcidBitmap {
+        used: {
+            let mut used = [0u64; NUM_PCIDS / 64];
+            used[0] = 1;
+            used
+        },
+    }),
+};
+
+impl PcidAllocator {
+    fn alloc(&self) -> Result<u16> {
+        let mut inner = self.inner.borrow_mut();
+        let (i, word) = inner
+            .used
+            .iter_mut()
+            .enumerate()
+            .find(|(_, w)| **w != !0)
+            .ok_or("Out of PCIDs")?;
+        let bit = word.trailing_ones() as usize;
+        *word |= 1 << bit;
+        Ok((i * 64 + bit) as u16)
+    }
+    fn free(&self, pcid: u16) {
+        let mut inner = self.inner.borrow_mut();
+        let pcid = pcid as usize;
+        inner.used[pcid / 64] &= !(1 << (pcid % 64));
+    }
+    // Calls f for each PCIDs in use, including the kernel's one
+    fn for_each_used(&self, mut f: impl FnMut(u16)) {
+        let inner = self.inner.borrow();
+        for (i, word) in inner.used.iter().enumerate() {
+            let mut bits = *word;
+            while bits != 0 {
+                f((i * 64 + bits.trailing_zeros() as usize) as u16);
+                // Clears the lowest set bit
+                bits &= bits - 1;
+            }
+        }
+    }
+    fn num_used(&self) -> u64 {
+        let inner = self.inner.borrow();
+        inner.used.iter().map(|w| w.count_ones() as u64).sum()
+    }
+}
+
+/// Flushes the TLB entry for virt in all address spaces. Use this after
+/// changing mappings shared by all address spaces (e.g. the kernel part).
+pub fn flush_tlb_page_all_address_spaces(virt: u64) {
+    if is_pcid_enabled() {
+        PCID_ALLOCATOR.for_each_used(|pcid| flush_tlb_page_for_pcid(pcid, virt));
+    } else {
+        flush_tlb_page(virt)
+    }
+}
+
+/// Flushes the TLB entries for range in all address spaces. The whole TLB
+/// of all the PCIDs is flushed instead if range is large.
+pub fn flush_tlb_range_all_address_spaces(range: Range<u64>) {
+    let num_pages = range
+        .end
+        .saturating_sub(range.start)
+        .div_ceil(PAGE_SIZE as u64);
+    let num_pcids = if is_pcid_enabled() {
+        PCID_ALLOCATOR.num_used()
+    } else {
+        1
+    };
+    if num_pages * num_pcids > FLUSH_ALL_THRESHOLD {
+        flush_tlb_all();
+        return;
+    }
+    for virt in range.step_by(PAGE_SIZE) {
+        flush_tlb_page_all_address_spaces(virt);
+    }
+}
+
+/// Root table of an address space, which depends on the paging mode
+pub enum PageTableRoot {
+    Level4(Box<PML4>),
+    Level5(Box<PML5>),
+}
+
+impl PageTableRoot {
+    // Returns the value to be loaded into CR3
+    fn as_ptr(&self) -> *const PML4 {
+        match self {
+            PageTableRoot::Level4(t) => t.as_ref() as *const PML4,
+            PageTableRoot::Level5(t) => t.as_ref() as *const PML5 as *const PML4,
+        }
+    }
+}
+
+// The table loaded while user code runs in an isolated address space
+struct UserTable {
+    // Entries for USER_SPACE are synchronized on activate()
+    root: RefCell<Box<PML4>>,
+    pcid: u16,
+}
+
+pub struct AddressSpace {
+    root: PageTableRoot,
+    pcid: u16,
+    user: Option<UserTable>,
+}
+
+impl AddressSpace {
+    /// Creates a new address space which shares the mappings of the current
+    /// page table except the user space, which is empty.
+    pub fn new() -> Result<Self> {
+        let pcid = PCID_ALLOCATOR.alloc()?;
+        let root = if is_la57_enabled() {
+            let mut root = unsafe { (*(read_cr3() as *const PML5)).clone_top_level() };
+            root.clear_entries(LA57_USER_PML5_INDICES);
+            // The PML4 under the entry 0 maps both the identity mapping and the
+            // user space, so it is private to this space
+            match root.clone_pml4(0) {
+                Ok(pml4) => pml4.clear_entries(LA57_USER_PML4_INDICES),
+                Err(e) => {
+                    PCID_ALLOCATOR.free(pcid);
+                    return Err(e);
+                }
+            }
+            PageTableRoot::Level5(root)
+        } else {
+            let mut root = unsafe { (*read_cr3()).clone_top_level() };
+            root.clear_entries(USER_PML4_INDICES);
+            PageTableRoot::Level4(root)
+        };
+        if is_pcid_enabled() {
+            // The PCID may have stale entries if it was used by a dropped space
+            flush_tlb_for_pcid(pcid);
+        }
+        Ok(Self {
+            root,
+            pcid,
+            user: None,
+        })
+    }
+    /// Creates a new address space isolated by KPTI, which has another table
+    /// for user mode in addition to the one created by new().
+    pub fn new_isolated() -> Result<Self> {
+        if is_la57_enabled() {
+            return Err("KPTI is not supported with 5-level paging");
+        }
+        let mut space = Self::new()?;
+        let PageTableRoot::Level4(kernel) = &space.root else {
+            unreachable!()
+        };
+        let root = new_user_table(kernel)?;
+        let pcid = match PCID_ALLOCATOR.alloc() {
+            Ok(pcid) => pcid,
+            Err(e) => {
+                free_user_table(root);
+                return Err(e);
+            }
+        };
+        if is_pcid_enabled() {
+            flush_tlb_for_pcid(pcid);
+        }
+        space.user = Some(UserTable {
+            root: RefCell::new(root),
+            pcid,
+        });
+        Ok(space)
+    }
+    pub fn is_isolated(&self) -> bool {
+        self.user.is_some()
+    }
+    /// Returns the table used in user mode if the space is isolated. Note
+    /// that the user space part is updated on activate().
+    pub fn user_page_table(&self) -> Option<&dyn PageMapper> {
+        self.user.as_ref().map(
+            |user| unsafe { &*(user.root.borrow().as_ref() as *const PML4) } as &dyn PageMapper,
+        )
+    }
+    pub fn user_pcid(&self) -> Option<u16> {
+        self.user.as_ref().map(|user| user.pcid)
+    }
+    pub fn root(&self) -> &PageTableRoot {
+        &self.root
+    }
+    pub fn page_table(&self) -> &dyn PageMapper {
+        match &self.root {
+            PageTableRoot::Level4(t) => t.as_ref(),
+            PageTableRoot::Level5(t) => t.as_ref(),
+        }
+    }
+    pub fn page_table_mut(&mut self) -> &mut dyn PageMapper {
+        match &mut self.root {
+            PageTableRoot::Level4(t) => t.as_mut(),
+            PageTableRoot::Level5(t) => t.as_mut(),
+        }
+    }
+    /// Number of 2MiB pages collapsed by the THP in this address space
+    pub fn num_huge_pages(&self) -> usize {
+        num_huge_pages(self.root.as_ptr() as u64)
+    }
+    pub fn pcid(&self) -> u16 {
+        self.pcid
+    }
+    pub fn is_active(&self) -> bool {
+        core::ptr::eq(read_cr3(), self.root.as_ptr())
+    }
+    /// Switches CR3 to this address space. TLB entries of this space are
+    /// preserved if PCID is enabled.
+    pub fn activate(&self) {
+        unsafe { write_cr3_with_pcid(self.root.as_ptr(), self.pcid, true) }
+        let Some(user) = &self.user else {
+            set_kpti_cr3(0, 0);
+            return;
+        };
+        let mut user_root = user.root.borrow_mut();
+        user_root.share_entries(unsafe { &*self.root.as_ptr() }, USER_PML4_INDICES);
+        set_kpti_cr3(
+            cr3_value_with_pcid(self.root.as_ptr(), self.pcid, true),
+            cr3_value_with_pcid(user_root.as_ref(), user.pcid, true),
+        );
+    }
+    /// Flushes the TLB entry for virt in this space even if it is not active.
+    pub fn flush_tlb_page(&self, virt: u64) {
+        if is_pcid_enabled() || self.is_active() {
+            flush_tlb_page_for_pcid(self.pcid, virt);
+            if let Some(pcid) = self.user_pcid() {
+                flush_tlb_page_for_pcid(pcid, virt);
+            }
+        }
+    }
+    pub fn flush_tlb(&self) {
+        if is_pcid_enabled() || self.is_active() {
+            flush_tlb_for_pcid(self.pcid);
+            if let Some(pcid) = self.user_pcid() {
+                flush_tlb_for_pcid(pcid);
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Dropping the active address space");
+        if is_pcid_enabled() {
+            flush_tlb_for_pcid(self.pcid);
+        }
+        PCID_ALLOCATOR.free(self.pcid);
+        // The tables for the user space were populated by this space, and the
+        // others are shared with the kernel page table
+        rmap_forget_root(self.root.as_ptr() as u64);
+        match &mut self.root {
+            PageTableRoot::Level4(root) => root.free_lower_tables(USER_PML4_INDICES),
+            PageTableRoot::Level5(root) => {
+                if let Ok(pml4) = root.pml4_mut(0) {
+                    pml4.clear_entries(0..LA57_USER_PML4_INDICES.start);
+                }
+                root.free_lower_tables(0..LA57_USER_PML5_INDICES.end);
+            }
+        }
+        FRAME_ALLOCATOR.set_frame_state(self.root.as_ptr() as u64, FrameState::Heap);
+        if let Some(user) = self.user.take() {
+            if kpti_cr3()
+                .is_some_and(|(kernel, _)| kernel & ENTRY_ADDR_MASK == self.root.as_ptr() as u64)
+            {
+                set_kpti_cr3(0, 0);
+            }
+            if is_pcid_enabled() {
+                flush_tlb_for_pcid(user.pcid);
+            }
+            PCID_ALLOCATOR.free(user.pcid);
+            free_user_table(user.root.into_inner());
+        }
+    }
+}
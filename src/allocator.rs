@@ -1,6 +1,8 @@
 extern crate alloc;
 
-use crate::uefi::{EfiMemoryDescriptor, EfiMemoryType, MemoryMapHolder};
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::x86::PAGE_SIZE;
 use alloc::alloc::{GlobalAlloc, Layout};
 use alloc::boxed::Box;
 use core::borrow::BorrowMut;
@@ -87,23 +89,57 @@ impl Header {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes given by add_free_region()
+    pub total: usize,
+    /// Bytes in the allocated regions, including the headers
+    pub used: usize,
+    pub num_allocations: usize,
+}
+
 pub struct FirstFitAllocator {
     first_header: RefCell<Option<Box<Header>>>,
 }
 
-#[global_allocator]
+// ホストでのテストではstdのアロケータを使う
+#[cfg_attr(not(test), global_allocator)]
 pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator {
     first_header: RefCell::new(None),
 };
 
 unsafe impl Sync for FirstFitAllocator {}
 
+// ページ境界に揃える割り当て（ページテーブルなど）は、ヒープではなくフレーム
+// アロケータから取る。ヒープはKASLRで選んだダイレクトマップ上にあるが、これらは
+// 恒等マッピングでアクセスされ、アドレスがそのまま物理アドレスとして使われる
+fn is_page_allocation(layout: &Layout) -> bool {
+    layout.align() >= PAGE_SIZE
+}
+
+fn num_pages(layout: &Layout) -> usize {
+    layout.size().div_ceil(PAGE_SIZE).max(1)
+}
+
 unsafe impl GlobalAlloc for FirstFitAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_page_allocation(&layout) {
+            return FRAME_ALLOCATOR
+                .alloc_contiguous_as(
+                    num_pages(&layout),
+                    layout.align() / PAGE_SIZE,
+                    FrameState::Heap,
+                )
+                .map_or(null_mut(), |phys| phys as *mut u8);
+        }
         self.alloc_with_options(layout)
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_page_allocation(&layout) {
+            FRAME_ALLOCATOR.free_contiguous(ptr as u64, num_pages(&layout));
+            return;
+        }
         let mut region = Header::from_allocated_region(ptr);
         region.is_allocated = false;
         Box::leak(region);
@@ -130,24 +166,23 @@ impl FirstFitAllocator {
         }
     }
 
-    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
-        for e in memory_map.iter() {
-            if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
-                continue;
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats::default();
+        let first_header = self.first_header.borrow();
+        let mut header = first_header.as_deref();
+        while let Some(h) = header {
+            stats.total += h.size;
+            if h.is_allocated() {
+                stats.used += h.size;
+                stats.num_allocations += 1;
             }
-            self.add_free_from_descriptor(e);
+            header = h.next_header.as_deref();
         }
+        stats
     }
 
-    fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
-        let mut start_addr = desc.physical_start() as usize;
-        let mut size = desc.number_of_pages() as usize * 4096;
-
-        if start_addr == 0 {
-            start_addr += 4096;
-            size = size.saturating_sub(4096);
-        }
-
+    // 物理的に連続した領域（のダイレクトマップ上のアドレス）をヒープの空き領域として追加
+    pub fn add_free_region(&self, start_addr: usize, size: usize) {
         if size <= 4096 {
             return;
         }
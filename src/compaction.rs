@@ -0,0 +1,154 @@
+extern crate alloc;
+
+use crate::address_space::flush_tlb_page_all_address_spaces;
+use crate::frame::frame_info;
+use crate::frame::FrameInfo;
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::frame::FRAME_FLAG_HUGE;
+use crate::frame::FRAME_FLAG_PINNED;
+use crate::frame::FRAME_FLAG_ZERO;
+use crate::result::Result;
+use crate::rmap::for_each_mapping;
+use crate::swap::frame_migrated;
+use crate::x86::ENTRY_ADDR_MASK;
+use crate::x86::PAGE_SIZE;
+use alloc::vec::Vec;
+use core::fmt;
+
+// ページ移動（マイグレーション）とメモリコンパクション
+// 物理メモリが断片化して2MiBの連続領域が確保できなくなったときに、移動可能なフレーム
+// （無名メモリ）の中身を別のフレームに移し、逆マッピングでページテーブルを書き換えて
+// 2MiB境界に揃った空きブロックを作る
+// Linuxと同様に、移動元は低いアドレスから、移動先は高いアドレスから探す
+
+/// Number of frames in a 2MiB block
+pub const HUGE_BLOCK_FRAMES: usize = 512;
+const HUGE_BLOCK_SIZE: u64 = (HUGE_BLOCK_FRAMES * PAGE_SIZE) as u64;
+
+/// Frames only reached through the page tables can be moved. Frames in
+/// 2MiB pages are not, since the rmap does not track them.
+pub fn is_movable(info: &FrameInfo) -> bool {
+    info.state == FrameState::User
+        && info.ref_count > 0
+        && info.flags & (FRAME_FLAG_PINNED | FRAME_FLAG_ZERO | FRAME_FLAG_HUGE) == 0
+}
+
+/// Returns true if all the frames in the 2MiB block at phys are free.
+pub fn is_free_block(phys: u64) -> bool {
+    (0..HUGE_BLOCK_FRAMES as u64).all(|i| {
+        frame_info(phys + i * PAGE_SIZE as u64).map(|info| info.state) == Some(FrameState::Free)
+    })
+}
+
+/// Moves the contents and the mappings of the frame at src to the free frame
+/// at dst, and frees src.
+pub fn migrate_frame(src: u64, dst: u64) -> Result<()> {
+    let info = frame_info(src).ok_or("Frame is not managed")?;
+    if !is_movable(&info) {
+        return Err("Frame is not movable");
+    }
+    FRAME_ALLOCATOR.alloc_frame_at(dst, info.state)?;
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, PAGE_SIZE) };
+    // Entries found by the rmap are populated, so write_pte() does not fail
+    for_each_mapping(src, |table, virt, pte| {
+        let _ = table.write_pte(virt, dst | (pte & !ENTRY_ADDR_MASK));
+        flush_tlb_page_all_address_spaces(virt);
+    });
+    FRAME_ALLOCATOR.move_frame_info(src, dst);
+    frame_migrated(src, dst);
+    FRAME_ALLOCATOR.free_frame(src);
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompactionReport {
+    pub pages_moved: usize,
+    pub pages_failed: usize,
+    /// Number of free 2MiB blocks
+    pub free_blocks_before: usize,
+    pub free_blocks_after: usize,
+    /// Size of the largest run of free frames after the compaction in bytes
+    pub largest_free_block: u64,
+}
+impl fmt::Display for CompactionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "compaction: {} pages moved ({} failed), free 2MiB blocks: {} -> {}, largest free block: {} KiB",
+            self.pages_moved,
+            self.pages_failed,
+            self.free_blocks_before,
+            self.free_blocks_after,
+            self.largest_free_block / 1024
+        )
+    }
+}
+
+// Hands out free frames from the top of the memory. Fully free blocks are
+// skipped so that the compaction does not break them.
+struct FreeScanner {
+    // The block being scanned. Blocks at and above this are done.
+    block: usize,
+    frames: Vec<u64>,
+}
+impl FreeScanner {
+    // Returns a free frame above the block `above`
+    fn next(&mut self, above: usize) -> Option<u64> {
+        while self.frames.is_empty() {
+            if self.block <= above + 1 {
+                return None;
+            }
+            self.block -= 1;
+            let start = self.block as u64 * HUGE_BLOCK_SIZE;
+            if is_free_block(start) {
+                continue;
+            }
+            self.frames = (0..HUGE_BLOCK_FRAMES as u64)
+                .map(|i| start + i * PAGE_SIZE as u64)
+                .filter(|phys| frame_info(*phys).map(|info| info.state) == Some(FrameState::Free))
+                .collect();
+        }
+        self.frames.pop()
+    }
+}
+
+/// Moves movable frames out of the 2MiB blocks which consist only of free and
+/// movable frames, to make them free.
+pub fn compact_memory() -> CompactionReport {
+    let num_blocks = FRAME_ALLOCATOR.num_frames() / HUGE_BLOCK_FRAMES;
+    let mut report = CompactionReport {
+        free_blocks_before: FRAME_ALLOCATOR.num_free_blocks(HUGE_BLOCK_FRAMES),
+        ..Default::default()
+    };
+    let mut free_scanner = FreeScanner {
+        block: num_blocks,
+        frames: Vec::new(),
+    };
+    'blocks: for block in 0..num_blocks {
+        if block >= free_scanner.block {
+            break;
+        }
+        let start = block as u64 * HUGE_BLOCK_SIZE;
+        let mut used = Vec::new();
+        for phys in (start..start + HUGE_BLOCK_SIZE).step_by(PAGE_SIZE) {
+            match frame_info(phys) {
+                Some(info) if info.state == FrameState::Free => {}
+                Some(info) if is_movable(&info) => used.push(phys),
+                _ => continue 'blocks,
+            }
+        }
+        for src in used {
+            let Some(dst) = free_scanner.next(block) else {
+                break 'blocks;
+            };
+            match migrate_frame(src, dst) {
+                Ok(()) => report.pages_moved += 1,
+                Err(_) => report.pages_failed += 1,
+            }
+        }
+    }
+    report.free_blocks_after = FRAME_ALLOCATOR.num_free_blocks(HUGE_BLOCK_FRAMES);
+    report.largest_free_block = (FRAME_ALLOCATOR.largest_free_run() * PAGE_SIZE) as u64;
+    report
+}
@@ -0,0 +1,127 @@
+use core::arch::global_asm;
+use core::mem::size_of;
+
+// 例外修正テーブル（Linuxの__ex_tableのようなもの）
+// ユーザーが渡したアドレスへのアクセスのように、失敗しうる命令のアドレスと、
+// 失敗したときに実行を再開するアドレスの組を、その命令の隣でex_table_entry!()を
+// 使って.rdata$wxtbに出力する
+// リンカは.rdata$wxt*を名前順に連結するので、.rdata$wxtaと.rdata$wxtcに置いた
+// 印の間にすべての組が並ぶ
+// #PF / #GPが他のハンドラで解決できなかったとき、inthandlerがこの表を引いて、
+// 見つかればRIPを再開アドレスに書き換える
+// ホストでのテスト用のELFでは名前順の連結がないので、代わりにwasabi_ex_table
+// セクションに出力し、リンカが作る__start_ / __stop_シンボルで範囲を得る
+
+/// A pair of (faulting RIP, recovery RIP)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionTableEntry {
+    pub fault: u64,
+    pub fixup: u64,
+}
+
+/// Emits an entry of the exception fixup table in an asm!() template. Both
+/// arguments are labels, e.g. ex_table_entry!("3b", "4f"). The asm!() should
+/// use options(att_syntax), since the Intel syntax drops the '$' in the
+/// section name.
+#[cfg(target_os = "uefi")]
+#[macro_export]
+macro_rules! ex_table_entry {
+    ($fault:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection \".rdata$wxtb\",\"dr\"\n",
+            ".balign 8\n",
+            ".quad ",
+            $fault,
+            ", ",
+            $fixup,
+            "\n",
+            ".popsection\n"
+        )
+    };
+}
+
+/// Emits an entry of the exception fixup table in an asm!() template (ELF
+/// version for the host tests).
+#[cfg(not(target_os = "uefi"))]
+#[macro_export]
+macro_rules! ex_table_entry {
+    ($fault:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection wasabi_ex_table,\"aw\"\n",
+            ".balign 8\n",
+            ".quad ",
+            $fault,
+            ", ",
+            $fixup,
+            "\n",
+            ".popsection\n"
+        )
+    };
+}
+
+#[cfg(target_os = "uefi")]
+global_asm!(
+    ".section \".rdata$wxta\",\"dr\"",
+    ".balign 8",
+    ".global wasabi_ex_table_start",
+    "wasabi_ex_table_start:",
+    ".section \".rdata$wxtc\",\"dr\"",
+    ".balign 8",
+    ".global wasabi_ex_table_end",
+    "wasabi_ex_table_end:",
+);
+
+#[cfg(target_os = "uefi")]
+extern "C" {
+    static wasabi_ex_table_start: ExceptionTableEntry;
+    static wasabi_ex_table_end: ExceptionTableEntry;
+}
+
+// Makes sure the section exists even if no entries are linked
+#[cfg(not(target_os = "uefi"))]
+global_asm!(".pushsection wasabi_ex_table,\"aw\"", ".popsection");
+
+#[cfg(not(target_os = "uefi"))]
+extern "C" {
+    #[link_name = "__start_wasabi_ex_table"]
+    static wasabi_ex_table_start: ExceptionTableEntry;
+    #[link_name = "__stop_wasabi_ex_table"]
+    static wasabi_ex_table_end: ExceptionTableEntry;
+}
+
+/// Returns all the entries of the exception fixup table.
+pub fn exception_table() -> &'static [ExceptionTableEntry] {
+    unsafe {
+        let start = &wasabi_ex_table_start as *const ExceptionTableEntry;
+        let end = &wasabi_ex_table_end as *const ExceptionTableEntry;
+        let len = (end as usize - start as usize) / size_of::<ExceptionTableEntry>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// Returns the recovery RIP for a fault at rip, if any.
+pub fn search_exception_table(rip: u64) -> Option<u64> {
+    exception_table()
+        .iter()
+        .find(|e| e.fault == rip)
+        .map(|e| e.fixup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uaccess_entries_are_found() {
+        // copy_user_generic() emits at least one entry. Refer to it so that
+        // it is linked into the test binary.
+        core::hint::black_box(crate::uaccess::copy_from_user as fn(&mut [u8], u64) -> _);
+        let table = exception_table();
+        assert!(!table.is_empty());
+        for e in table {
+            assert_eq!(search_exception_table(e.fault), Some(e.fixup));
+        }
+        assert_eq!(search_exception_table(0), None);
+    }
+}
@@ -0,0 +1,725 @@
+extern crate alloc;
+
+use crate::memblock::find_unreserved;
+use crate::memblock::for_each_reservation;
+use crate::memblock::freeze_reservations;
+use crate::memblock::is_reserved;
+use crate::memblock::reserve;
+use crate::result::Result;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryMapHolder;
+use crate::x86::PAGE_SIZE;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::cmp::max;
+use core::cmp::min;
+use core::fmt;
+use core::mem::size_of;
+use core::ops::Range;
+use core::slice;
+
+// 物理ページ（フレーム）単位のアロケータ
+// 1フレームにつき1ビットのビットマップで管理する（1: 使用中, 0: 空き）
+// 加えて、フレームごとの用途や参照カウントを記録するメタデータ（Linuxのstruct page）を持つ
+// 物理アドレスによってフレームをゾーン（DMA16 / DMA32 / Normal）に分けている
+// 古いデバイスは16MiB未満、32ビットのDMAしかできないデバイスは4GiB未満のメモリしか
+// 扱えないので、通常の確保は上のゾーンから行い、下のゾーンはなるべく残しておく
+
+/// What a physical frame is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameState {
+    /// Not usable RAM (firmware, ACPI, the frame metadata itself...)
+    Reserved,
+    Free,
+    /// Allocated by the kernel for other purposes
+    Kernel,
+    PageTable,
+    Heap,
+    /// Backing user (anonymous) memory
+    User,
+    Mmio,
+}
+impl FrameState {
+    pub const ALL: [FrameState; 7] = [
+        FrameState::Reserved,
+        FrameState::Free,
+        FrameState::Kernel,
+        FrameState::PageTable,
+        FrameState::Heap,
+        FrameState::User,
+        FrameState::Mmio,
+    ];
+}
+
+/// Physical memory zone, split by the address that devices can DMA to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    /// Below 16MiB, for legacy (ISA) DMA
+    Dma16,
+    /// Below 4GiB, for devices with 32-bit DMA addressing
+    Dma32,
+    /// The rest of the memory
+    Normal,
+}
+impl Zone {
+    /// From the lowest to the highest
+    pub const ALL: [Zone; 3] = [Zone::Dma16, Zone::Dma32, Zone::Normal];
+
+    /// Physical address range of the zone
+    pub fn range(self) -> Range<u64> {
+        match self {
+            Zone::Dma16 => 0..(16 << 20),
+            Zone::Dma32 => (16 << 20)..(4 << 30),
+            Zone::Normal => (4 << 30)..u64::MAX,
+        }
+    }
+    pub fn of(phys: u64) -> Zone {
+        Zone::ALL
+            .into_iter()
+            .find(|z| z.range().contains(&phys))
+            .unwrap_or(Zone::Normal)
+    }
+    fn frame_range(self) -> Range<usize> {
+        let range = self.range();
+        range.start as usize / PAGE_SIZE..range.end as usize / PAGE_SIZE
+    }
+}
+
+/// The frame is shared read-only and copied on write
+pub const FRAME_FLAG_COW: u8 = 1 << 0;
+/// The frame is the shared zero frame
+pub const FRAME_FLAG_ZERO: u8 = 1 << 1;
+/// The frame is accessed by a device and should not be moved
+pub const FRAME_FLAG_PINNED: u8 = 1 << 2;
+/// The frame is a part of a 2MiB page mapping
+pub const FRAME_FLAG_HUGE: u8 = 1 << 3;
+
+/// Metadata of a physical frame
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FrameInfo {
+    pub state: FrameState,
+    pub flags: u8,
+    /// Number of page table entries which map the frame, excluding the
+    /// identity mapping
+    pub map_count: u32,
+    /// Number of owners. The frame is freed when it drops to 0.
+    pub ref_count: u32,
+}
+impl FrameInfo {
+    const fn new(state: FrameState) -> Self {
+        Self {
+            state,
+            flags: 0,
+            map_count: 0,
+            ref_count: 0,
+        }
+    }
+}
+impl fmt::Display for FrameInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} ref: {} map: {}",
+            self.state, self.ref_count, self.map_count
+        )?;
+        for (flag, name) in [
+            (FRAME_FLAG_COW, "COW"),
+            (FRAME_FLAG_ZERO, "ZERO"),
+            (FRAME_FLAG_PINNED, "PINNED"),
+            (FRAME_FLAG_HUGE, "HUGE"),
+        ] {
+            if self.flags & flag != 0 {
+                write!(f, " {name}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of frames in each state
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameStats {
+    /// Indexed by FrameState as usize
+    pub by_state: [usize; FrameState::ALL.len()],
+    /// Frames which are a part of 2MiB page mappings
+    pub huge: usize,
+}
+impl FrameStats {
+    pub fn count(&self, state: FrameState) -> usize {
+        self.by_state[state as usize]
+    }
+}
+
+struct FrameBitmap {
+    bitmap: &'static mut [u64],
+    infos: &'static mut [FrameInfo],
+    num_frames: usize,
+    num_free: usize,
+    /// Indexed by Zone as usize
+    num_free_in_zone: [usize; Zone::ALL.len()],
+    num_managed: usize,
+    num_reclaimed: usize,
+    next_search: usize,
+}
+
+impl FrameBitmap {
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+    fn set_used(&mut self, frame: usize, used: bool) {
+        if self.is_used(frame) == used {
+            return;
+        }
+        let zone = Zone::of((frame * PAGE_SIZE) as u64) as usize;
+        if used {
+            self.bitmap[frame / 64] |= 1 << (frame % 64);
+            self.num_free -= 1;
+            self.num_free_in_zone[zone] -= 1;
+        } else {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+            self.num_free += 1;
+            self.num_free_in_zone[zone] += 1;
+        }
+    }
+    // Finds num_frames free frames aligned to align_frames within range,
+    // starting the search from next_search (if it is in range) and wrapping
+    // around.
+    fn find_free(
+        &self,
+        range: Range<usize>,
+        num_frames: usize,
+        align_frames: usize,
+    ) -> Option<usize> {
+        let align = |f: usize| (f + align_frames - 1) / align_frames * align_frames;
+        let range = range.start..min(range.end, self.num_frames);
+        let hint = if range.contains(&self.next_search) {
+            self.next_search
+        } else {
+            range.start
+        };
+        let mut start = align(hint);
+        let mut wrapped = false;
+        loop {
+            if start + num_frames > range.end {
+                if wrapped {
+                    return None;
+                }
+                wrapped = true;
+                start = align(range.start);
+                continue;
+            }
+            if wrapped && start >= hint {
+                return None;
+            }
+            // Skip fully used words quickly
+            if align_frames == 1 && start % 64 == 0 && self.bitmap[start / 64] == !0 {
+                start += 64;
+                continue;
+            }
+            match (start..start + num_frames).rev().find(|f| self.is_used(*f)) {
+                None => return Some(start),
+                Some(used) => start = align(used + 1),
+            }
+        }
+    }
+}
+
+pub struct FrameAllocator {
+    inner: RefCell<Option<FrameBitmap>>,
+}
+
+pub static FRAME_ALLOCATOR: FrameAllocator = FrameAllocator {
+    inner: RefCell::new(None),
+};
+
+unsafe impl Sync for FrameAllocator {}
+
+fn is_usable(memory_type: EfiMemoryType) -> bool {
+    memory_type == EfiMemoryType::CONVENTIONAL_MEMORY
+}
+
+// Used by the firmware or the loader until ExitBootServices(), and can be
+// given to the allocator by reclaim_boot_memory() later
+fn is_reclaimable(memory_type: EfiMemoryType) -> bool {
+    matches!(
+        memory_type,
+        EfiMemoryType::BOOT_SERVICES_CODE
+            | EfiMemoryType::BOOT_SERVICES_DATA
+            | EfiMemoryType::LOADER_DATA
+    )
+}
+
+/// Result of FrameAllocator::reclaim_boot_memory()
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReclaimReport {
+    /// Frames freed from BOOT_SERVICES_CODE / BOOT_SERVICES_DATA
+    pub boot_services: usize,
+    /// Frames freed from LOADER_DATA
+    pub loader_data: usize,
+    /// Frames kept since they are reserved
+    pub reserved: usize,
+}
+impl ReclaimReport {
+    pub fn num_reclaimed(&self) -> usize {
+        self.boot_services + self.loader_data
+    }
+}
+impl fmt::Display for ReclaimReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "reclaimed {} KiB of boot services and {} KiB of loader data ({} KiB kept as reserved)",
+            self.boot_services * PAGE_SIZE / 1024,
+            self.loader_data * PAGE_SIZE / 1024,
+            self.reserved * PAGE_SIZE / 1024
+        )
+    }
+}
+
+impl FrameAllocator {
+    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
+        let end_of_ram = memory_map
+            .iter()
+            .filter(|e| is_usable(e.memory_type()) || is_reclaimable(e.memory_type()))
+            .map(|e| e.physical_start() + e.number_of_pages() * PAGE_SIZE as u64)
+            .fold(0, max);
+        let num_frames = end_of_ram as usize / PAGE_SIZE;
+        let bitmap_words = (num_frames + 63) / 64;
+        let bitmap_pages = (bitmap_words * 8).div_ceil(PAGE_SIZE);
+        let info_pages = (num_frames * size_of::<FrameInfo>()).div_ceil(PAGE_SIZE);
+        let metadata_pages = bitmap_pages + info_pages;
+
+        // Place the bitmap and the frame metadata in a free region which is
+        // large enough and not reserved.
+        let metadata_size = (metadata_pages * PAGE_SIZE) as u64;
+        let bitmap_addr = memory_map
+            .iter()
+            .filter(|e| is_usable(e.memory_type()))
+            .find_map(|e| {
+                let start = e.physical_start();
+                let end = start + e.number_of_pages() * PAGE_SIZE as u64;
+                find_unreserved(start..end, metadata_size)
+            })
+            .expect("No space for the frame bitmap");
+        reserve(bitmap_addr, metadata_size, "frame metadata")
+            .expect("Failed to reserve the frame metadata");
+        let bitmap = unsafe { slice::from_raw_parts_mut(bitmap_addr as *mut u64, bitmap_words) };
+        bitmap.fill(!0);
+        let infos = unsafe {
+            slice::from_raw_parts_mut(
+                (bitmap_addr + (bitmap_pages * PAGE_SIZE) as u64) as *mut FrameInfo,
+                num_frames,
+            )
+        };
+        infos.fill(FrameInfo::new(FrameState::Reserved));
+        for e in memory_map.iter() {
+            if e.memory_type() != EfiMemoryType::MEMORY_MAPPED_IO {
+                continue;
+            }
+            let start = e.physical_start() as usize / PAGE_SIZE;
+            let end = start + e.number_of_pages() as usize;
+            for info in infos.iter_mut().take(end).skip(start) {
+                info.state = FrameState::Mmio;
+            }
+        }
+
+        let mut frames = FrameBitmap {
+            bitmap,
+            infos,
+            num_frames,
+            num_free: 0,
+            num_free_in_zone: [0; Zone::ALL.len()],
+            num_managed: 0,
+            num_reclaimed: 0,
+            next_search: 0,
+        };
+        for e in memory_map.iter().filter(|e| is_usable(e.memory_type())) {
+            let start = e.physical_start() as usize / PAGE_SIZE;
+            for f in start..start + e.number_of_pages() as usize {
+                frames.set_used(f, false);
+                frames.infos[f].state = FrameState::Free;
+            }
+        }
+        for_each_reservation(|r| {
+            let start = r.start as usize / PAGE_SIZE;
+            let end = min(r.end as usize / PAGE_SIZE, num_frames);
+            for f in start..end {
+                if frames.infos[f].state == FrameState::Free {
+                    frames.set_used(f, true);
+                    frames.infos[f].state = FrameState::Reserved;
+                }
+            }
+        });
+        freeze_reservations();
+        frames.num_managed = frames.num_free;
+        *self.inner.borrow_mut() = Some(frames);
+    }
+
+    /// Allocates num_frames physically contiguous frames aligned to
+    /// align_frames frames. Returned frames are not zero-cleared.
+    pub fn alloc_contiguous(&self, num_frames: usize, align_frames: usize) -> Result<u64> {
+        self.alloc_contiguous_as(num_frames, align_frames, FrameState::Kernel)
+    }
+
+    /// Same as alloc_contiguous() but records state as the usage of the
+    /// frames. Each frame has a reference count of 1.
+    pub fn alloc_contiguous_as(
+        &self,
+        num_frames: usize,
+        align_frames: usize,
+        state: FrameState,
+    ) -> Result<u64> {
+        self.alloc_contiguous_in(num_frames, align_frames, state, Zone::Normal)
+    }
+
+    /// Same as alloc_contiguous_as() but the frames come from zone or, if it
+    /// is exhausted, from the lower zones in the descending order.
+    pub fn alloc_contiguous_in(
+        &self,
+        num_frames: usize,
+        align_frames: usize,
+        state: FrameState,
+        zone: Zone,
+    ) -> Result<u64> {
+        self.alloc_contiguous_below(num_frames, align_frames, state, zone.range().end)
+    }
+
+    /// Same as alloc_contiguous_as() but all the frames are below max_addr.
+    /// Tries the highest zone first and falls back to the lower ones.
+    pub fn alloc_contiguous_below(
+        &self,
+        num_frames: usize,
+        align_frames: usize,
+        state: FrameState,
+        max_addr: u64,
+    ) -> Result<u64> {
+        let mut inner = self.inner.borrow_mut();
+        let frames = inner.as_mut().ok_or("Frame allocator is not initialized")?;
+        if num_frames == 0 || !align_frames.is_power_of_two() {
+            return Err("Invalid frame allocation request");
+        }
+        let max_frame = (max_addr / PAGE_SIZE as u64)
+            .try_into()
+            .unwrap_or(usize::MAX);
+        let start = Zone::ALL
+            .into_iter()
+            .rev()
+            .filter(|z| frames.num_free_in_zone[*z as usize] >= num_frames)
+            .find_map(|z| {
+                let range = z.frame_range();
+                frames.find_free(
+                    range.start..min(range.end, max_frame),
+                    num_frames,
+                    align_frames,
+                )
+            })
+            .ok_or("Out of physical frames")?;
+        for f in start..start + num_frames {
+            frames.set_used(f, true);
+            frames.infos[f] = FrameInfo {
+                ref_count: 1,
+                ..FrameInfo::new(state)
+            };
+        }
+        frames.next_search = start + num_frames;
+        Ok((start * PAGE_SIZE) as u64)
+    }
+
+    pub fn free_contiguous(&self, phys: u64, num_frames: usize) {
+        let mut inner = self.inner.borrow_mut();
+        let frames = inner.as_mut().expect("Frame allocator is not initialized");
+        assert_eq!(phys % PAGE_SIZE as u64, 0, "Freeing unaligned frame");
+        let start = phys as usize / PAGE_SIZE;
+        for f in start..start + num_frames {
+            assert!(frames.is_used(f), "Double free of a frame");
+            frames.set_used(f, false);
+            // map_count is kept to catch frames freed while still mapped
+            frames.infos[f] = FrameInfo {
+                map_count: frames.infos[f].map_count,
+                ..FrameInfo::new(FrameState::Free)
+            };
+        }
+    }
+
+    /// Allocates a zero-cleared frame and returns its physical address.
+    pub fn alloc_frame(&self) -> Result<u64> {
+        self.alloc_frame_as(FrameState::Kernel)
+    }
+
+    pub fn alloc_frame_as(&self, state: FrameState) -> Result<u64> {
+        let phys = self.alloc_contiguous_as(1, 1, state)?;
+        // Physical memory is identity-mapped
+        unsafe { (phys as *mut u8).write_bytes(0, PAGE_SIZE) };
+        Ok(phys)
+    }
+
+    pub fn free_frame(&self, phys: u64) {
+        self.free_contiguous(phys, 1)
+    }
+
+    /// Allocates the frame at phys if it is free. The frame is not
+    /// zero-cleared.
+    pub fn alloc_frame_at(&self, phys: u64, state: FrameState) -> Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let frames = inner.as_mut().ok_or("Frame allocator is not initialized")?;
+        assert_eq!(phys % PAGE_SIZE as u64, 0, "Unaligned frame");
+        let f = phys as usize / PAGE_SIZE;
+        if f >= frames.num_frames || frames.is_used(f) {
+            return Err("Frame is not free");
+        }
+        frames.set_used(f, true);
+        frames.infos[f] = FrameInfo {
+            ref_count: 1,
+            ..FrameInfo::new(state)
+        };
+        Ok(())
+    }
+
+    // Copies the metadata of src to dst on page migration. The map counts
+    // follow the page table updates instead.
+    pub(crate) fn move_frame_info(&self, src: u64, dst: u64) {
+        let Some(info) = frame_info(src) else {
+            return;
+        };
+        self.with_info(dst, |dst| {
+            dst.state = info.state;
+            dst.flags = info.flags;
+            dst.ref_count = info.ref_count;
+        });
+    }
+
+    // Calls f with the metadata of the frame at phys if it is managed
+    fn with_info<T>(&self, phys: u64, f: impl FnOnce(&mut FrameInfo) -> T) -> Option<T> {
+        let mut inner = self.inner.borrow_mut();
+        let frames = inner.as_mut()?;
+        frames.infos.get_mut(phys as usize / PAGE_SIZE).map(f)
+    }
+
+    /// Takes another reference to an allocated frame.
+    pub fn get_frame(&self, phys: u64) {
+        assert_eq!(phys % PAGE_SIZE as u64, 0, "Unaligned frame");
+        self.with_info(phys, |info| {
+            assert_ne!(info.ref_count, 0, "get_frame on a free frame");
+            info.ref_count += 1;
+        });
+    }
+
+    /// Drops a reference to a frame, and frees it if it was the last one.
+    /// Returns true if the frame is freed.
+    pub fn put_frame(&self, phys: u64) -> bool {
+        assert_eq!(phys % PAGE_SIZE as u64, 0, "Unaligned frame");
+        let freed = self
+            .with_info(phys, |info| {
+                assert_ne!(info.ref_count, 0, "put_frame on a free frame");
+                info.ref_count -= 1;
+                info.ref_count == 0
+            })
+            .unwrap_or(false);
+        if freed {
+            self.free_frame(phys);
+        }
+        freed
+    }
+
+    pub fn set_frame_state(&self, phys: u64, state: FrameState) {
+        self.with_info(phys, |info| info.state = state);
+    }
+
+    pub fn set_frame_flags(&self, phys: u64, flags: u8, enable: bool) {
+        self.with_info(phys, |info| {
+            if enable {
+                info.flags |= flags;
+            } else {
+                info.flags &= !flags;
+            }
+        });
+    }
+
+    // Called when a page table entry starts / stops mapping the frame. The
+    // increments and the decrements should be paired.
+    pub(crate) fn inc_map_count(&self, phys: u64) {
+        self.with_info(phys, |info| {
+            debug_assert_ne!(info.map_count, u32::MAX, "map_count overflow");
+            info.map_count = info.map_count.wrapping_add(1);
+        });
+    }
+    pub(crate) fn dec_map_count(&self, phys: u64) {
+        self.with_info(phys, |info| {
+            debug_assert_ne!(info.map_count, 0, "map_count underflow");
+            info.map_count = info.map_count.wrapping_sub(1);
+        });
+    }
+
+    pub fn num_free_frames(&self) -> usize {
+        self.inner
+            .borrow()
+            .as_ref()
+            .map(|f| f.num_free)
+            .unwrap_or(0)
+    }
+
+    pub fn num_free_frames_in(&self, zone: Zone) -> usize {
+        self.inner
+            .borrow()
+            .as_ref()
+            .map(|f| f.num_free_in_zone[zone as usize])
+            .unwrap_or(0)
+    }
+
+    /// Makes the next allocation search for free frames from phys. Used to
+    /// randomize the placement of the heap.
+    pub fn set_search_hint(&self, phys: u64) {
+        if let Some(frames) = self.inner.borrow_mut().as_mut() {
+            frames.next_search = min(phys as usize / PAGE_SIZE, frames.num_frames);
+        }
+    }
+
+    /// Number of frames covered by the allocator, including the ones which
+    /// are not usable.
+    pub fn num_frames(&self) -> usize {
+        self.inner
+            .borrow()
+            .as_ref()
+            .map(|f| f.num_frames)
+            .unwrap_or(0)
+    }
+
+    /// Returns the number of frames in the largest run of free frames.
+    pub fn largest_free_run(&self) -> usize {
+        let inner = self.inner.borrow();
+        let Some(frames) = inner.as_ref() else {
+            return 0;
+        };
+        let mut largest = 0;
+        let mut run = 0;
+        for f in 0..frames.num_frames {
+            if frames.is_used(f) {
+                run = 0;
+            } else {
+                run += 1;
+                largest = max(largest, run);
+            }
+        }
+        largest
+    }
+
+    /// Returns the number of free blocks of align_frames frames aligned to
+    /// their size.
+    pub fn num_free_blocks(&self, align_frames: usize) -> usize {
+        let inner = self.inner.borrow();
+        let Some(frames) = inner.as_ref() else {
+            return 0;
+        };
+        (0..frames.num_frames / align_frames)
+            .filter(|b| {
+                let start = b * align_frames;
+                (start..start + align_frames).all(|f| !frames.is_used(f))
+            })
+            .count()
+    }
+
+    pub fn frame_stats(&self) -> FrameStats {
+        let mut stats = FrameStats::default();
+        if let Some(frames) = self.inner.borrow().as_ref() {
+            for info in frames.infos.iter() {
+                stats.by_state[info.state as usize] += 1;
+                if info.flags & FRAME_FLAG_HUGE != 0 {
+                    stats.huge += 1;
+                }
+            }
+        }
+        stats
+    }
+
+    /// Returns the physical address ranges of the runs of frames in state.
+    pub fn ranges_in_state(&self, state: FrameState) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        let inner = self.inner.borrow();
+        let Some(frames) = inner.as_ref() else {
+            return ranges;
+        };
+        for (f, info) in frames.infos.iter().enumerate() {
+            if info.state != state {
+                continue;
+            }
+            let phys = (f * PAGE_SIZE) as u64;
+            match ranges.last_mut() {
+                Some(last) if last.end == phys => last.end += PAGE_SIZE as u64,
+                _ => ranges.push(phys..phys + PAGE_SIZE as u64),
+            }
+        }
+        ranges
+    }
+
+    /// Frees the frames used by the firmware and the loader until
+    /// ExitBootServices(), except the reserved ones. LOADER_DATA is reclaimed
+    /// only if loader_data is true. Should be called once nothing refers to
+    /// the structures built by the firmware (e.g. its page tables and GDT).
+    pub fn reclaim_boot_memory(
+        &self,
+        memory_map: &MemoryMapHolder,
+        loader_data: bool,
+    ) -> ReclaimReport {
+        let mut report = ReclaimReport::default();
+        let mut inner = self.inner.borrow_mut();
+        let Some(frames) = inner.as_mut() else {
+            return report;
+        };
+        for e in memory_map.iter() {
+            let memory_type = e.memory_type();
+            if !is_reclaimable(memory_type)
+                || (memory_type == EfiMemoryType::LOADER_DATA && !loader_data)
+            {
+                continue;
+            }
+            let start = e.physical_start() as usize / PAGE_SIZE;
+            let end = min(start + e.number_of_pages() as usize, frames.num_frames);
+            for f in start..end {
+                if frames.infos[f].state != FrameState::Reserved {
+                    continue;
+                }
+                if is_reserved((f * PAGE_SIZE) as u64) {
+                    report.reserved += 1;
+                    continue;
+                }
+                frames.set_used(f, false);
+                frames.infos[f].state = FrameState::Free;
+                if memory_type == EfiMemoryType::LOADER_DATA {
+                    report.loader_data += 1;
+                } else {
+                    report.boot_services += 1;
+                }
+            }
+        }
+        frames.num_managed += report.num_reclaimed();
+        frames.num_reclaimed += report.num_reclaimed();
+        report
+    }
+
+    /// Number of frames given by reclaim_boot_memory()
+    pub fn num_reclaimed_frames(&self) -> usize {
+        self.inner
+            .borrow()
+            .as_ref()
+            .map(|f| f.num_reclaimed)
+            .unwrap_or(0)
+    }
+
+    pub fn num_managed_frames(&self) -> usize {
+        self.inner
+            .borrow()
+            .as_ref()
+            .map(|f| f.num_managed)
+            .unwrap_or(0)
+    }
+}
+
+/// Returns the metadata of the frame containing phys, or None if it is out of
+/// the range managed by the frame allocator.
+pub fn frame_info(phys: u64) -> Option<FrameInfo> {
+    let inner = FRAME_ALLOCATOR.inner.borrow();
+    let frames = inner.as_ref()?;
+    frames.infos.get(phys as usize / PAGE_SIZE).copied()
+}
@@ -1,38 +1,130 @@
 extern crate alloc;
 
 use crate::allocator::ALLOCATOR;
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::info;
+use crate::kaslr::init_kaslr;
+use crate::kaslr::kaslr_layout;
+use crate::kaslr::phys_to_virt;
+use crate::la57::switch_to_la57;
+use crate::memblock::dump_reservations;
+use crate::memblock::reserve;
 use crate::uefi::exit_from_efi_boot_services;
+use crate::uefi::locate_loaded_image_protocol;
 use crate::uefi::EfiHandle;
 use crate::uefi::EfiMemoryType::*;
 use crate::uefi::EfiSystemTable;
 use crate::uefi::MemoryMapHolder;
+use crate::x86::enable_pcid;
+use crate::x86::enable_smap;
+use crate::x86::enable_smep;
+use crate::x86::enable_umip;
+use crate::x86::init_pat;
+use crate::x86::is_la57_supported;
+use crate::x86::read_rsp;
 use crate::x86::write_cr3;
 use crate::x86::PageAttr;
+use crate::x86::PageMapper;
 use crate::x86::PAGE_SIZE;
 use crate::x86::PML4;
+use crate::x86::PML5;
 use alloc::boxed::Box;
 use core::cmp::max;
+use core::cmp::min;
 
-// 基本ランタイムの初期化（アロケータのセットアップ）
+// ヒープに割り当てる物理メモリの上限（残りはフレームアロケータが管理する）
+const HEAP_SIZE_MAX: usize = 256 * 1024 * 1024;
+const HEAP_CHUNK_SIZE_MIN: usize = 1024 * 1024;
+
+// 基本ランタイムの初期化（フレームアロケータのセットアップ）
+// ヒープはダイレクトマップ上に置くので、init_paging()で作られる
 pub fn init_basic_runtime(
     image_handle: EfiHandle,
     efi_system_table: &EfiSystemTable,
 ) -> MemoryMapHolder {
+    // カーネルイメージは、後でLOADER_DATAを回収しても残るように予約しておく
+    // （ブートサービスを抜けるとLoaded Image Protocolは引けなくなる）
+    let image = locate_loaded_image_protocol(image_handle, efi_system_table)
+        .expect("Failed to get LoadedImageProtocol");
+    reserve(image.image_base, image.image_size, "kernel image")
+        .expect("Failed to reserve the kernel image");
     let mut memory_map = MemoryMapHolder::new();
     exit_from_efi_boot_services(image_handle, efi_system_table, &mut memory_map);
-    ALLOCATOR.init_with_mmap(&memory_map);
+    reserve_early_memory(&memory_map);
+    FRAME_ALLOCATOR.init_with_mmap(&memory_map);
+    dump_reservations();
+    init_kaslr();
     memory_map
 }
 
+// アロケータを作る前に、まだ使われている物理メモリを予約する
+// フレームバッファはinit_vram()で予約される
+fn reserve_early_memory(memory_map: &MemoryMapHolder) {
+    // NULLポインタ参照を無効なままにするため、ページ0は使わない
+    reserve(0, PAGE_SIZE as u64, "NULL page").expect("Failed to reserve page 0");
+    // スタックはファームウェアが確保したもので、返り値のメモリマップもここに置かれる
+    let rsp = read_rsp();
+    if let Some(e) = memory_map.iter().find(|e| {
+        e.physical_start() <= rsp
+            && rsp < e.physical_start() + e.number_of_pages() * PAGE_SIZE as u64
+    }) {
+        reserve(
+            e.physical_start(),
+            e.number_of_pages() * PAGE_SIZE as u64,
+            "boot stack and UEFI memory map",
+        )
+        .expect("Failed to reserve the boot stack");
+    }
+    for e in memory_map.iter() {
+        if matches!(e.memory_type(), ACPI_RECLAIM_MEMORY | ACPI_MEMORY_NVS) {
+            reserve(
+                e.physical_start(),
+                e.number_of_pages() * PAGE_SIZE as u64,
+                "ACPI tables",
+            )
+            .expect("Failed to reserve the ACPI tables");
+        }
+    }
+}
+
+// フレームアロケータから連続領域を切り出して、ダイレクトマップ上にヒープを構築
+// 断片化していて一度に確保できない場合は、より小さい塊に分けて確保する
+// KASLRが有効なら、ランダムに選んだ位置から空き領域を探す
+fn init_heap() {
+    if let Some(layout) = kaslr_layout() {
+        FRAME_ALLOCATOR.set_search_hint(layout.heap_hint);
+    }
+    let mut remaining = min(
+        HEAP_SIZE_MAX,
+        FRAME_ALLOCATOR.num_free_frames() / 2 * PAGE_SIZE,
+    );
+    let mut chunk_size = remaining;
+    while remaining >= HEAP_CHUNK_SIZE_MIN && chunk_size >= HEAP_CHUNK_SIZE_MIN {
+        match FRAME_ALLOCATOR.alloc_contiguous_as(chunk_size / PAGE_SIZE, 1, FrameState::Heap) {
+            Ok(phys) => {
+                let virt = phys_to_virt(phys).expect("Heap is out of the direct map");
+                ALLOCATOR.add_free_region(virt as usize, chunk_size);
+                remaining -= chunk_size;
+                chunk_size = min(chunk_size, remaining);
+            }
+            Err(_) => {
+                chunk_size = chunk_size / 2 / PAGE_SIZE * PAGE_SIZE;
+            }
+        }
+    }
+    FRAME_ALLOCATOR.set_search_hint(0);
+}
+
 // ページングの初期化
 pub fn init_paging(memory_map: &MemoryMapHolder) {
-    let mut table = PML4::new();
     let mut end_of_mem = 0x1_0000_0000u64;
 
     // メモリマップから物理メモリの最大アドレスを取得
     for e in memory_map.iter() {
         match e.memory_type() {
-            CONVENTIONAL_MEMORY | LOADER_CODE | LOADER_DATA => {
+            CONVENTIONAL_MEMORY | LOADER_CODE | LOADER_DATA | BOOT_SERVICES_CODE
+            | BOOT_SERVICES_DATA => {
                 end_of_mem = max(
                     end_of_mem,
                     e.physical_start() + e.number_of_pages() * PAGE_SIZE as u64,
@@ -42,13 +134,65 @@ pub fn init_paging(memory_map: &MemoryMapHolder) {
         }
     }
 
+    // CPUが対応していれば5レベルページング（LA57）を使う
+    // ファームウェアが有効にしていなくても、テーブルを作った後で切り替える
+    let pml5 = is_la57_supported().then(|| Box::into_raw(PML5::new()));
+    let table: *mut dyn PageMapper = match pml5 {
+        Some(pml5) => pml5,
+        None => Box::into_raw(PML4::new()),
+    };
+
     // 0から物理メモリ終端まで恒等マッピング（仮想アドレス = 物理アドレス）
-    table
-        .create_mapping(0, end_of_mem, 0, PageAttr::ReadWriteKernel)
-        .expect("Failed to create initial page mapping");
+    unsafe {
+        (*table)
+            .create_mapping(0, end_of_mem, 0, PageAttr::ReadWriteKernel)
+            .expect("Failed to create initial page mapping");
+    }
+
+    // KASLRで選んだアドレスにダイレクトマップ（恒等マッピングの別名）を作る
+    // ヒープはここに置かれる
+    if let Some(layout) = kaslr_layout() {
+        unsafe {
+            (*table)
+                .alias_identity_map(layout.direct_map_base, end_of_mem)
+                .expect("Failed to create the direct map");
+        }
+    }
+
+    // カーネル空間（上位半分）の最上位のエントリを全部作っておく
+    // アドレス空間は最上位のテーブルを複製して作られ、下位のテーブルは共有するので、
+    // 後からvmallocなどで作られたカーネルのマッピングも全てのアドレス空間から見える
+    unsafe {
+        (*table)
+            .populate_kernel_half()
+            .expect("Failed to populate the kernel half");
+    }
+
+    // Write-Combiningを使えるようにPATを設定
+    init_pat();
 
-    // CR3にPML4のアドレスを設定して、ページングを有効化
+    // CR3にPML4（またはPML5）のアドレスを設定して、ページングを有効化
+    // PCIDを有効にするとページングを無効にできなくなるので、LA57への切り替えはその前に行う
     unsafe {
-        write_cr3(Box::into_raw(table));
+        match pml5 {
+            Some(pml5) => {
+                switch_to_la57(&*pml5).expect("Failed to switch to 5-level paging");
+                info!("5-level paging (LA57) is enabled");
+            }
+            None => write_cr3(table as *const PML4),
+        }
     }
+
+    // 使えるならPCIDを有効化（CR3の切り替えでTLBを捨てずに済む）
+    enable_pcid();
+
+    // 使えるならSMEP/SMAP/UMIPを有効化
+    // カーネルがユーザーページのコードを実行したり、うっかり読み書きしたりしないようにする
+    enable_smep();
+    enable_smap();
+    enable_umip();
+
+    // ダイレクトマップができたので、ヒープを作る
+    // それまでのページテーブルはページ境界に揃えた割り当てなので、ヒープを使わない
+    init_heap();
 }
@@ -0,0 +1,192 @@
+extern crate alloc;
+
+use crate::address_space::flush_tlb_range_all_address_spaces;
+use crate::result::Result;
+use crate::x86::current_page_table;
+use crate::x86::is_write_combining_enabled;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::Range;
+
+// ioremap: MMIO領域を専用の仮想アドレス領域にキャッシュ無効（またはWC）でマップする
+pub const IOREMAP_START: u64 = 0xFFFF_E000_0000_0000;
+pub const IOREMAP_SIZE: u64 = 1 << 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    Uncacheable,
+    // Falls back to Uncacheable if the PAT is not programmed by init_pat()
+    WriteCombining,
+}
+impl CacheMode {
+    pub fn page_attr(&self) -> PageAttr {
+        match self {
+            CacheMode::Uncacheable => PageAttr::ReadWriteIo,
+            CacheMode::WriteCombining if is_write_combining_enabled() => {
+                PageAttr::ReadWriteWriteCombining
+            }
+            CacheMode::WriteCombining => PageAttr::ReadWriteIo,
+        }
+    }
+}
+
+struct IoArea {
+    end: u64,
+}
+
+struct IoremapRegion {
+    // Keyed by the page-aligned start address of the mapping
+    areas: RefCell<BTreeMap<u64, IoArea>>,
+}
+
+unsafe impl Sync for IoremapRegion {}
+
+static IOREMAP: IoremapRegion = IoremapRegion {
+    areas: RefCell::new(BTreeMap::new()),
+};
+
+fn find_free_range(areas: &BTreeMap<u64, IoArea>, size: u64) -> Option<u64> {
+    let mut candidate = IOREMAP_START;
+    for (start, area) in areas.iter() {
+        if start - candidate >= size {
+            return Some(candidate);
+        }
+        candidate = area.end;
+    }
+    (IOREMAP_START + IOREMAP_SIZE - candidate >= size).then_some(candidate)
+}
+
+/// Maps physical range [phys, phys + len) into the ioremap region and returns
+/// the virtual address that corresponds to phys.
+pub fn ioremap(phys: u64, len: usize, mode: CacheMode) -> Result<*mut u8> {
+    if len == 0 {
+        return Err("ioremap: len is zero");
+    }
+    let page_mask = PAGE_SIZE as u64 - 1;
+    let phys_start = phys & !page_mask;
+    let phys_end = phys
+        .checked_add(len as u64)
+        .and_then(|end| end.checked_add(page_mask))
+        .ok_or("ioremap: range overflows")?
+        & !page_mask;
+    let size = phys_end - phys_start;
+    let mut areas = IOREMAP.areas.borrow_mut();
+    let virt = find_free_range(&areas, size).ok_or("ioremap: region exhausted")?;
+    let table = current_page_table();
+    let result =
+        unsafe { (*table).create_mapping(virt, virt + size, phys_start, mode.page_attr()) };
+    if let Err(e) = result {
+        // Pages mapped before the failure are not recorded in areas
+        unsafe {
+            (*table)
+                .create_mapping(virt, virt + size, 0, PageAttr::NotPresent)
+                .expect("Failed to unmap a partial ioremap area");
+        }
+        flush_tlb_range_all_address_spaces(virt..virt + size);
+        return Err(e);
+    }
+    areas.insert(virt, IoArea { end: virt + size });
+    Ok((virt + (phys - phys_start)) as *mut u8)
+}
+
+/// Unmaps a region mapped by ioremap(). virt can be any address returned by
+/// ioremap().
+pub fn iounmap(virt: *mut u8) -> Result<()> {
+    let virt = virt as u64 & !(PAGE_SIZE as u64 - 1);
+    let area = IOREMAP
+        .areas
+        .borrow_mut()
+        .remove(&virt)
+        .ok_or("iounmap: not mapped by ioremap")?;
+    unsafe {
+        (*current_page_table()).create_mapping(virt, area.end, 0, PageAttr::NotPresent)?;
+    }
+    flush_tlb_range_all_address_spaces(virt..area.end);
+    Ok(())
+}
+
+/// Returns the virtual address ranges mapped by ioremap().
+pub fn ioremap_areas() -> Vec<Range<u64>> {
+    IOREMAP
+        .areas
+        .borrow()
+        .iter()
+        .map(|(start, area)| *start..area.end)
+        .collect()
+}
+
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// Types that a register can hold. Implemented only for u8, u16, u32 and
+/// u64, which are accessed with a single load or store of the same width.
+pub trait RegisterValue: Copy + sealed::Sealed {}
+impl RegisterValue for u8 {}
+impl RegisterValue for u16 {}
+impl RegisterValue for u32 {}
+impl RegisterValue for u64 {}
+
+/// Offset and type of a memory-mapped register
+pub struct Register<T: RegisterValue> {
+    offset: usize,
+    _type: PhantomData<T>,
+}
+impl<T: RegisterValue> Register<T> {
+    pub const fn new(offset: usize) -> Self {
+        Self {
+            offset,
+            _type: PhantomData,
+        }
+    }
+}
+
+/// A region mapped with ioremap() that provides volatile register accessors.
+/// The region is unmapped when this is dropped.
+pub struct IoMapping {
+    base: *mut u8,
+    phys: u64,
+    len: usize,
+}
+impl IoMapping {
+    pub fn new(phys: u64, len: usize, mode: CacheMode) -> Result<Self> {
+        let base = ioremap(phys, len, mode)?;
+        Ok(Self { base, phys, len })
+    }
+    pub fn phys_addr(&self) -> u64 {
+        self.phys
+    }
+    /// Size of the mapped region in bytes (never zero)
+    pub fn size(&self) -> usize {
+        self.len
+    }
+    fn reg_ptr<T: RegisterValue>(&self, reg: &Register<T>) -> *mut T {
+        assert!(
+            reg.offset + size_of::<T>() <= self.len,
+            "Register out of range"
+        );
+        assert_eq!(reg.offset % size_of::<T>(), 0, "Misaligned register");
+        // SAFETY: the range is checked above and mapped while self is alive
+        unsafe { self.base.add(reg.offset) as *mut T }
+    }
+    pub fn read<T: RegisterValue>(&self, reg: Register<T>) -> T {
+        unsafe { self.reg_ptr(&reg).read_volatile() }
+    }
+    pub fn write<T: RegisterValue>(&self, reg: Register<T>, value: T) {
+        unsafe { self.reg_ptr(&reg).write_volatile(value) }
+    }
+}
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        iounmap(self.base).expect("Failed to unmap IoMapping");
+    }
+}
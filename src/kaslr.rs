@@ -0,0 +1,401 @@
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::result::Result;
+use crate::x86::current_page_table;
+use crate::x86::rdrand64;
+use crate::x86::rdseed64;
+use crate::x86::rdtsc;
+use crate::x86::PageAttr;
+use crate::x86::Protection;
+use crate::x86::HUGE_PAGE_SIZE;
+use crate::x86::PAGE_SIZE;
+use core::cell::RefCell;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::Range;
+
+// KASLR（カーネルのアドレス空間配置のランダム化）
+// 起動時にRDSEED / RDRAND（どちらも使えなければTSC）から乱数を得て、次を決める
+// - カーネルイメージの仮想ベースアドレス: ロードされたイメージをコピーし、PEのベース
+//   再配置テーブルに従って再配置してからマップし、そちらに制御を移す
+// - ダイレクトマップのオフセット: 恒等マッピングの下位のテーブルを共有する別名で、
+//   ヒープはここに置かれる
+// - ヒープの物理的な位置（フレームアロケータの探索開始位置）
+// - GDT、IDT、TSSの仮想アドレス（alloc_at_random_addr()）
+// 再配置したイメージに移った後、元のイメージは読み出し専用かつ実行不可にする
+// 移る前に作られたデータ（文字列リテラルへの参照など）が指しているので、アンマップは
+// しない
+// ページテーブルのように物理アドレスをそのままポインタとして使うものは、引き続き
+// 恒等マッピングを使う
+
+const KERNEL_WINDOW: Range<u64> = 0xFFFF_FFFF_8000_0000..0xFFFF_FFFF_C000_0000;
+/// Images larger than this can not be relocated
+pub const KERNEL_IMAGE_MAX: u64 = 64 * 1024 * 1024;
+const DIRECT_MAP_WINDOW: Range<u64> = 0xFFFF_8800_0000_0000..0xFFFF_C000_0000_0000;
+/// Size of the virtual range reserved for the direct map
+pub const DIRECT_MAP_SIZE: u64 = 1 << 41;
+// The direct map shares the PML4 entries of the identity mapping
+const DIRECT_MAP_ALIGN: u64 = 1 << 39;
+// Where alloc_at_random_addr() places the CPU tables
+const CPU_TABLES_WINDOW: Range<u64> = 0xFFFF_FE00_0000_0000..0xFFFF_FF00_0000_0000;
+
+/// Ordered from the strongest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntropySource {
+    Rdseed,
+    Rdrand,
+    /// Only used if neither RDSEED nor RDRAND is available
+    Tsc,
+}
+
+/// Returns a random number and where it came from.
+pub fn random_u64() -> (u64, EntropySource) {
+    if let Some(value) = rdseed64() {
+        (value, EntropySource::Rdseed)
+    } else if let Some(value) = rdrand64() {
+        (value, EntropySource::Rdrand)
+    } else {
+        // Mix the bits since only the lower bits of the TSC change a lot
+        let tsc = rdtsc();
+        (
+            (tsc ^ (tsc >> 29)).wrapping_mul(0xBF58_476D_1CE4_E5B9),
+            EntropySource::Tsc,
+        )
+    }
+}
+
+// Returns a random address in window aligned to align, which leaves size
+// bytes after it in window.
+fn random_addr(window: &Range<u64>, size: u64, align: u64) -> (u64, EntropySource) {
+    let slots = (window.end - window.start - size) / align + 1;
+    let (value, source) = random_u64();
+    (window.start + value % slots * align, source)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KaslrLayout {
+    /// Virtual address where the relocated kernel image is mapped
+    pub kernel_base: u64,
+    /// Virtual address which maps the physical address 0
+    pub direct_map_base: u64,
+    /// Physical address where the search for the heap starts
+    pub heap_hint: u64,
+    /// The weakest source used for the above
+    pub source: EntropySource,
+}
+impl fmt::Display for KaslrLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "KASLR: kernel @ {:#018X}, direct map @ {:#018X}, heap hint {:#X} (entropy: {:?})",
+            self.kernel_base, self.direct_map_base, self.heap_hint, self.source
+        )
+    }
+}
+
+struct Kaslr {
+    layout: RefCell<Option<KaslrLayout>>,
+}
+
+unsafe impl Sync for Kaslr {}
+
+static KASLR: Kaslr = Kaslr {
+    layout: RefCell::new(None),
+};
+
+/// Chooses the randomized layout. Should be called after the frame
+/// allocator is initialized. Returns the same layout if called again.
+pub fn init_kaslr() -> KaslrLayout {
+    if let Some(layout) = kaslr_layout() {
+        return layout;
+    }
+    let (kernel_base, s1) = random_addr(&KERNEL_WINDOW, KERNEL_IMAGE_MAX, HUGE_PAGE_SIZE);
+    let (direct_map_base, s2) = random_addr(&DIRECT_MAP_WINDOW, DIRECT_MAP_SIZE, DIRECT_MAP_ALIGN);
+    let memory_size = (FRAME_ALLOCATOR.num_frames() * PAGE_SIZE) as u64;
+    let (heap_hint, s3) = random_addr(&(0..memory_size), 0, HUGE_PAGE_SIZE);
+    let layout = KaslrLayout {
+        kernel_base,
+        direct_map_base,
+        heap_hint,
+        source: s1.max(s2).max(s3),
+    };
+    *KASLR.layout.borrow_mut() = Some(layout);
+    layout
+}
+
+pub fn kaslr_layout() -> Option<KaslrLayout> {
+    *KASLR.layout.borrow()
+}
+
+/// Virtual address range reserved for the direct map
+pub fn direct_map_range() -> Option<Range<u64>> {
+    kaslr_layout().map(|l| l.direct_map_base..l.direct_map_base + DIRECT_MAP_SIZE)
+}
+
+/// Returns the address of phys in the direct map.
+pub fn phys_to_virt(phys: u64) -> Option<u64> {
+    (phys < DIRECT_MAP_SIZE)
+        .then(|| kaslr_layout().map(|l| l.direct_map_base + phys))
+        .flatten()
+}
+
+/// Returns the physical address of virt in the direct map (e.g. a heap
+/// object), or virt itself if it is not in the direct map, assuming the
+/// identity mapping.
+pub fn virt_to_phys(virt: u64) -> u64 {
+    match direct_map_range() {
+        Some(range) if range.contains(&virt) => virt - range.start,
+        _ => virt,
+    }
+}
+
+/// Allocates zeroed frames for size bytes and maps them at a random address
+/// in the kernel space, which is not executable. Used for the tables the CPU
+/// refers to (e.g. GDT, IDT, TSS), so that their addresses are not
+/// predictable. Never freed.
+pub fn alloc_at_random_addr(size: usize) -> Result<u64> {
+    const MAX_TRIES: usize = 16;
+    let size = size.next_multiple_of(PAGE_SIZE) as u64;
+    let table = unsafe { &mut *current_page_table() };
+    for _ in 0..MAX_TRIES {
+        let (virt, _) = random_addr(&CPU_TABLES_WINDOW, size, PAGE_SIZE as u64);
+        if (virt..virt + size)
+            .step_by(PAGE_SIZE)
+            .any(|v| table.translate(v).is_ok())
+        {
+            continue;
+        }
+        let num_frames = size as usize / PAGE_SIZE;
+        let phys = FRAME_ALLOCATOR.alloc_contiguous_as(num_frames, 1, FrameState::Kernel)?;
+        unsafe { (phys as *mut u8).write_bytes(0, size as usize) };
+        table.create_mapping(virt, virt + size, phys, PageAttr::ReadWriteKernel)?;
+        table
+            .protect(virt..virt + size, Protection::READ_WRITE)?
+            .flush();
+        return Ok(virt);
+    }
+    Err("No free range for alloc_at_random_addr")
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16> {
+    let bytes = image
+        .get(offset..offset + 2)
+        .ok_or("PE image is truncated")?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32> {
+    let bytes = image
+        .get(offset..offset + 4)
+        .ok_or("PE image is truncated")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// Returns the byte range of the base relocation table (data directory 5) in
+// the loaded PE32+ image.
+fn base_relocation_table(image: &[u8]) -> Result<Range<usize>> {
+    if image.get(0..2) != Some(b"MZ") {
+        return Err("No DOS header");
+    }
+    let pe = read_u32(image, 0x3C)? as usize;
+    if image.get(pe..pe + 4) != Some(b"PE\0\0") {
+        return Err("No PE signature");
+    }
+    // The optional header follows the COFF file header (20 bytes)
+    let opt = pe + 4 + 20;
+    if read_u16(image, opt)? != 0x20B {
+        return Err("Not a PE32+ image");
+    }
+    const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+    if read_u32(image, opt + 108)? as usize <= IMAGE_DIRECTORY_ENTRY_BASERELOC {
+        return Ok(0..0);
+    }
+    let dir = opt + 112 + IMAGE_DIRECTORY_ENTRY_BASERELOC * 8;
+    let rva = read_u32(image, dir)? as usize;
+    let size = read_u32(image, dir + 4)? as usize;
+    if rva + size > image.len() {
+        return Err("Base relocation table is out of the image");
+    }
+    Ok(rva..rva + size)
+}
+
+/// Adds delta to each absolute address in the loaded PE32+ image, following
+/// its base relocation table. Returns the number of relocations applied.
+pub fn apply_base_relocations(image: &mut [u8], delta: u64) -> Result<usize> {
+    const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+    const IMAGE_REL_BASED_DIR64: u16 = 10;
+    let table = base_relocation_table(image)?;
+    let mut block = table.start;
+    let mut count = 0;
+    while block + 8 <= table.end {
+        let page = read_u32(image, block)? as usize;
+        let block_size = read_u32(image, block + 4)? as usize;
+        if block_size < 8 || block + block_size > table.end {
+            return Err("Broken base relocation block");
+        }
+        for entry in (block + 8..block + block_size).step_by(2) {
+            let entry = read_u16(image, entry)?;
+            match entry >> 12 {
+                // Padding to align the blocks
+                IMAGE_REL_BASED_ABSOLUTE => {}
+                IMAGE_REL_BASED_DIR64 => {
+                    let offset = page + (entry & 0xFFF) as usize;
+                    let target = image
+                        .get_mut(offset..offset + 8)
+                        .ok_or("Relocation target is out of the image")?;
+                    let value = u64::from_le_bytes((*target).try_into().unwrap());
+                    target.copy_from_slice(&value.wrapping_add(delta).to_le_bytes());
+                    count += 1;
+                }
+                _ => return Err("Unsupported base relocation type"),
+            }
+        }
+        block += block_size;
+    }
+    Ok(count)
+}
+
+// Returns the (range, characteristics) of each section in the loaded PE32+
+// image.
+fn sections(image: &[u8]) -> Result<impl Iterator<Item = Result<(Range<usize>, u32)>> + '_> {
+    const SECTION_HEADER_SIZE: usize = 40;
+    let pe = read_u32(image, 0x3C)? as usize;
+    let num_sections = read_u16(image, pe + 4 + 2)? as usize;
+    let opt_size = read_u16(image, pe + 4 + 16)? as usize;
+    let table = pe + 4 + 20 + opt_size;
+    Ok((0..num_sections).map(move |i| {
+        let header = table + i * SECTION_HEADER_SIZE;
+        let size = read_u32(image, header + 8)? as usize;
+        let rva = read_u32(image, header + 12)? as usize;
+        let characteristics = read_u32(image, header + 36)?;
+        Ok((rva..rva + size, characteristics))
+    }))
+}
+
+/// The kernel image relocated to and mapped at the randomized base address
+#[derive(Debug)]
+pub struct RelocatedKernel {
+    /// Address where the firmware loaded the image
+    pub image_base: u64,
+    pub base: u64,
+    pub phys: u64,
+    pub size: u64,
+    pub num_relocations: usize,
+}
+
+impl RelocatedKernel {
+    /// Returns the address in the relocated image which corresponds to addr
+    /// in the loaded image.
+    pub fn virt_of(&self, addr: u64) -> u64 {
+        addr - self.image_base + self.base
+    }
+    /// Returns true if the caller runs in the relocated image.
+    pub fn is_running(&self) -> bool {
+        let addr = Self::is_running as usize as u64;
+        (self.base..self.base + self.size).contains(&addr)
+    }
+    /// Copies the current state of the loaded image, applies the base
+    /// relocations, and continues with f in the relocated image. Both images
+    /// are W^X-protected before f is called with the relocated kernel. f
+    /// should not return.
+    ///
+    /// # Safety
+    /// Should be called with nothing borrowed from the statics, since they are
+    /// copied as is. Function pointers stored into the statics at runtime
+    /// still point to the loaded image, which will not be executable.
+    pub unsafe fn enter<F: FnOnce(&RelocatedKernel)>(mut self, f: F) -> ! {
+        fn start<F: FnOnce(&RelocatedKernel)>(kernel: &RelocatedKernel, f: *mut F) -> ! {
+            kernel
+                .protect_images()
+                .expect("Failed to protect the kernel images");
+            unsafe { f.read()(kernel) };
+            panic!("The relocated kernel returned");
+        }
+        let image = core::slice::from_raw_parts_mut(self.base as *mut u8, self.size as usize);
+        core::ptr::copy_nonoverlapping(
+            self.image_base as *const u8,
+            image.as_mut_ptr(),
+            image.len(),
+        );
+        self.num_relocations =
+            apply_base_relocations(image, self.base.wrapping_sub(self.image_base))
+                .expect("Failed to relocate the kernel image");
+        // No statics should be changed from here
+        let mut f = ManuallyDrop::new(f);
+        let start = core::mem::transmute::<u64, fn(&RelocatedKernel, *mut F) -> !>(
+            self.virt_of(start::<F> as usize as u64),
+        );
+        start(&self, &mut *f as *mut F)
+    }
+    // Makes the sections of the relocated image W^X following their
+    // characteristics, and the loaded image read-only and non-executable.
+    fn protect_images(&self) -> Result<()> {
+        const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+        const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+        let table = unsafe { &mut *current_page_table() };
+        let image =
+            unsafe { core::slice::from_raw_parts(self.base as *const u8, self.size as usize) };
+        // The headers and the gaps between the sections
+        table
+            .protect(self.base..self.base + self.size, Protection::READ_ONLY)?
+            .flush();
+        for section in sections(image)? {
+            let (range, characteristics) = section?;
+            let start = self.base + range.start as u64;
+            let end = (self.base + range.end as u64).next_multiple_of(PAGE_SIZE as u64);
+            if start % PAGE_SIZE as u64 != 0 || end > self.base + self.size {
+                return Err("Section is not page-aligned");
+            }
+            if start == end {
+                continue;
+            }
+            let prot = Protection {
+                writable: characteristics & IMAGE_SCN_MEM_WRITE != 0,
+                executable: characteristics & IMAGE_SCN_MEM_EXECUTE != 0,
+                user: false,
+            };
+            if prot.writable && prot.executable {
+                return Err("Section is writable and executable");
+            }
+            table.protect(start..end, prot)?.flush();
+        }
+        table
+            .protect(
+                self.image_base..self.image_base + self.size,
+                Protection::READ_ONLY,
+            )?
+            .flush();
+        Ok(())
+    }
+}
+
+/// Allocates frames for the relocated image and maps them at the randomized
+/// base in the current page table. The image is copied and relocated by
+/// RelocatedKernel::enter().
+pub fn relocate_kernel(image_base: u64, image_size: u64) -> Result<RelocatedKernel> {
+    let layout = kaslr_layout().ok_or("KASLR is not initialized")?;
+    let size = image_size.next_multiple_of(PAGE_SIZE as u64);
+    if size > KERNEL_IMAGE_MAX {
+        return Err("Kernel image is too large");
+    }
+    if image_base % PAGE_SIZE as u64 != 0 {
+        return Err("Kernel image is not page-aligned");
+    }
+    let num_frames = size as usize / PAGE_SIZE;
+    let phys = FRAME_ALLOCATOR.alloc_contiguous_as(num_frames, 1, FrameState::Kernel)?;
+    let base = layout.kernel_base;
+    let result = unsafe {
+        (*current_page_table()).create_mapping(base, base + size, phys, PageAttr::ReadWriteKernel)
+    };
+    if let Err(e) = result {
+        FRAME_ALLOCATOR.free_contiguous(phys, num_frames);
+        return Err(e);
+    }
+    Ok(RelocatedKernel {
+        image_base,
+        base,
+        phys,
+        size,
+        num_relocations: 0,
+    })
+}
@@ -0,0 +1,107 @@
+extern crate alloc;
+
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::result::Result;
+use crate::rmap::rmap_forget_root;
+use crate::x86::interrupt_entry_ranges;
+use crate::x86::PageAttr;
+use crate::x86::PageMapper;
+use crate::x86::Protection;
+use crate::x86::TranslationResult;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::mem::size_of;
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+// カーネルページテーブル分離（KPTI）
+// 分離されたアドレス空間はPML4を2つ持つ。カーネル用はすべてをマップし、ユーザー用は
+// ユーザー空間と、割り込みの入口で必要なページ（エントリコード、GDT、IDT、TSS、
+// 割り込みスタック、KPTI_CR3）だけをマップする
+// inthandler_commonは、リング3からの割り込みでカーネル用のテーブルに、リング3に
+// 戻るときにユーザー用のテーブルにCR3を切り替える。PCIDが有効な場合は別々のPCIDを
+// 使うので、切り替えでTLBはフラッシュされない
+// ユーザー用のテーブルでは、エントリコードは読み出しと実行のみ、GDT、IDT、TSS、
+// KPTI_CR3は読み出しのみ、書き込めるのは割り込みスタックだけにする
+// 4レベルページングのみ対応
+
+/// Range shared by the kernel and the user tables of an isolated address
+/// space. PML4 entry 0 is excluded since it holds the identity mapping of RAM.
+pub const USER_SPACE: Range<u64> = 0x0000_0080_0000_0000..0x0000_8000_0000_0000;
+pub(crate) const USER_PML4_INDICES: Range<usize> = 1..256;
+
+// Values loaded into CR3 by inthandler_common on the ring transitions. Both
+// are 0 if the active address space is not isolated. This page is mapped in
+// the user tables, so it should not contain anything else.
+#[repr(C, align(4096))]
+struct KptiCr3 {
+    kernel: AtomicU64,
+    user: AtomicU64,
+}
+
+#[no_mangle]
+static KPTI_CR3: KptiCr3 = KptiCr3 {
+    kernel: AtomicU64::new(0),
+    user: AtomicU64::new(0),
+};
+
+pub(crate) fn set_kpti_cr3(kernel: u64, user: u64) {
+    KPTI_CR3.kernel.store(kernel, Ordering::SeqCst);
+    KPTI_CR3.user.store(user, Ordering::SeqCst);
+}
+
+/// Returns the CR3 values used on the entry from / the return to user mode,
+/// or None if the active address space is not isolated.
+pub fn kpti_cr3() -> Option<(u64, u64)> {
+    let kernel = KPTI_CR3.kernel.load(Ordering::SeqCst);
+    let user = KPTI_CR3.user.load(Ordering::SeqCst);
+    (kernel != 0).then_some((kernel, user))
+}
+
+/// Creates the user table for the kernel table of an isolated address space.
+pub(crate) fn new_user_table(kernel: &PML4) -> Result<Box<PML4>> {
+    let mut user = PML4::new();
+    user.share_entries(kernel, USER_PML4_INDICES);
+    let cr3 = &KPTI_CR3 as *const KptiCr3 as u64;
+    let mut ranges = interrupt_entry_ranges();
+    ranges.push((
+        cr3..cr3 + size_of::<KptiCr3>() as u64,
+        Protection::READ_ONLY,
+    ));
+    // A page shared by multiple ranges gets all the permissions they need
+    let mut pages: BTreeMap<u64, Protection> = BTreeMap::new();
+    for (range, prot) in ranges {
+        let start = range.start & !(PAGE_SIZE as u64 - 1);
+        for virt in (start..range.end).step_by(PAGE_SIZE) {
+            let page = pages.entry(virt).or_insert(Protection::READ_ONLY);
+            page.writable |= prot.writable;
+            page.executable |= prot.executable;
+        }
+    }
+    for (virt, prot) in pages {
+        let phys = match kernel.translate(virt)? {
+            TranslationResult::PageMapped4K { phys }
+            | TranslationResult::PageMapped2M { phys }
+            | TranslationResult::PageMapped1G { phys } => phys & !(PAGE_SIZE as u64 - 1),
+        };
+        let end = virt + PAGE_SIZE as u64;
+        user.create_mapping(virt, end, phys, PageAttr::ReadOnlyKernel)?;
+        // The user table has never been loaded
+        user.protect(virt..end, prot)?.ignore();
+    }
+    Ok(user)
+}
+
+/// Frees the user table and the lower level tables which are not shared
+/// with the kernel table.
+pub(crate) fn free_user_table(mut user: Box<PML4>) {
+    let root = user.as_ref() as *const PML4 as u64;
+    rmap_forget_root(root);
+    user.free_lower_tables(0..USER_PML4_INDICES.start);
+    user.free_lower_tables(USER_PML4_INDICES.end..512);
+    FRAME_ALLOCATOR.set_frame_state(root, FrameState::Heap);
+}
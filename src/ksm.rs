@@ -0,0 +1,134 @@
+extern crate alloc;
+
+use crate::result::Result;
+use crate::swap::merge_anonymous_page;
+use crate::swap::resident_anonymous_pages;
+use crate::swap::share_anonymous_page;
+use crate::x86::current_page_table;
+use crate::x86::ATTR_PRESENT;
+use crate::x86::ENTRY_ADDR_MASK;
+use crate::x86::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use core::fmt;
+
+// 同一ページのマージ（LinuxのKSMのようなもの）
+// 常駐している無名ページの内容のハッシュを取り、同じ内容のページを見つけたら
+// 1つのフレームを読み込み専用で共有させる。書き込まれるとCOWで再び分かれる
+// 中身がすべてゼロのページは共有のゼロページに寄せる
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KsmStats {
+    pub full_scans: u64,
+    pub pages_scanned: u64,
+    /// Pages mapped to a frame of another page with the same content
+    pub pages_merged: u64,
+    /// Pages mapped to the zero frame
+    pub zero_pages_merged: u64,
+}
+impl fmt::Display for KsmStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "KSM: {} full scans, {} pages scanned, {} merged, {} merged to zero",
+            self.full_scans, self.pages_scanned, self.pages_merged, self.zero_pages_merged
+        )
+    }
+}
+
+// FNV-1a
+fn hash_page(page: &[u8; PAGE_SIZE]) -> u64 {
+    page.iter().fold(0xCBF2_9CE4_8422_2325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+fn page_at(virt: u64) -> &'static [u8; PAGE_SIZE] {
+    unsafe { &*(virt as *const [u8; PAGE_SIZE]) }
+}
+
+// Returns the frame mapped at virt if it is present
+fn mapped_frame(virt: u64) -> Option<u64> {
+    let pte = unsafe { (*current_page_table()).read_pte(virt) }.ok()?;
+    (pte & ATTR_PRESENT != 0).then_some(pte & ENTRY_ADDR_MASK)
+}
+
+/// Scans the resident anonymous pages incrementally and merges the pages
+/// with the same content.
+pub struct KsmScanner {
+    // Index in the resident pages where the next scan starts
+    cursor: usize,
+    // Content hash => (virt, phys) of a page seen in the current full scan
+    candidates: BTreeMap<u64, (u64, u64)>,
+    stats: KsmStats,
+}
+
+impl KsmScanner {
+    pub fn new() -> Self {
+        Self {
+            cursor: 0,
+            candidates: BTreeMap::new(),
+            stats: KsmStats::default(),
+        }
+    }
+    pub fn stats(&self) -> KsmStats {
+        self.stats
+    }
+    /// Scans up to budget pages. Returns the number of pages merged.
+    pub fn scan(&mut self, budget: usize) -> Result<usize> {
+        let pages = resident_anonymous_pages();
+        let mut merged = 0;
+        for _ in 0..budget {
+            if self.cursor >= pages.len() {
+                // Hashes are only compared within a full scan, since the
+                // pages may have been modified since then.
+                self.cursor = 0;
+                self.candidates.clear();
+                self.stats.full_scans += 1;
+                break;
+            }
+            let virt = pages[self.cursor];
+            self.cursor += 1;
+            if self.scan_page(virt)? {
+                merged += 1;
+            }
+        }
+        Ok(merged)
+    }
+    fn scan_page(&mut self, virt: u64) -> Result<bool> {
+        // The page may have been swapped out or freed after the list was taken
+        let Some(phys) = mapped_frame(virt) else {
+            return Ok(false);
+        };
+        self.stats.pages_scanned += 1;
+        let page = page_at(virt);
+        if page.iter().all(|b| *b == 0) {
+            merge_anonymous_page(virt, None)?;
+            self.stats.zero_pages_merged += 1;
+            return Ok(true);
+        }
+        let hash = hash_page(page);
+        match self.candidates.get(&hash) {
+            Some(&(cvirt, cphys))
+                if cvirt != virt
+                    && cphys != phys
+                    && mapped_frame(cvirt) == Some(cphys)
+                    && page_at(cvirt) == page =>
+            {
+                let target = share_anonymous_page(cvirt)?;
+                merge_anonymous_page(virt, Some(target))?;
+                self.stats.pages_merged += 1;
+                Ok(true)
+            }
+            _ => {
+                self.candidates.insert(hash, (virt, phys));
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl Default for KsmScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,100 @@
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::result::Result;
+use crate::x86::current_page_table;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use core::cell::RefCell;
+
+// カーネルスタック（RSP0 / IST）専用の仮想アドレス領域
+// 各スタックの直下にマップされないガードページを置くことで、
+// スタックオーバーフローを隣接領域の破壊ではなくフォルトとして検出する
+// UEFIから引き継いだブートスタック（efi_mainが動いているスタック）はここに無く、
+// 下端が分からないのでガードページも無い。ブートスタックでのオーバーフローは
+// 検出できず、その下のメモリを壊す
+pub const KERNEL_STACK_REGION_START: u64 = 0xFFFF_C000_0000_0000;
+pub const KERNEL_STACK_REGION_SIZE: u64 = 1 << 30;
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
+const GUARD_SIZE: u64 = PAGE_SIZE as u64;
+const MAX_KERNEL_STACKS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct KernelStack {
+    name: &'static str,
+    // The guard page is placed at [guard_start, guard_start + GUARD_SIZE)
+    // and the stack itself follows it.
+    guard_start: u64,
+}
+impl KernelStack {
+    fn guard_contains(&self, addr: u64) -> bool {
+        (self.guard_start..self.guard_start + GUARD_SIZE).contains(&addr)
+    }
+}
+
+struct KernelStackRegion {
+    next_addr: u64,
+    stacks: [Option<KernelStack>; MAX_KERNEL_STACKS],
+}
+
+pub struct KernelStackAllocator {
+    region: RefCell<KernelStackRegion>,
+}
+
+unsafe impl Sync for KernelStackAllocator {}
+
+static KERNEL_STACKS: KernelStackAllocator = KernelStackAllocator {
+    region: RefCell::new(KernelStackRegion {
+        next_addr: KERNEL_STACK_REGION_START,
+        stacks: [None; MAX_KERNEL_STACKS],
+    }),
+};
+
+/// Allocates a stack in the kernel stack region with an unmapped guard page
+/// below it, and returns the address of the top of the stack (initial RSP).
+/// The mapping is created in the page table currently loaded in CR3.
+pub fn alloc_kernel_stack(name: &'static str) -> Result<u64> {
+    let mut region = KERNEL_STACKS.region.borrow_mut();
+    let slot = region
+        .stacks
+        .iter()
+        .position(|s| s.is_none())
+        .ok_or("Too many kernel stacks")?;
+    let guard_start = region.next_addr;
+    let stack_start = guard_start + GUARD_SIZE;
+    let stack_end = stack_start + KERNEL_STACK_SIZE as u64;
+    if stack_end > KERNEL_STACK_REGION_START + KERNEL_STACK_REGION_SIZE {
+        return Err("Kernel stack region exhausted");
+    }
+    // The backing frames are never freed, same as the stack itself.
+    let num_frames = KERNEL_STACK_SIZE / PAGE_SIZE;
+    let phys = FRAME_ALLOCATOR.alloc_contiguous_as(num_frames, 1, FrameState::Kernel)?;
+    let result = unsafe {
+        core::ptr::write_bytes(phys as *mut u8, 0, KERNEL_STACK_SIZE);
+        (*current_page_table()).create_mapping(
+            stack_start,
+            stack_end,
+            phys,
+            PageAttr::ReadWriteKernel,
+        )
+    };
+    if let Err(e) = result {
+        FRAME_ALLOCATOR.free_contiguous(phys, num_frames);
+        return Err(e);
+    }
+    region.next_addr = stack_end;
+    region.stacks[slot] = Some(KernelStack { name, guard_start });
+    Ok(stack_end)
+}
+
+/// Returns the name of the stack whose guard page contains addr.
+pub fn find_stack_by_guard_page(addr: u64) -> Option<&'static str> {
+    // This is called from exception handlers, so it should not panic even if
+    // the fault happened during alloc_kernel_stack().
+    let region = KERNEL_STACKS.region.try_borrow().ok()?;
+    region
+        .stacks
+        .iter()
+        .flatten()
+        .find(|s| s.guard_contains(addr))
+        .map(|s| s.name)
+}
@@ -0,0 +1,255 @@
+use crate::frame::FrameState;
+use crate::frame::Zone;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::result::Result;
+use crate::x86::is_la57_enabled;
+use crate::x86::is_la57_supported;
+use crate::x86::read_cr4;
+use crate::x86::write_cr3;
+use crate::x86::CR4_PCIDE;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML4;
+use crate::x86::PML5;
+use core::arch::asm;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::mem::size_of;
+
+// 5レベルページング（LA57）への切り替え
+// CR4.LA57はIA-32eモードの間は変更できないので、4GiB未満に置いたトランポリンで
+// 一旦32ビットのコードセグメントに移ってページングを無効にし、LA57を立ててから
+// ページングを有効にし直して64ビットモードに戻ってくる
+// 32ビットモードではCR3に32ビットの値しか書けないので、PML5の複製もトランポリンと
+// 一緒に4GiB未満に置き、64ビットモードに戻ってから本物のPML5に切り替える
+// SDM Vol.3: 10.8.5 Initializing IA-32e Mode, 10.8.5.4 Switching Out of IA-32e Mode
+
+// Layout of the trampoline: page 0 has the code and TrampolineData at
+// DATA_OFFSET, page 1 has the copy of the PML5 loaded in 32-bit mode.
+const DATA_OFFSET: usize = 0x800;
+const NUM_PAGES: usize = 2;
+
+// Selectors in the temporary GDT
+const CODE64_SEL: u16 = 1 << 3;
+const CODE32_SEL: u16 = 2 << 3;
+const DATA_SEL: u16 = 3 << 3;
+// Flat segments with the limit of 4GiB
+const GDT: [u64; 4] = [
+    0,
+    0x00AF_9A00_0000_FFFF, // 64-bit code
+    0x00CF_9A00_0000_FFFF, // 32-bit code
+    0x00CF_9200_0000_FFFF, // Data
+];
+
+// Operand of ljmp (m16:32)
+#[repr(C)]
+struct FarPointer {
+    offset: u32,
+    selector: u16,
+    _pad: u16,
+}
+
+// Operand of lgdt and sgdt
+#[repr(C, packed)]
+struct GdtrParameters {
+    limit: u16,
+    base: u64,
+}
+
+// Values used by the trampoline code. The upper halves of the registers are
+// undefined after leaving 64-bit mode, so the 64-bit values are kept here.
+#[repr(C)]
+struct TrampolineData {
+    entry32: FarPointer,
+    entry64: FarPointer,
+    gdtr: GdtrParameters,
+    saved_gdtr: GdtrParameters,
+    // Physical address of the copy of the PML5, which is below 4GiB
+    temp_cr3: u64,
+    cr3: u64,
+    rsp: u64,
+    rip: u64,
+    cs: u64,
+    ds: u64,
+    es: u64,
+    ss: u64,
+    gdt: [u64; 4],
+}
+const _: () = assert!(DATA_OFFSET + size_of::<TrampolineData>() <= PAGE_SIZE);
+
+// Runs at a copy in the trampoline page with rsi pointing to the page.
+// Interrupts should be disabled.
+global_asm!(
+    ".global la57_trampoline_start",
+    ".global la57_trampoline_32",
+    ".global la57_trampoline_64",
+    ".global la57_trampoline_end",
+    ".code64",
+    "la57_trampoline_start:",
+    "lgdt {gdtr}(%rsi)",
+    "ljmpl *{entry32}(%rsi)",
+    ".code32",
+    "la57_trampoline_32:",
+    // Data accesses in the compatibility mode go through DS
+    "movw ${data_sel}, %ax",
+    "movw %ax, %ds",
+    "movw %ax, %es",
+    "movw %ax, %ss",
+    // Disabling paging leaves IA-32e mode (IA32_EFER.LMA is cleared)
+    "movl %cr0, %eax",
+    "andl $0x7FFFFFFF, %eax",
+    "movl %eax, %cr0",
+    "movl %cr4, %eax",
+    "orl $0x1000, %eax",
+    "movl %eax, %cr4",
+    "movl {temp_cr3}(%esi), %eax",
+    "movl %eax, %cr3",
+    // IA32_EFER.LME is still set, so this enters IA-32e mode again
+    "movl %cr0, %eax",
+    "orl $0x80000000, %eax",
+    "movl %eax, %cr0",
+    "ljmpl *{entry64}(%esi)",
+    ".code64",
+    "la57_trampoline_64:",
+    "movl %esi, %esi",
+    "movq {cr3}(%rsi), %rax",
+    "movq %rax, %cr3",
+    "lgdt {saved_gdtr}(%rsi)",
+    "movq {rsp}(%rsi), %rsp",
+    "movq {ds}(%rsi), %rax",
+    "movw %ax, %ds",
+    "movq {es}(%rsi), %rax",
+    "movw %ax, %es",
+    "movq {ss}(%rsi), %rax",
+    "movw %ax, %ss",
+    "pushq {cs}(%rsi)",
+    "pushq {rip}(%rsi)",
+    "lretq",
+    "la57_trampoline_end:",
+    gdtr = const DATA_OFFSET + offset_of!(TrampolineData, gdtr),
+    entry32 = const DATA_OFFSET + offset_of!(TrampolineData, entry32),
+    entry64 = const DATA_OFFSET + offset_of!(TrampolineData, entry64),
+    data_sel = const DATA_SEL,
+    temp_cr3 = const DATA_OFFSET + offset_of!(TrampolineData, temp_cr3),
+    cr3 = const DATA_OFFSET + offset_of!(TrampolineData, cr3),
+    saved_gdtr = const DATA_OFFSET + offset_of!(TrampolineData, saved_gdtr),
+    rsp = const DATA_OFFSET + offset_of!(TrampolineData, rsp),
+    ds = const DATA_OFFSET + offset_of!(TrampolineData, ds),
+    es = const DATA_OFFSET + offset_of!(TrampolineData, es),
+    ss = const DATA_OFFSET + offset_of!(TrampolineData, ss),
+    cs = const DATA_OFFSET + offset_of!(TrampolineData, cs),
+    rip = const DATA_OFFSET + offset_of!(TrampolineData, rip),
+    options(att_syntax)
+);
+
+extern "C" {
+    fn la57_trampoline_start();
+    fn la57_trampoline_32();
+    fn la57_trampoline_64();
+    fn la57_trampoline_end();
+}
+
+/// Switches to 5-level paging with pml5 as the root table. pml5 should
+/// identity-map the memory below 4GiB and the running kernel. Does nothing
+/// but loading pml5 if LA57 is already enabled.
+///
+/// # Safety
+/// Same as write_cr3(). PCID should not be enabled, since it prevents
+/// disabling paging.
+pub unsafe fn switch_to_la57(pml5: &PML5) -> Result<()> {
+    let root = pml5 as *const PML5 as *const PML4;
+    if is_la57_enabled() {
+        write_cr3(root);
+        return Ok(());
+    }
+    if !is_la57_supported() {
+        return Err("LA57 is not supported");
+    }
+    if read_cr4() & CR4_PCIDE != 0 {
+        return Err("Paging can not be disabled with PCID enabled");
+    }
+    let start = la57_trampoline_start as usize;
+    let code_size = la57_trampoline_end as usize - start;
+    if code_size > DATA_OFFSET {
+        return Err("LA57 trampoline is too large");
+    }
+    let page =
+        FRAME_ALLOCATOR.alloc_contiguous_in(NUM_PAGES, 1, FrameState::Kernel, Zone::Dma32)?;
+    let temp_pml5 = page + PAGE_SIZE as u64;
+    core::ptr::copy_nonoverlapping(start as *const u8, page as *mut u8, code_size);
+    core::ptr::copy_nonoverlapping(root as *const u8, temp_pml5 as *mut u8, PAGE_SIZE);
+    let data = &mut *((page as usize + DATA_OFFSET) as *mut TrampolineData);
+    *data = TrampolineData {
+        entry32: FarPointer {
+            offset: (page as usize + la57_trampoline_32 as usize - start) as u32,
+            selector: CODE32_SEL,
+            _pad: 0,
+        },
+        entry64: FarPointer {
+            offset: (page as usize + la57_trampoline_64 as usize - start) as u32,
+            selector: CODE64_SEL,
+            _pad: 0,
+        },
+        gdtr: GdtrParameters {
+            limit: (size_of::<[u64; 4]>() - 1) as u16,
+            base: page + (DATA_OFFSET + offset_of!(TrampolineData, gdt)) as u64,
+        },
+        saved_gdtr: GdtrParameters { limit: 0, base: 0 },
+        temp_cr3: temp_pml5,
+        cr3: root as u64,
+        rsp: 0,
+        rip: 0,
+        cs: 0,
+        ds: 0,
+        es: 0,
+        ss: 0,
+        gdt: GDT,
+    };
+    // Registers which may be broken by the trip through 32-bit mode are
+    // saved on the stack or marked as clobbered.
+    asm!(
+        "pushfq",
+        "cli",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "lea rax, [rip + 2f]",
+        "mov [rsi + {rip}], rax",
+        "mov [rsi + {rsp}], rsp",
+        "xor eax, eax",
+        "mov ax, cs",
+        "mov [rsi + {cs}], rax",
+        "mov ax, ds",
+        "mov [rsi + {ds}], rax",
+        "mov ax, es",
+        "mov [rsi + {es}], rax",
+        "mov ax, ss",
+        "mov [rsi + {ss}], rax",
+        "sgdt [rsi + {saved_gdtr}]",
+        "jmp rsi",
+        "2:",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "popfq",
+        rip = const DATA_OFFSET + offset_of!(TrampolineData, rip),
+        rsp = const DATA_OFFSET + offset_of!(TrampolineData, rsp),
+        cs = const DATA_OFFSET + offset_of!(TrampolineData, cs),
+        ds = const DATA_OFFSET + offset_of!(TrampolineData, ds),
+        es = const DATA_OFFSET + offset_of!(TrampolineData, es),
+        ss = const DATA_OFFSET + offset_of!(TrampolineData, ss),
+        saved_gdtr = const DATA_OFFSET + offset_of!(TrampolineData, saved_gdtr),
+        inout("rsi") page => _,
+        clobber_abi("sysv64"),
+    );
+    FRAME_ALLOCATOR.free_contiguous(page, NUM_PAGES);
+    if !is_la57_enabled() {
+        return Err("Failed to enable LA57");
+    }
+    Ok(())
+}
@@ -1,14 +1,41 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#![feature(asm_const)]
 #![feature(offset_of)]
 
 extern crate alloc;
 
+pub mod access_trace;
+pub mod address_space;
 pub mod allocator;
+pub mod compaction;
+pub mod extable;
+pub mod frame;
+// Bitmap::unchecked_pixel_at_mut() has no # Safety section
+#[allow(clippy::missing_safety_doc)]
 pub mod graphics;
 pub mod init;
+pub mod ioremap;
+pub mod kaslr;
+pub mod kpti;
+pub mod kstack;
+pub mod la57;
+pub mod ksm;
+pub mod memblock;
+pub mod meminfo;
+pub mod mmu_difftest;
+pub mod mmu_model;
+pub mod pci;
 pub mod print;
+pub mod ptcheck;
 pub mod qemu;
 pub mod result;
+pub mod rmap;
 pub mod serial;
+pub mod swap;
+pub mod thp;
+pub mod uaccess;
 pub mod uefi;
+pub mod virtio_blk;
+pub mod vmalloc;
+pub mod working_set;
 pub mod x86;
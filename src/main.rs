@@ -2,33 +2,123 @@
 #![no_main]
 #![feature(offset_of)]
 
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::writeln;
+use wasabi::access_trace::take_trace_events;
+use wasabi::access_trace::trace_range;
+use wasabi::access_trace::untrace_all;
+use wasabi::address_space::flush_tlb_page_all_address_spaces;
+use wasabi::address_space::AddressSpace;
+use wasabi::compaction::compact_memory;
+use wasabi::compaction::is_free_block;
+use wasabi::compaction::HUGE_BLOCK_FRAMES;
 use wasabi::error;
+use wasabi::extable::exception_table;
+use wasabi::frame::frame_info;
+use wasabi::frame::FrameState;
+use wasabi::frame::Zone;
+use wasabi::frame::FRAME_ALLOCATOR;
+use wasabi::frame::FRAME_FLAG_COW;
 use wasabi::graphics::draw_test_pattern;
 use wasabi::graphics::fill_rect;
 use wasabi::graphics::Bitmap;
 use wasabi::info;
 use wasabi::init::init_basic_runtime;
 use wasabi::init::init_paging;
+use wasabi::ioremap::CacheMode;
+use wasabi::ioremap::IoMapping;
+use wasabi::ioremap::Register;
+use wasabi::kaslr::direct_map_range;
+use wasabi::kaslr::kaslr_layout;
+use wasabi::kaslr::phys_to_virt;
+use wasabi::kaslr::relocate_kernel;
+use wasabi::kaslr::virt_to_phys;
+use wasabi::kaslr::RelocatedKernel;
+use wasabi::kpti::kpti_cr3;
+use wasabi::ksm::KsmScanner;
+use wasabi::kstack::alloc_kernel_stack;
+use wasabi::kstack::find_stack_by_guard_page;
+use wasabi::meminfo::meminfo;
+use wasabi::mmu_difftest::run_mmu_diff_test;
 use wasabi::print::hexdump;
 use wasabi::println;
+use wasabi::ptcheck::check_current_page_table;
+use wasabi::ptcheck::PageTableCheckConfig;
+use wasabi::ptcheck::PageTableIssueKind;
 use wasabi::qemu::exit_qemu;
 use wasabi::qemu::QemuExitCode;
+use wasabi::rmap::for_each_mapping;
+use wasabi::rmap::mappings_of;
+use wasabi::rmap::unmap_frame;
+use wasabi::swap::alloc_anonymous;
+use wasabi::swap::free_anonymous;
+use wasabi::swap::init_swap;
+use wasabi::swap::probe_swap_device;
+use wasabi::swap::set_resident_limit;
+use wasabi::swap::sharing_stats;
+use wasabi::swap::swap_out_pages;
+use wasabi::swap::swap_stats;
+use wasabi::thp::num_huge_pages;
+use wasabi::thp::promote_huge_pages;
+use wasabi::thp::split_huge_page;
+use wasabi::uaccess::copy_from_user;
+use wasabi::uaccess::copy_to_user;
+use wasabi::uaccess::EFAULT;
 use wasabi::uefi::init_vram;
 use wasabi::uefi::locate_loaded_image_protocol;
 use wasabi::uefi::EfiHandle;
 use wasabi::uefi::EfiMemoryType;
 use wasabi::uefi::EfiSystemTable;
+use wasabi::uefi::MemoryMapHolder;
+use wasabi::uefi::VramBufferInfo;
 use wasabi::uefi::VramTextWriter;
+use wasabi::vmalloc::vfree;
+use wasabi::vmalloc::vmalloc;
 use wasabi::warn;
-use wasabi::x86::flush_tlb;
+use wasabi::working_set::WorkingSetScanner;
+use wasabi::x86::current_page_table;
+use wasabi::x86::flush_tlb_page;
 use wasabi::x86::hlt;
 use wasabi::x86::init_exceptions;
+use wasabi::x86::interrupt_entry_ranges;
+use wasabi::x86::is_la57_enabled;
+use wasabi::x86::is_la57_supported;
+use wasabi::x86::is_nx_enabled;
+use wasabi::x86::is_pcid_enabled;
+use wasabi::x86::is_smap_enabled;
+use wasabi::x86::is_smep_enabled;
+use wasabi::x86::is_umip_enabled;
+use wasabi::x86::is_write_combining_enabled;
+use wasabi::x86::probe_execute;
+use wasabi::x86::probe_read;
+use wasabi::x86::probe_stack_overflow;
+use wasabi::x86::rdtsc;
 use wasabi::x86::read_cr3;
+use wasabi::x86::read_gdtr_base;
+use wasabi::x86::read_idtr_base;
+use wasabi::x86::read_msr;
+use wasabi::x86::read_tss_base;
+use wasabi::x86::run_user_code;
 use wasabi::x86::trigger_debug_interrupt;
+use wasabi::x86::write_cr3_with_pcid;
 use wasabi::x86::PageAttr;
+use wasabi::x86::PageFaultError;
+use wasabi::x86::Protection;
+use wasabi::x86::ATTR_NO_EXECUTE;
+use wasabi::x86::ATTR_WRITABLE;
+use wasabi::x86::ENTRY_ADDR_MASK;
+use wasabi::x86::HUGE_PAGE_SIZE;
+use wasabi::x86::MSR_IA32_APIC_BASE;
+use wasabi::x86::PF_ERROR_FETCH;
+use wasabi::x86::PF_ERROR_PRESENT;
+use wasabi::x86::PF_ERROR_RESERVED;
+use wasabi::x86::PF_ERROR_USER;
+use wasabi::x86::PF_ERROR_WRITE;
 
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
@@ -93,26 +183,697 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     let t = t.and_then(|t| t.next_level(0));
     println!("{t:?}");
 
+    // ページング初期化
+    init_paging(&memory_map);
+    info!("Now we are using our own page tables!");
+
+    // KASLR
+    // ランダムに選んだアドレスにカーネルイメージを再配置して、そちらに制御を移す
+    // 以降はkernel_main()が再配置されたイメージ上で動く
+    let layout = kaslr_layout().expect("KASLR is not initialized");
+    info!("{layout}");
+    let kernel = relocate_kernel(
+        loaded_image_protocol.image_base,
+        loaded_image_protocol.image_size,
+    )
+    .expect("relocate_kernel failed");
+    unsafe { kernel.enter(move |kernel| kernel_main(kernel, memory_map, vram)) }
+}
+
+// 再配置されたカーネルイメージ上で動くカーネルの本体
+fn kernel_main(
+    kernel: &RelocatedKernel,
+    memory_map: MemoryMapHolder,
+    mut vram: VramBufferInfo,
+) -> ! {
+    let vw = vram.width();
+    let vh = vram.height();
+
+    // KASLRのテスト
+    // 再配置したイメージで動いていて、再配置したイメージはW^X、元のイメージは
+    // 読み出し専用かつ実行不可になっているはず
+    // ヒープはダイレクトマップ上にあり、ダイレクトマップ経由でも恒等マッピングと同じ
+    // 内容が読めるはず
+    let running = kernel_main as usize as u64;
+    info!(
+        "Kernel image relocated from {:#018X} to {:#018X} ({} relocations), running at {running:#018X}",
+        kernel.image_base, kernel.base, kernel.num_relocations
+    );
+    assert!(kernel.is_running());
+    assert!(kernel.num_relocations > 0);
+    let table = unsafe { &*current_page_table() };
+    let pte = |virt: u64| table.read_pte(virt).expect("read_pte failed");
+    let nx = if is_nx_enabled() { ATTR_NO_EXECUTE } else { 0 };
+    assert_eq!(pte(running) & (ATTR_WRITABLE | ATTR_NO_EXECUTE), 0);
+    let data = &FRAME_ALLOCATOR as *const _ as u64;
+    assert_eq!(pte(data) & (ATTR_WRITABLE | nx), ATTR_WRITABLE | nx);
+    let loaded = kernel.image_base + (running - kernel.base);
+    assert_eq!(pte(loaded) & (ATTR_WRITABLE | nx), nx);
+    let heap = Box::new(0x4B41_534C_5248_4541u64);
+    let heap_addr = heap.as_ref() as *const u64 as u64;
+    info!("Heap @ {heap_addr:#018X}");
+    assert!(direct_map_range().is_some_and(|r| r.contains(&heap_addr)));
+    assert_eq!(
+        unsafe { (virt_to_phys(heap_addr) as *const u64).read_volatile() },
+        *heap
+    );
+    let direct = phys_to_virt(kernel.phys).expect("phys_to_virt failed");
+    assert_eq!(unsafe { (direct as *const u64).read_volatile() }, unsafe {
+        (kernel.base as *const u64).read_volatile()
+    });
+
+    // NULLポインタ参照を検出できるようにページ0をアンマップ
+    let page_table = current_page_table();
+    unsafe {
+        (*page_table)
+            .create_mapping(0, 4096, 0, PageAttr::NotPresent)
+            .expect("Failed to unmap page 0");
+    }
+    flush_tlb_page(0);
+
+    // 5レベルページングのテスト
+    // CPUが対応していればカーネルが自分で切り替えているはずなので、4レベルでは
+    // 非正準な（48ビットを超える）アドレスにマップしてアクセスしてみる
+    assert_eq!(is_la57_enabled(), is_la57_supported());
+    if is_la57_enabled() {
+        const LA57_TEST_VIRT: u64 = 0x0001_0000_0000_0000;
+        let frame = FRAME_ALLOCATOR.alloc_frame().expect("alloc_frame failed");
+        unsafe {
+            *(frame as *mut u64) = 0x5757_5757_5757_5757;
+            (*page_table)
+                .create_mapping(
+                    LA57_TEST_VIRT,
+                    LA57_TEST_VIRT + 4096,
+                    frame,
+                    PageAttr::ReadWriteKernel,
+                )
+                .expect("create_mapping failed");
+            assert_eq!(*(LA57_TEST_VIRT as *const u64), 0x5757_5757_5757_5757);
+            (*page_table)
+                .create_mapping(
+                    LA57_TEST_VIRT,
+                    LA57_TEST_VIRT + 4096,
+                    0,
+                    PageAttr::NotPresent,
+                )
+                .expect("create_mapping failed");
+        }
+        flush_tlb_page(LA57_TEST_VIRT);
+        FRAME_ALLOCATOR.free_frame(frame);
+        info!("Accessed {LA57_TEST_VIRT:#018X} with 5-level paging");
+    } else {
+        info!("5-level paging is not supported (try cargo run -- --la57)");
+    }
+
     // 例外ハンドラ初期化
+    // 割り込みスタックは自前のページテーブル上にマップされるので、
+    // ページングの初期化より後に行う
+    // GDT、IDT、TSSはKASLRで選んだアドレスに置かれる
     let (_gdt, _idt) = init_exceptions();
     info!("Exception initialized!");
+    let cpu_tables = [read_gdtr_base(), read_idtr_base(), read_tss_base()];
+    info!(
+        "GDT @ {:#018X}, IDT @ {:#018X}, TSS @ {:#018X}",
+        cpu_tables[0], cpu_tables[1], cpu_tables[2]
+    );
+    for addr in cpu_tables {
+        assert!(
+            addr >= 0xFFFF_8000_0000_0000,
+            "CPU table is not in the kernel space"
+        );
+        assert_eq!(pte(addr) & (ATTR_WRITABLE | nx), ATTR_WRITABLE | nx);
+    }
 
     // デバッグ割り込みのテスト
     trigger_debug_interrupt();
     info!("Execution continued.");
 
-    // ページング初期化
-    init_paging(&memory_map);
-    info!("Now we are using our own page tables!");
+    // ガードページのテスト
+    // ガードページ付きのスタックをあふれさせると、#PFを同じスタックに積めずに#DFになり、
+    // #DFのハンドラがどのスタックか報告するはず
+    let top = alloc_kernel_stack("overflow test").expect("alloc_kernel_stack failed");
+    let guard = unsafe { probe_stack_overflow(top) }.expect_err("Stack overflow was not caught");
+    assert_eq!(find_stack_by_guard_page(guard), Some("overflow test"));
+    info!("Stack overflow detected at {guard:#018X}");
 
-    // NULLポインタ参照を検出できるようにページ0をアンマップ
-    let page_table = read_cr3();
+    // ブートサービスが使っていたメモリを回収
+    // ファームウェアのページテーブルやGDTはもう使っていないので、ここで回収できる
+    // Loaded Image Protocolもこの領域にあるので、以降は参照しない
+    let free_before = FRAME_ALLOCATOR.num_free_frames();
+    let reclaim = FRAME_ALLOCATOR.reclaim_boot_memory(&memory_map, true);
+    info!("{reclaim}");
+    assert_eq!(
+        FRAME_ALLOCATOR.num_free_frames(),
+        free_before + reclaim.num_reclaimed()
+    );
+
+    // 物理メモリのゾーンのテスト
+    // 制約のない確保は上のゾーンから、DMA用の確保は指定したゾーン以下から取られる
+    for zone in Zone::ALL {
+        info!(
+            "Zone {zone:?}: {} frames free",
+            FRAME_ALLOCATOR.num_free_frames_in(zone)
+        );
+    }
+    assert_eq!(
+        Zone::ALL
+            .map(|z| FRAME_ALLOCATOR.num_free_frames_in(z))
+            .iter()
+            .sum::<usize>(),
+        FRAME_ALLOCATOR.num_free_frames()
+    );
+    for zone in [Zone::Dma16, Zone::Dma32] {
+        let free_before = FRAME_ALLOCATOR.num_free_frames_in(zone);
+        if free_before == 0 {
+            continue;
+        }
+        let phys = FRAME_ALLOCATOR
+            .alloc_contiguous_in(1, 1, FrameState::Kernel, zone)
+            .expect("alloc_contiguous_in failed");
+        assert!(phys < zone.range().end);
+        // 上のゾーンから順に探すので、空きがあるならそのゾーンから取られる
+        assert_eq!(Zone::of(phys), zone);
+        assert_eq!(FRAME_ALLOCATOR.num_free_frames_in(zone), free_before - 1);
+        FRAME_ALLOCATOR.free_frame(phys);
+        assert_eq!(FRAME_ALLOCATOR.num_free_frames_in(zone), free_before);
+    }
+    const DMA_TEST_MAX_ADDR: u64 = 1 << 30;
+    let phys = FRAME_ALLOCATOR
+        .alloc_contiguous_below(4, 4, FrameState::Kernel, DMA_TEST_MAX_ADDR)
+        .expect("alloc_contiguous_below failed");
+    assert!(phys + 4 * 4096 <= DMA_TEST_MAX_ADDR);
+    assert_eq!(phys % (4 * 4096), 0);
+    FRAME_ALLOCATOR.free_contiguous(phys, 4);
+    info!("Physical memory zones work!");
+
+    // vmallocのテスト（物理的に不連続なフレームを仮想的に連続した領域へ）
+    const VMALLOC_TEST_SIZE: usize = 4 * 1024 * 1024;
+    let buf = vmalloc(VMALLOC_TEST_SIZE).expect("vmalloc failed");
+    unsafe { buf.write_bytes(0xAA, VMALLOC_TEST_SIZE) };
+    vfree(buf).expect("vfree failed");
+    info!("vmalloc/vfree works!");
+
+    // アクセスビットによるワーキングセット推定のテスト
+    const WSS_TEST_PAGES: usize = 16;
+    let buf = vmalloc(WSS_TEST_PAGES * 4096).expect("vmalloc failed");
+    let mut scanner =
+        WorkingSetScanner::new(buf as u64..buf as u64 + (WSS_TEST_PAGES * 4096) as u64);
+    scanner.scan_current();
+    unsafe { buf.write_bytes(0x55, WSS_TEST_PAGES * 4096) };
+    let all = scanner.scan_current();
+    for i in 0..WSS_TEST_PAGES / 4 {
+        unsafe { buf.add(i * 4096).read_volatile() };
+    }
+    let some = scanner.scan_current();
+    info!("working set: {all}, then {some}");
+    assert_eq!(all.touched.len(), WSS_TEST_PAGES);
+    assert_eq!(some.touched.len(), WSS_TEST_PAGES / 4);
+    assert_eq!(
+        scanner.working_set_size(1),
+        (WSS_TEST_PAGES / 4 * 4096) as u64
+    );
+    assert_eq!(scanner.working_set_size(2), (WSS_TEST_PAGES * 4096) as u64);
+    vfree(buf).expect("vfree failed");
+
+    // スワップのテスト（常駐ページ数を制限して、追い出したページの内容が戻ってくるか確認）
+    const SWAP_TEST_PAGES: usize = 64;
+    let swap_device = probe_swap_device(1024);
+    info!("Swap device: {}", swap_device.name());
+    init_swap(swap_device).expect("init_swap failed");
+    set_resident_limit(SWAP_TEST_PAGES / 4);
+    let anon = alloc_anonymous(SWAP_TEST_PAGES * 4096).expect("alloc_anonymous failed");
+    for i in 0..SWAP_TEST_PAGES {
+        unsafe { (anon.add(i * 4096) as *mut usize).write_volatile(i) };
+    }
+    for i in 0..SWAP_TEST_PAGES {
+        assert_eq!(
+            unsafe { (anon.add(i * 4096) as *const usize).read_volatile() },
+            i
+        );
+    }
+    info!("Swap: {}", swap_stats());
+    free_anonymous(anon).expect("free_anonymous failed");
+    set_resident_limit(0);
+
+    // スワップ領域が一杯になっても、追い出せなかったページが失われないか確認
+    let num_slots = swap_stats().num_slots;
+    if num_slots <= 4096 {
+        let num_pages = num_slots + 8;
+        let anon = alloc_anonymous(num_pages * 4096).expect("alloc_anonymous failed");
+        for i in 0..num_pages {
+            unsafe { (anon.add(i * 4096) as *mut usize).write_volatile(i) };
+        }
+        assert_eq!(swap_out_pages(num_pages), num_slots);
+        let stats = swap_stats();
+        assert_eq!(stats.swapped_pages, num_slots);
+        assert_eq!(stats.resident_pages, num_pages - num_slots);
+        for i in 0..num_pages {
+            assert_eq!(
+                unsafe { (anon.add(i * 4096) as *const usize).read_volatile() },
+                i
+            );
+        }
+        info!("Swap (full): {}", swap_stats());
+        free_anonymous(anon).expect("free_anonymous failed");
+    }
+
+    // ゼロページ共有とKSMのテスト
+    // 読み込みだけならゼロページが共有され、書き込むとCOWで分かれる
+    const KSM_TEST_PAGES: usize = 16;
+    let anon = alloc_anonymous(KSM_TEST_PAGES * 4096).expect("alloc_anonymous failed");
+    for i in 0..KSM_TEST_PAGES {
+        assert_eq!(unsafe { anon.add(i * 4096).read_volatile() }, 0);
+    }
+    assert_eq!(sharing_stats().zero_mapped_pages, KSM_TEST_PAGES);
+    // 前半を同じ内容で埋めてマージさせる
+    for i in 0..KSM_TEST_PAGES / 2 {
+        unsafe { anon.add(i * 4096).write_bytes(0x5A, 4096) };
+    }
+    let mut ksm = KsmScanner::new();
+    ksm.scan(KSM_TEST_PAGES).expect("KSM scan failed");
+    info!("{}", ksm.stats());
+    assert_eq!(sharing_stats().frames_saved(), KSM_TEST_PAGES - 1);
     unsafe {
-        (*page_table)
-            .create_mapping(0, 4096, 0, PageAttr::NotPresent)
-            .expect("Failed to unmap page 0");
+        anon.add(3 * 4096).write_volatile(0xA5);
+        assert_eq!(anon.add(3 * 4096).read_volatile(), 0xA5);
+        assert_eq!(anon.add(4 * 4096).read_volatile(), 0x5A);
+    }
+    assert_eq!(sharing_stats().frames_saved(), KSM_TEST_PAGES - 2);
+    // 共有されたフレームのメタデータ（残り7ページから参照されているはず）
+    let shared = unsafe { (*current_page_table()).read_pte(anon.add(4 * 4096) as u64) }
+        .expect("read_pte failed")
+        & ENTRY_ADDR_MASK;
+    let info = frame_info(shared).expect("frame_info failed");
+    info!("Shared frame {shared:#X}: {info}");
+    assert_eq!(info.state, FrameState::User);
+    assert_eq!(info.ref_count, 7);
+    assert_eq!(info.map_count, 7);
+    assert!(info.flags & FRAME_FLAG_COW != 0);
+    assert_eq!(
+        frame_info(read_cr3() as u64).map(|info| info.state),
+        Some(FrameState::PageTable)
+    );
+    info!("Swap: {}", swap_stats());
+    free_anonymous(anon).expect("free_anonymous failed");
+
+    // ページアクセストレーサのテスト（書き込みと読み込みが1回ずつ記録されるはず）
+    let buf = vmalloc(4096).expect("vmalloc failed");
+    trace_range(buf as u64..buf as u64 + 4096).expect("trace_range failed");
+    unsafe {
+        (buf as *mut u32).write_volatile(0xCAFE);
+        assert_eq!((buf as *const u32).read_volatile(), 0xCAFE);
+    }
+    untrace_all().expect("untrace_all failed");
+    let events = take_trace_events();
+    assert_eq!(events.len(), 2);
+    assert!(events[0].write && !events[1].write);
+    vfree(buf).expect("vfree failed");
+
+    // protectのテスト（フレームを変えずに読み込み専用にし、元に戻す）
+    // 後ろのガードページを含む範囲の変更は失敗し、何も変わらないはず
+    let buf = vmalloc(2 * 4096).expect("vmalloc failed") as u64;
+    let table = unsafe { &mut *current_page_table() };
+    let pte_before = table.read_pte(buf).expect("read_pte failed");
+    table
+        .protect(buf..buf + 4096, Protection::READ_ONLY)
+        .expect("protect failed")
+        .flush();
+    let pte = table.read_pte(buf).expect("read_pte failed");
+    assert_eq!(pte & ENTRY_ADDR_MASK, pte_before & ENTRY_ADDR_MASK);
+    assert_eq!(pte & ATTR_WRITABLE, 0);
+    assert!(table
+        .protect(buf + 4096..buf + 3 * 4096, Protection::READ_ONLY)
+        .is_err());
+    assert!(table.read_pte(buf + 4096).expect("read_pte failed") & ATTR_WRITABLE != 0);
+    table
+        .protect(buf..buf + 4096, Protection::READ_WRITE)
+        .expect("protect failed")
+        .flush();
+    unsafe { (buf as *mut u64).write_volatile(0x1234) };
+    vfree(buf as *mut u8).expect("vfree failed");
+
+    // ioremapのテスト（Local APICのレジスタを読む）
+    const LAPIC_ID: Register<u32> = Register::new(0x20);
+    const LAPIC_VERSION: Register<u32> = Register::new(0x30);
+    let lapic_base = read_msr(MSR_IA32_APIC_BASE) & !0xFFF;
+    let lapic = IoMapping::new(lapic_base, 4096, CacheMode::Uncacheable).expect("ioremap failed");
+    info!(
+        "Local APIC @ {:#X}: id = {}, version = {:#X}",
+        lapic.phys_addr(),
+        lapic.read(LAPIC_ID) >> 24,
+        lapic.read(LAPIC_VERSION) & 0xFF
+    );
+    drop(lapic);
+
+    // フレームバッファをWrite-Combiningでマップし直し、fill_rectの速度を比較
+    let cycles_before = measure_fill_rect(&mut vram);
+    vram.remap_write_combining()
+        .expect("Failed to remap the frame buffer");
+    let cycles_after = measure_fill_rect(&mut vram);
+    info!(
+        "fill_rect: {cycles_before} cycles -> {cycles_after} cycles (WC: {})",
+        is_write_combining_enabled()
+    );
+
+    // PCIDのテスト（アドレス空間を切り替えて戻ってくる）
+    // アドレス空間を作った後にカーネル空間へ追加したマッピングも見えるか確認する
+    // （まだ誰も使っていないPML4エントリの範囲にマップする）
+    const KERNEL_HALF_TEST_VIRT: u64 = 0xFFFF_F000_0000_0000;
+    let kernel_pml4 = read_cr3();
+    let space = AddressSpace::new().expect("Failed to create an address space");
+    let frame = FRAME_ALLOCATOR.alloc_frame().expect("alloc_frame failed");
+    unsafe {
+        *(frame as *mut u64) = 0x1234_5678_9ABC_DEF0;
+        (*current_page_table())
+            .create_mapping(
+                KERNEL_HALF_TEST_VIRT,
+                KERNEL_HALF_TEST_VIRT + 4096,
+                frame,
+                PageAttr::ReadWriteKernel,
+            )
+            .expect("create_mapping failed");
+    }
+    space.activate();
+    info!(
+        "Switched to an address space with PCID {} (PCID enabled: {})",
+        space.pcid(),
+        is_pcid_enabled()
+    );
+    assert_eq!(
+        unsafe { *(KERNEL_HALF_TEST_VIRT as *const u64) },
+        0x1234_5678_9ABC_DEF0
+    );
+    unsafe { write_cr3_with_pcid(kernel_pml4, 0, true) };
+    drop(space);
+    unsafe {
+        (*current_page_table())
+            .create_mapping(
+                KERNEL_HALF_TEST_VIRT,
+                KERNEL_HALF_TEST_VIRT + 4096,
+                0,
+                PageAttr::NotPresent,
+            )
+            .expect("create_mapping failed");
+    }
+    flush_tlb_page_all_address_spaces(KERNEL_HALF_TEST_VIRT);
+    FRAME_ALLOCATOR.free_frame(frame);
+    info!("Kernel mappings added later are shared by the address spaces");
+
+    // 逆マッピングのテスト（2つのアドレス空間で同じフレームを共有し、すべてのマッピングを外す）
+    // ユーザー空間のテーブルはアドレス空間ごとに別なので、カーネルのページテーブルからは
+    // 見えず、アドレス空間の破棄時に解放されるはず
+    const RMAP_TEST_VIRT: u64 = 0x0000_4000_0000_0000;
+    let frame = FRAME_ALLOCATOR.alloc_frame().expect("alloc_frame failed");
+    let mut spaces = [
+        AddressSpace::new().expect("Failed to create an address space"),
+        AddressSpace::new().expect("Failed to create an address space"),
+    ];
+    for (i, space) in spaces.iter_mut().enumerate() {
+        let virt = RMAP_TEST_VIRT + (i * 4096) as u64;
+        space
+            .page_table_mut()
+            .create_mapping(virt, virt + 4096, frame, PageAttr::ReadWriteKernel)
+            .expect("create_mapping failed");
+    }
+    let mut num_mappings = 0;
+    for_each_mapping(frame, |_, virt, pte| {
+        info!("rmap: {frame:#X} is mapped at {virt:#018X} (PTE = {pte:#018X})");
+        num_mappings += 1;
+    });
+    assert_eq!(num_mappings, 2);
+    assert_eq!(frame_info(frame).map(|info| info.map_count), Some(2));
+    assert_eq!(unmap_frame(frame), Ok(2));
+    assert!(mappings_of(frame).is_empty());
+    assert_eq!(frame_info(frame).map(|info| info.map_count), Some(0));
+    assert_eq!(
+        spaces[1].page_table().read_pte(RMAP_TEST_VIRT + 4096),
+        Ok(0)
+    );
+    assert!(unsafe { (*current_page_table()).read_pte(RMAP_TEST_VIRT) }.is_err());
+    let pt = spaces[0]
+        .page_table()
+        .read_pde(RMAP_TEST_VIRT)
+        .expect("read_pde failed")
+        & ENTRY_ADDR_MASK;
+    drop(spaces);
+    assert_eq!(
+        frame_info(pt).map(|info| info.state),
+        Some(FrameState::Free)
+    );
+    FRAME_ALLOCATOR.free_frame(frame);
+
+    // KPTIのテスト
+    // ユーザー用のページテーブルには、ユーザー空間と割り込みの入口だけがマップされ、
+    // RAMの恒等マッピングは無いはず
+    // リング3のコードでint3を実行し、そこから戻った後にRAMの恒等マッピングを読ませる
+    // 割り込みの入口でカーネル用、リング3に戻るときにユーザー用のテーブルに切り替わって
+    // いれば、読み出しはページが存在しないことによる#PFになる（カーネル用のテーブルなら
+    // 存在するカーネルのページへのアクセスによる#PFになる）
+    const KPTI_TEST_VIRT: u64 = 0x0000_4000_0040_0000;
+    match AddressSpace::new_isolated() {
+        Ok(mut space) => {
+            let code = FRAME_ALLOCATOR.alloc_frame().expect("alloc_frame failed");
+            let stack = FRAME_ALLOCATOR.alloc_frame().expect("alloc_frame failed");
+            // The frame is also reachable through the identity mapping of RAM
+            let kernel_data = code;
+            // int3; mov rax, [kernel_data]; ud2
+            let mut stub = [0xCC, 0x48, 0xA1, 0, 0, 0, 0, 0, 0, 0, 0, 0x0F, 0x0B];
+            stub[3..11].copy_from_slice(&kernel_data.to_le_bytes());
+            unsafe { core::ptr::copy_nonoverlapping(stub.as_ptr(), code as *mut u8, stub.len()) };
+            let table = space.page_table_mut();
+            for (virt, frame, writable, executable) in [
+                (KPTI_TEST_VIRT, code, false, true),
+                (KPTI_TEST_VIRT + 4096, stack, true, false),
+            ] {
+                table
+                    .create_mapping(virt, virt + 4096, frame, PageAttr::ReadWriteKernel)
+                    .expect("create_mapping failed");
+                table
+                    .protect(
+                        virt..virt + 4096,
+                        Protection {
+                            writable,
+                            executable,
+                            user: true,
+                        },
+                    )
+                    .expect("protect failed")
+                    .flush();
+            }
+            space.activate();
+            let (entry, _) = interrupt_entry_ranges()[0].clone();
+            let user = space.user_page_table().expect("Not isolated");
+            assert!(user.translate(kernel_data).is_err());
+            assert!(user.translate(entry.start).is_ok());
+            let pte = user.read_pte(entry.start).expect("read_pte failed");
+            assert_eq!(pte & ATTR_WRITABLE, 0);
+            assert!(user.translate(KPTI_TEST_VIRT).is_ok());
+            assert!(space.page_table().translate(kernel_data).is_ok());
+            info!("KPTI: {:X?}", kpti_cr3());
+            let exit = unsafe { run_user_code(KPTI_TEST_VIRT, KPTI_TEST_VIRT + 8192) };
+            info!("KPTI: {exit:X?}");
+            let (kernel_cr3, _) = kpti_cr3().expect("KPTI is not active");
+            assert_eq!(exit.num_breakpoints, 1);
+            assert_eq!(exit.cr3 & ENTRY_ADDR_MASK, kernel_cr3 & ENTRY_ADDR_MASK);
+            assert_eq!(exit.vector, 14);
+            assert_eq!(exit.fault_addr, kernel_data);
+            assert_eq!(
+                exit.error_code & (PF_ERROR_PRESENT | PF_ERROR_USER),
+                PF_ERROR_USER
+            );
+            assert!(space.is_active());
+            unsafe { write_cr3_with_pcid(kernel_pml4, 0, true) };
+            drop(space);
+            assert_eq!(kpti_cr3(), None);
+            FRAME_ALLOCATOR.free_frame(code);
+            FRAME_ALLOCATOR.free_frame(stack);
+        }
+        Err(e) => warn!("KPTI test skipped: {e}"),
     }
-    flush_tlb();
+
+    // SMEP/SMAPのテスト
+    // カーネルがユーザーページを読んだり実行したりすると、有効なら#PFになるはず
+    // copy_from_user/copy_to_userではアクセスでき、カーネルのページは拒否される
+    const USER_ACCESS_TEST_VIRT: u64 = 0x0000_4000_0060_0000;
+    const PF_ERROR_MASK: u64 =
+        PF_ERROR_PRESENT | PF_ERROR_WRITE | PF_ERROR_USER | PF_ERROR_RESERVED | PF_ERROR_FETCH;
+    info!(
+        "SMEP: {}, SMAP: {}, UMIP: {}",
+        is_smep_enabled(),
+        is_smap_enabled(),
+        is_umip_enabled()
+    );
+    let frame = FRAME_ALLOCATOR.alloc_frame().expect("alloc_frame failed");
+    // Fill with ret instructions for probe_execute()
+    unsafe { (frame as *mut u8).write_bytes(0xC3, 4096) };
+    let table = unsafe { &mut *current_page_table() };
+    table
+        .create_mapping(
+            USER_ACCESS_TEST_VIRT,
+            USER_ACCESS_TEST_VIRT + 4096,
+            frame,
+            PageAttr::ReadWriteKernel,
+        )
+        .expect("create_mapping failed");
+    table
+        .protect(
+            USER_ACCESS_TEST_VIRT..USER_ACCESS_TEST_VIRT + 4096,
+            Protection {
+                writable: true,
+                executable: true,
+                user: true,
+            },
+        )
+        .expect("protect failed")
+        .flush();
+    if is_smap_enabled() {
+        let e = probe_read(USER_ACCESS_TEST_VIRT).expect_err("SMAP did not prevent the read");
+        info!("SMAP: {}", PageFaultError(e));
+        assert_eq!(e & PF_ERROR_MASK, PF_ERROR_PRESENT);
+    }
+    if is_smep_enabled() {
+        let e = unsafe { probe_execute(USER_ACCESS_TEST_VIRT) }
+            .expect_err("SMEP did not prevent the fetch");
+        info!("SMEP: {}", PageFaultError(e));
+        assert_eq!(e & PF_ERROR_MASK, PF_ERROR_PRESENT | PF_ERROR_FETCH);
+    }
+    let message = b"Hello, user space!";
+    copy_to_user(USER_ACCESS_TEST_VIRT + 8, message).expect("copy_to_user failed");
+    let mut buf = [0u8; 18];
+    copy_from_user(&mut buf, USER_ACCESS_TEST_VIRT + 8).expect("copy_from_user failed");
+    assert_eq!(&buf, message);
+    // The identity mapping of the frame is not accessible from the user
+    assert!(copy_from_user(&mut buf, frame).is_err());
+
+    // 例外修正テーブルのテスト
+    // マップされていないページにかかるコピーは、パニックせずにEFAULTで失敗するはず
+    info!("Exception table: {} entries", exception_table().len());
+    assert_eq!(
+        copy_to_user(USER_ACCESS_TEST_VIRT + 4096 - 8, message),
+        Err(EFAULT)
+    );
+    table
+        .create_mapping(
+            USER_ACCESS_TEST_VIRT,
+            USER_ACCESS_TEST_VIRT + 4096,
+            0,
+            PageAttr::NotPresent,
+        )
+        .expect("create_mapping failed");
+    flush_tlb_page(USER_ACCESS_TEST_VIRT);
+    assert_eq!(
+        copy_from_user(&mut buf, USER_ACCESS_TEST_VIRT + 8),
+        Err(EFAULT)
+    );
+    // Unmapped on both sides of the page boundary
+    assert_eq!(
+        copy_to_user(USER_ACCESS_TEST_VIRT + 4096 - 8, message),
+        Err(EFAULT)
+    );
+    assert_eq!(
+        copy_from_user(&mut buf, USER_ACCESS_TEST_VIRT + 4096 - 8),
+        Err(EFAULT)
+    );
+    FRAME_ALLOCATOR.free_frame(frame);
+
+    // メモリコンパクションのテスト
+    // 最も低い空き2MiBブロックに無名メモリ相当のフレームを散らばらせて断片化させ、
+    // コンパクションで空きブロックに戻るか確認する
+    // 移動先を確保するため、最も高い空きブロックにも移動できないフレームを1つ置く
+    const COMPACTION_TEST_VIRT: u64 = 0x0000_4000_0020_0000;
+    const COMPACTION_TEST_PAGES: usize = 8;
+    let blocks: Vec<u64> = (0..FRAME_ALLOCATOR.num_frames() / HUGE_BLOCK_FRAMES)
+        .map(|b| (b * HUGE_BLOCK_FRAMES * 4096) as u64)
+        .filter(|phys| is_free_block(*phys))
+        .collect();
+    assert!(blocks.len() >= 2, "Not enough free 2MiB blocks");
+    let (low, high) = (blocks[0], blocks[blocks.len() - 1]);
+    FRAME_ALLOCATOR
+        .alloc_frame_at(high, FrameState::Kernel)
+        .expect("alloc_frame_at failed");
+    let table = unsafe { &mut *current_page_table() };
+    for i in 0..COMPACTION_TEST_PAGES {
+        let virt = COMPACTION_TEST_VIRT + (i * 4096) as u64;
+        let phys = low + (i * 64 * 4096) as u64;
+        FRAME_ALLOCATOR
+            .alloc_frame_at(phys, FrameState::User)
+            .expect("alloc_frame_at failed");
+        table
+            .create_mapping(virt, virt + 4096, phys, PageAttr::ReadWriteKernel)
+            .expect("create_mapping failed");
+        unsafe { (virt as *mut usize).write_volatile(i) };
+    }
+    let report = compact_memory();
+    info!("{report}");
+    assert!(is_free_block(low));
+    for i in 0..COMPACTION_TEST_PAGES {
+        let virt = COMPACTION_TEST_VIRT + (i * 4096) as u64;
+        assert_eq!(unsafe { (virt as *const usize).read_volatile() }, i);
+        let pte = table.read_pte(virt).expect("read_pte failed");
+        table.write_pte(virt, 0).expect("write_pte failed");
+        flush_tlb_page(virt);
+        FRAME_ALLOCATOR.put_frame(pte & ENTRY_ADDR_MASK);
+    }
+    FRAME_ALLOCATOR.free_frame(high);
+
+    // THPのテスト（無名メモリを2MiBページにまとめ、1ページだけマップし直して分割させる）
+    const THP_TEST_SIZE: usize = 6 * 1024 * 1024;
+    let anon = alloc_anonymous(THP_TEST_SIZE).expect("alloc_anonymous failed");
+    let range = anon as u64..anon as u64 + THP_TEST_SIZE as u64;
+    for (i, virt) in range.clone().step_by(4096).enumerate() {
+        unsafe { (virt as *mut usize).write_volatile(i) };
+    }
+    let report = promote_huge_pages(unsafe { &mut *current_page_table() }, range.clone());
+    info!("{report}");
+    assert!(report.num_collapsed() >= 1);
+    let num_huge = num_huge_pages(read_cr3() as u64);
+    assert!(num_huge >= 1);
+    for (i, virt) in range.clone().step_by(4096).enumerate() {
+        assert_eq!(unsafe { (virt as *const usize).read_volatile() }, i);
+    }
+    let huge = range.start.next_multiple_of(HUGE_PAGE_SIZE);
+    assert_eq!(
+        split_huge_page(unsafe { &mut *current_page_table() }, huge),
+        Ok(true)
+    );
+    assert_eq!(num_huge_pages(read_cr3() as u64), num_huge - 1);
+    for (i, virt) in range.clone().step_by(4096).enumerate() {
+        assert_eq!(unsafe { (virt as *const usize).read_volatile() }, i);
+    }
+    free_anonymous(anon).expect("free_anonymous failed");
+    assert_eq!(num_huge_pages(read_cr3() as u64), 0);
+
+    // ページテーブルの整合性チェック
+    let ram = PageTableCheckConfig::from_memory_map(&memory_map);
+    let report = check_current_page_table(&ram);
+    info!("{report}");
+    // W+Xのマッピングを数える（LinuxのCONFIG_DEBUG_WXのようなもの）
+    // 恒等マッピングはまだW+Xなので、警告を出すだけにしている
+    let wx = check_current_page_table(&PageTableCheckConfig {
+        wx_strict: true,
+        ..PageTableCheckConfig::from_memory_map(&memory_map)
+    })
+    .count(PageTableIssueKind::WritableAndExecutable);
+    if wx == 0 {
+        info!("Checked W+X mappings: passed, no W+X pages found");
+    } else {
+        warn!("Checked W+X mappings: found {wx} W+X ranges");
+    }
+
+    // MMUモデルと実機のアドレス変換の比較
+    match run_mmu_diff_test(&ram, 4096) {
+        Ok(report) => {
+            info!("{report}");
+            assert!(report.is_ok(), "MMU model does not match the hardware");
+        }
+        Err(e) => warn!("MMU model test skipped: {e}"),
+    }
+
+    // メモリ使用状況をシリアルと画面に出力
+    let mem = meminfo(&memory_map);
+    println!("{mem}");
+    fill_rect(&mut vram, 0x000000, 0, 0, vw, vh).expect("fill_rect failed");
+    write!(VramTextWriter::new(&mut vram), "{mem}").unwrap();
 
     // メインループ
     loop {
@@ -120,6 +881,19 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     }
 }
 
+// 画面全体のfill_rectにかかるTSCサイクル数（数回の平均）
+fn measure_fill_rect<T: Bitmap>(vram: &mut T) -> u64 {
+    const ITERATIONS: u64 = 4;
+    let w = vram.width();
+    let h = vram.height();
+    let start = rdtsc();
+    for i in 0..ITERATIONS {
+        let color = if i % 2 == 0 { 0x202020 } else { 0x000000 };
+        fill_rect(vram, color, 0, 0, w, h).expect("fill_rect failed");
+    }
+    (rdtsc() - start) / ITERATIONS
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("PANIC: {info:?}");
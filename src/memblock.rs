@@ -0,0 +1,129 @@
+use crate::info;
+use crate::result::Result;
+use crate::x86::PAGE_SIZE;
+use core::cell::RefCell;
+use core::ops::Range;
+
+// 起動初期の物理メモリの予約（Linuxのmemblockのようなもの）
+// ブートサービスを抜けてからアロケータを作るまでの間に、まだ使われている物理メモリ
+// （フレームバッファ、ACPIテーブル、メモリマップ自体、ページ0など）を各サブシステムが
+// reserve()で登録しておく。フレームアロケータ（とそこから切り出すヒープ）は、
+// 予約されていないRAMだけで初期化される
+// ヒープが無い時期に使うので、予約は固定長の配列に記録する
+
+const MAX_RESERVATIONS: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    /// Page-aligned physical address range
+    pub start: u64,
+    pub end: u64,
+    /// Who reserved the range, for the boot log
+    pub owner: &'static str,
+}
+impl Reservation {
+    pub fn range(&self) -> Range<u64> {
+        self.start..self.end
+    }
+}
+
+struct MemBlockInner {
+    reservations: [Option<Reservation>; MAX_RESERVATIONS],
+    // Set once the allocators are built from the reservations
+    frozen: bool,
+}
+
+struct MemBlock {
+    inner: RefCell<MemBlockInner>,
+}
+
+unsafe impl Sync for MemBlock {}
+
+static MEMBLOCK: MemBlock = MemBlock {
+    inner: RefCell::new(MemBlockInner {
+        reservations: [None; MAX_RESERVATIONS],
+        frozen: false,
+    }),
+};
+
+/// Keeps [phys, phys + len), extended to the page boundaries, away from the
+/// allocators. Should be called before the frame allocator is initialized.
+pub fn reserve(phys: u64, len: u64, owner: &'static str) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let mut inner = MEMBLOCK.inner.borrow_mut();
+    if inner.frozen {
+        return Err("memblock: the allocators are already built");
+    }
+    let start = phys & !(PAGE_SIZE as u64 - 1);
+    let end = phys
+        .checked_add(len)
+        .ok_or("memblock: range overflows")?
+        .next_multiple_of(PAGE_SIZE as u64);
+    let slot = inner
+        .reservations
+        .iter_mut()
+        .find(|r| r.is_none())
+        .ok_or("memblock: too many reservations")?;
+    *slot = Some(Reservation { start, end, owner });
+    Ok(())
+}
+
+/// Calls f for each reservation in the order of the registration.
+pub fn for_each_reservation(f: impl FnMut(&Reservation)) {
+    MEMBLOCK
+        .inner
+        .borrow()
+        .reservations
+        .iter()
+        .flatten()
+        .for_each(f);
+}
+
+/// Returns the reservation which overlaps with range, if any.
+pub fn find_overlap(range: Range<u64>) -> Option<Reservation> {
+    MEMBLOCK
+        .inner
+        .borrow()
+        .reservations
+        .iter()
+        .flatten()
+        .find(|r| r.start < range.end && range.start < r.end)
+        .copied()
+}
+
+pub fn is_reserved(phys: u64) -> bool {
+    find_overlap(phys..phys + 1).is_some()
+}
+
+/// Returns the lowest page-aligned address in range where size bytes fit
+/// without overlapping with any reservation.
+pub fn find_unreserved(range: Range<u64>, size: u64) -> Option<u64> {
+    let mut start = range.start.next_multiple_of(PAGE_SIZE as u64);
+    while start.checked_add(size)? <= range.end {
+        match find_overlap(start..start + size) {
+            Some(r) => start = r.end,
+            None => return Some(start),
+        }
+    }
+    None
+}
+
+/// Rejects further reservations. Called once the allocators are built.
+pub(crate) fn freeze_reservations() {
+    MEMBLOCK.inner.borrow_mut().frozen = true;
+}
+
+pub fn dump_reservations() {
+    info!("Reserved physical memory:");
+    for_each_reservation(|r| {
+        info!(
+            "  {:#018X}-{:#018X} {:>8} KiB {}",
+            r.start,
+            r.end,
+            (r.end - r.start) / 1024,
+            r.owner
+        );
+    });
+}
@@ -0,0 +1,193 @@
+extern crate alloc;
+
+use crate::allocator::HeapStats;
+use crate::allocator::ALLOCATOR;
+use crate::frame::FrameState;
+use crate::frame::FrameStats;
+use crate::frame::Zone;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::ioremap::ioremap_areas;
+use crate::ptcheck::check_current_page_table;
+use crate::ptcheck::PageTableCheckConfig;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryMapHolder;
+use crate::x86::HUGE_PAGE_SIZE;
+use crate::x86::PAGE_SIZE;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+// メモリ使用状況のレポート（Linuxの/proc/meminfoのようなもの）
+// UEFIのメモリマップ、フレーム、ヒープ、ページテーブル、ヒュージページ、MMIOの
+// マッピング、予約領域をまとめて集計する。カーネルの変更でメモリのオーバーヘッドが
+// どう変わったかを追うのに使う
+
+const TABLE_NAMES: [&str; 6] = ["", "PT", "PD", "PDPT", "PML4", "PML5"];
+
+pub struct MemInfo {
+    /// Number of pages in the UEFI memory map for each type
+    pub memory_map: Vec<(EfiMemoryType, u64)>,
+    pub total_frames: usize,
+    pub managed_frames: usize,
+    pub free_frames: usize,
+    /// Indexed by Zone as usize
+    pub free_frames_in_zone: [usize; Zone::ALL.len()],
+    /// Frames reclaimed from the boot services and the loader
+    pub reclaimed_frames: usize,
+    pub frames: FrameStats,
+    pub heap: HeapStats,
+    /// Number of page tables reachable from CR3 indexed by their level
+    pub page_tables: [usize; 6],
+    /// Number of 2MiB pages mapped by collapsing 4KiB pages
+    pub huge_pages: usize,
+    /// Virtual address ranges mapped by ioremap()
+    pub mmio_mappings: Vec<Range<u64>>,
+    /// Physical address ranges of the reserved frames
+    pub reserved: Vec<Range<u64>>,
+}
+
+impl MemInfo {
+    pub fn page_table_bytes(&self) -> u64 {
+        (self.page_tables.iter().sum::<usize>() * PAGE_SIZE) as u64
+    }
+}
+
+/// Collects the memory usage of the whole system.
+pub fn meminfo(memory_map: &MemoryMapHolder) -> MemInfo {
+    let mut pages_by_type: Vec<(EfiMemoryType, u64)> = Vec::new();
+    for e in memory_map.iter() {
+        match pages_by_type
+            .iter_mut()
+            .find(|(t, _)| *t == e.memory_type())
+        {
+            Some((_, pages)) => *pages += e.number_of_pages(),
+            None => pages_by_type.push((e.memory_type(), e.number_of_pages())),
+        }
+    }
+    pages_by_type.sort_by_key(|(t, _)| *t as i64);
+    let frames = FRAME_ALLOCATOR.frame_stats();
+    let page_tables = check_current_page_table(&PageTableCheckConfig::from_memory_map(memory_map))
+        .tables_per_level;
+    MemInfo {
+        memory_map: pages_by_type,
+        total_frames: FRAME_ALLOCATOR.num_frames(),
+        managed_frames: FRAME_ALLOCATOR.num_managed_frames(),
+        free_frames: FRAME_ALLOCATOR.num_free_frames(),
+        free_frames_in_zone: Zone::ALL.map(|z| FRAME_ALLOCATOR.num_free_frames_in(z)),
+        reclaimed_frames: FRAME_ALLOCATOR.num_reclaimed_frames(),
+        frames,
+        heap: ALLOCATOR.stats(),
+        page_tables,
+        huge_pages: frames.huge * PAGE_SIZE / HUGE_PAGE_SIZE as usize,
+        mmio_mappings: ioremap_areas(),
+        reserved: FRAME_ALLOCATOR.ranges_in_state(FrameState::Reserved),
+    }
+}
+
+fn kib(bytes: u64) -> u64 {
+    bytes / 1024
+}
+
+fn frames_kib(frames: usize) -> u64 {
+    kib((frames * PAGE_SIZE) as u64)
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "MemTotal:        {:>10} kB",
+            frames_kib(self.managed_frames)
+        )?;
+        writeln!(
+            f,
+            "MemFree:         {:>10} kB",
+            frames_kib(self.free_frames)
+        )?;
+        for zone in Zone::ALL {
+            writeln!(
+                f,
+                "  {:<15}{:>10} kB",
+                alloc::format!("{zone:?}:"),
+                frames_kib(self.free_frames_in_zone[zone as usize])
+            )?;
+        }
+        writeln!(
+            f,
+            "MemUsed:         {:>10} kB",
+            frames_kib(self.managed_frames - self.free_frames)
+        )?;
+        writeln!(
+            f,
+            "Reclaimed:       {:>10} kB",
+            frames_kib(self.reclaimed_frames)
+        )?;
+        writeln!(
+            f,
+            "FramesCovered:   {:>10} kB",
+            frames_kib(self.total_frames)
+        )?;
+        for state in FrameState::ALL {
+            writeln!(
+                f,
+                "  {:<15}{:>10} kB",
+                alloc::format!("{state:?}:"),
+                frames_kib(self.frames.count(state))
+            )?;
+        }
+        writeln!(f, "HeapTotal:       {:>10} kB", kib(self.heap.total as u64))?;
+        writeln!(
+            f,
+            "HeapUsed:        {:>10} kB ({} allocations)",
+            kib(self.heap.used as u64),
+            self.heap.num_allocations
+        )?;
+        writeln!(
+            f,
+            "PageTables:      {:>10} kB",
+            kib(self.page_table_bytes())
+        )?;
+        for (level, name) in TABLE_NAMES.iter().enumerate().skip(1) {
+            if self.page_tables[level] != 0 {
+                writeln!(
+                    f,
+                    "  {:<15}{:>10} kB ({} tables)",
+                    alloc::format!("{name}:"),
+                    frames_kib(self.page_tables[level]),
+                    self.page_tables[level]
+                )?;
+            }
+        }
+        writeln!(
+            f,
+            "HugePages:       {:>10} kB ({} pages)",
+            kib(self.huge_pages as u64 * HUGE_PAGE_SIZE),
+            self.huge_pages
+        )?;
+        writeln!(
+            f,
+            "MmioMapped:      {:>10} kB ({} mappings)",
+            kib(self.mmio_mappings.iter().map(|r| r.end - r.start).sum()),
+            self.mmio_mappings.len()
+        )?;
+        writeln!(
+            f,
+            "Reserved:        {:>10} kB ({} ranges)",
+            kib(self.reserved.iter().map(|r| r.end - r.start).sum()),
+            self.reserved.len()
+        )?;
+        for r in &self.reserved {
+            writeln!(f, "  {:#018X}-{:#018X}", r.start, r.end)?;
+        }
+        writeln!(f, "UEFI memory map:")?;
+        for (memory_type, pages) in &self.memory_map {
+            writeln!(
+                f,
+                "  {:<28}{:>10} kB",
+                alloc::format!("{memory_type:?}:"),
+                kib(pages * PAGE_SIZE as u64)
+            )?;
+        }
+        Ok(())
+    }
+}
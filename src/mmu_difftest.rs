@@ -0,0 +1,214 @@
+use crate::error;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::mmu_model::translate;
+use crate::mmu_model::translate_la57;
+use crate::mmu_model::Access;
+use crate::mmu_model::AccessKind;
+use crate::mmu_model::MmuConfig;
+use crate::mmu_model::ModelResult;
+use crate::ptcheck::PageTableCheckConfig;
+use crate::result::Result;
+use crate::x86::canonicalize_addr;
+use crate::x86::current_page_table;
+use crate::x86::flush_tlb_page;
+use crate::x86::is_la57_enabled;
+use crate::x86::max_phys_addr_bits;
+use crate::x86::probe_execute;
+use crate::x86::probe_read;
+use crate::x86::probe_write;
+use crate::x86::rdtsc;
+use crate::x86::read_cr3;
+use crate::x86::PageAttr;
+use crate::x86::ATTR_NO_EXECUTE;
+use crate::x86::ATTR_PRESENT;
+use crate::x86::ATTR_USER;
+use crate::x86::ATTR_WRITABLE;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PML5;
+use core::fmt;
+
+// ソフトウェアMMUモデルと実機のアドレス変換の比較テスト
+// ランダムな属性でマップしたテスト用ページと、ランダムなアドレスに対して
+// 実際にアクセスして#PFの有無とエラーコードがモデルの予測と一致するか確かめる
+
+const TEST_REGION_START: u64 = 0xFFFF_F000_0000_0000;
+const TEST_REGION_PAGES: usize = 64;
+// Bits of the error code which the model predicts
+const ERROR_CODE_MASK: u64 = 0x1F;
+
+struct XorShift64(u64);
+impl XorShift64 {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DiffTestReport {
+    pub num_probes: usize,
+    pub num_faults: usize,
+    pub num_skipped: usize,
+    pub num_mismatches: usize,
+}
+impl DiffTestReport {
+    pub fn is_ok(&self) -> bool {
+        self.num_mismatches == 0
+    }
+}
+impl fmt::Display for DiffTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MMU model: {} probes ({} faults, {} skipped), {} mismatches",
+            self.num_probes, self.num_faults, self.num_skipped, self.num_mismatches
+        )
+    }
+}
+
+fn probe(virt: u64, kind: AccessKind) -> core::result::Result<(), u64> {
+    match kind {
+        AccessKind::Read => probe_read(virt),
+        AccessKind::Write => probe_write(virt),
+        // Only used for the test pages, which are filled with ret
+        AccessKind::Execute => unsafe { probe_execute(virt) },
+    }
+}
+
+struct DiffTester<'a> {
+    rng: XorShift64,
+    mmu: MmuConfig,
+    ram: &'a PageTableCheckConfig,
+    report: DiffTestReport,
+}
+
+impl DiffTester<'_> {
+    fn check(&mut self, virt: u64, kind: AccessKind) {
+        let access = Access { kind, user: false };
+        let expected = if is_la57_enabled() {
+            let pml5 = unsafe { &*(read_cr3() as *const PML5) };
+            translate_la57(pml5, virt, access, &self.mmu)
+        } else {
+            translate(unsafe { &*read_cr3() }, virt, access, &self.mmu)
+        };
+        match expected {
+            ModelResult::GeneralProtection => {
+                self.report.num_skipped += 1;
+                return;
+            }
+            // Reading MMIO may have side effects
+            ModelResult::Mapped { phys, .. } if !self.ram.is_ram(phys, 1) => {
+                self.report.num_skipped += 1;
+                return;
+            }
+            _ => {}
+        }
+        self.report.num_probes += 1;
+        let actual = probe(virt, kind);
+        let agreed = match (expected, actual) {
+            (ModelResult::Mapped { .. }, Ok(())) => true,
+            (ModelResult::PageFault { error_code }, Err(e)) => {
+                self.report.num_faults += 1;
+                e & ERROR_CODE_MASK == error_code
+            }
+            _ => false,
+        };
+        if !agreed {
+            self.report.num_mismatches += 1;
+            error!("MMU model mismatch: {kind:?} {virt:#018X}");
+            error!("  model: {expected:?}, hardware: {actual:?}");
+        }
+    }
+    fn random_leaf_flags(&mut self) -> u64 {
+        let r = self.rng.next();
+        let mut flags = 0;
+        // Mostly present to test the permission checks
+        if r & 0b111 != 0 {
+            flags |= ATTR_PRESENT;
+        }
+        if r & (1 << 3) != 0 {
+            flags |= ATTR_WRITABLE;
+        }
+        if r & (1 << 4) != 0 {
+            flags |= ATTR_USER;
+        }
+        if r & (1 << 5) != 0 {
+            flags |= ATTR_NO_EXECUTE;
+        }
+        let phys_bits = max_phys_addr_bits();
+        if r & (0b1111 << 6) == 0 && phys_bits < 52 {
+            flags |= 1 << phys_bits;
+        }
+        flags
+    }
+    // Half of the addresses are taken from the low 8 GiB, where the identity
+    // mapping is.
+    fn random_addr(&mut self) -> u64 {
+        let r = self.rng.next();
+        if r & 1 == 0 {
+            (r >> 1) % (1 << 33)
+        } else {
+            canonicalize_addr(r >> 1)
+        }
+    }
+    fn random_kind(&mut self) -> AccessKind {
+        match self.rng.next() % 3 {
+            0 => AccessKind::Read,
+            1 => AccessKind::Write,
+            _ => AccessKind::Execute,
+        }
+    }
+}
+
+/// Compares the model with the hardware on the current page table. Test
+/// pages are mapped with random attributes and probed with all kinds of
+/// accesses, and random addresses are probed with reads.
+pub fn run_mmu_diff_test(
+    ram: &PageTableCheckConfig,
+    num_random_probes: usize,
+) -> Result<DiffTestReport> {
+    let mut tester = DiffTester {
+        rng: XorShift64(rdtsc() | 1),
+        mmu: MmuConfig::current(),
+        ram,
+        report: DiffTestReport::default(),
+    };
+    // All the test pages share one frame filled with ret instructions
+    let frame = FRAME_ALLOCATOR.alloc_frame()?;
+    unsafe { (frame as *mut u8).write_bytes(0xC3, PAGE_SIZE) };
+    let table = unsafe { &mut *current_page_table() };
+    let region_end = TEST_REGION_START + (TEST_REGION_PAGES * PAGE_SIZE) as u64;
+    table.create_mapping(
+        TEST_REGION_START,
+        region_end,
+        frame,
+        PageAttr::ReadWriteKernel,
+    )?;
+    for i in 0..TEST_REGION_PAGES {
+        let virt = TEST_REGION_START + (i * PAGE_SIZE) as u64;
+        table.write_pte(virt, frame | tester.random_leaf_flags())?;
+        flush_tlb_page(virt);
+        for kind in [AccessKind::Read, AccessKind::Write, AccessKind::Execute] {
+            let offset = tester.rng.next() % PAGE_SIZE as u64;
+            tester.check(virt + offset, kind);
+        }
+    }
+    for _ in 0..num_random_probes {
+        let virt = tester.random_addr();
+        let kind = tester.random_kind();
+        if (TEST_REGION_START..region_end).contains(&virt) {
+            tester.check(virt, kind);
+        } else {
+            // Only the test pages can be executed or written safely
+            tester.check(virt, AccessKind::Read);
+        }
+    }
+    for virt in (TEST_REGION_START..region_end).step_by(PAGE_SIZE) {
+        table.write_pte(virt, 0)?;
+        flush_tlb_page(virt);
+    }
+    FRAME_ALLOCATOR.free_frame(frame);
+    Ok(tester.report)
+}
@@ -0,0 +1,480 @@
+use crate::x86::is_1g_page_supported;
+use crate::x86::is_nx_enabled;
+use crate::x86::max_phys_addr_bits;
+use crate::x86::read_cr0;
+use crate::x86::read_cr4;
+use crate::x86::ATTR_NO_EXECUTE;
+use crate::x86::ATTR_PAGE_SIZE;
+use crate::x86::ATTR_PRESENT;
+use crate::x86::ATTR_USER;
+use crate::x86::ATTR_WRITABLE;
+use crate::x86::CR0_WP;
+use crate::x86::CR4_SMAP;
+use crate::x86::CR4_SMEP;
+use crate::x86::ENTRY_ADDR_MASK;
+use crate::x86::PF_ERROR_FETCH;
+use crate::x86::PF_ERROR_PRESENT;
+use crate::x86::PF_ERROR_RESERVED;
+use crate::x86::PF_ERROR_USER;
+use crate::x86::PF_ERROR_WRITE;
+use crate::x86::PML4;
+use crate::x86::PML5;
+use crate::x86::VIRT_ADDR_BITS;
+use crate::x86::VIRT_ADDR_BITS_LA57;
+
+// 4レベル（と5レベル）ページングのアドレス変換のソフトウェアモデル
+// ハードウェアには触れず、Table/Entryを辿るだけなので、実機の変換結果との比較に使える
+// SDM Vol.3: 4.5 4-Level Paging, 4.6 Access Rights, 4.7 Page-Fault Exceptions
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub kind: AccessKind,
+    /// Access from CPL 3
+    pub user: bool,
+}
+
+/// Processor state which affects the translation
+#[derive(Debug, Clone, Copy)]
+pub struct MmuConfig {
+    pub phys_addr_bits: u32,
+    /// IA32_EFER.NXE
+    pub nx_enabled: bool,
+    pub page_1g_supported: bool,
+    /// CR0.WP
+    pub write_protect: bool,
+    /// CR4.SMEP
+    pub smep: bool,
+    /// CR4.SMAP (EFLAGS.AC is assumed to be 0)
+    pub smap: bool,
+}
+
+impl MmuConfig {
+    /// Reads the configuration of the running processor.
+    pub fn current() -> Self {
+        let cr4 = read_cr4();
+        Self {
+            phys_addr_bits: max_phys_addr_bits(),
+            nx_enabled: is_nx_enabled(),
+            page_1g_supported: is_1g_page_supported(),
+            write_protect: read_cr0() & CR0_WP != 0,
+            smep: cr4 & CR4_SMEP != 0,
+            smap: cr4 & CR4_SMAP != 0,
+        }
+    }
+    /// Returns the bits of the paging-structure entry at level which are
+    /// reserved with this configuration.
+    pub fn reserved_bits(&self, level: usize, value: u64) -> u64 {
+        // Address bits beyond MAXPHYADDR are always reserved
+        let mut mask = ENTRY_ADDR_MASK & !((1u64 << self.phys_addr_bits) - 1);
+        if !self.nx_enabled {
+            mask |= ATTR_NO_EXECUTE;
+        }
+        let is_large = value & ATTR_PAGE_SIZE != 0;
+        match level {
+            4 | 5 => mask |= ATTR_PAGE_SIZE,
+            3 if is_large && !self.page_1g_supported => mask |= ATTR_PAGE_SIZE,
+            // Bit 12 is PAT for large pages, bits between it and the address
+            // are reserved.
+            3 if is_large => mask |= ((1 << 30) - 1) & !((1 << 13) - 1),
+            2 if is_large => mask |= ((1 << 21) - 1) & !((1 << 13) - 1),
+            _ => {}
+        }
+        value & mask
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelResult {
+    /// phys is the translated address (not the base of the page)
+    Mapped { phys: u64, page_size: u64 },
+    /// error_code is what the processor pushes for #PF
+    PageFault { error_code: u64 },
+    /// Non-canonical addresses cause #GP instead of #PF
+    GeneralProtection,
+}
+
+// Rights accumulated over the paging-structure entries
+struct Rights {
+    writable: bool,
+    user: bool,
+    executable: bool,
+}
+
+/// Translates virt as the processor would do for the given access.
+pub fn translate(pml4: &PML4, virt: u64, access: Access, config: &MmuConfig) -> ModelResult {
+    translate_from(Root::Level4(pml4), virt, access, config)
+}
+
+/// Same as translate() but with 5-level paging (CR4.LA57 = 1).
+pub fn translate_la57(pml5: &PML5, virt: u64, access: Access, config: &MmuConfig) -> ModelResult {
+    translate_from(Root::Level5(pml5), virt, access, config)
+}
+
+#[derive(Clone, Copy)]
+enum Root<'a> {
+    Level4(&'a PML4),
+    Level5(&'a PML5),
+}
+
+fn translate_from(root: Root, virt: u64, access: Access, config: &MmuConfig) -> ModelResult {
+    let shift = 64
+        - match root {
+            Root::Level4(_) => VIRT_ADDR_BITS,
+            Root::Level5(_) => VIRT_ADDR_BITS_LA57,
+        };
+    if (((virt << shift) as i64) >> shift) as u64 != virt {
+        return ModelResult::GeneralProtection;
+    }
+    let mut error_code = 0;
+    if access.kind == AccessKind::Write {
+        error_code |= PF_ERROR_WRITE;
+    }
+    if access.user {
+        error_code |= PF_ERROR_USER;
+    }
+    if access.kind == AccessKind::Execute && (config.nx_enabled || config.smep) {
+        error_code |= PF_ERROR_FETCH;
+    }
+    let index = |shift: u32| ((virt >> shift) & 0x1FF) as usize;
+    let mut rights = Rights {
+        writable: true,
+        user: true,
+        executable: true,
+    };
+    // Returns Err(error_code) if the entry is not present or has reserved
+    // bits set, and Ok(true) if it maps a page.
+    let mut visit = |level: usize, value: u64| -> Result<bool, u64> {
+        if value & ATTR_PRESENT == 0 {
+            return Err(error_code);
+        }
+        if config.reserved_bits(level, value) != 0 {
+            return Err(error_code | PF_ERROR_PRESENT | PF_ERROR_RESERVED);
+        }
+        rights.writable &= value & ATTR_WRITABLE != 0;
+        rights.user &= value & ATTR_USER != 0;
+        rights.executable &= !(config.nx_enabled && value & ATTR_NO_EXECUTE != 0);
+        Ok(level == 1 || (level <= 3 && value & ATTR_PAGE_SIZE != 0))
+    };
+    let mut walk = || -> Result<(u64, u64), u64> {
+        let pml4 = match root {
+            Root::Level4(pml4) => pml4,
+            Root::Level5(pml5) => {
+                let e5 = &pml5.entries()[index(48)];
+                visit(5, e5.value())?;
+                e5.table().map_err(|_| error_code)?
+            }
+        };
+        let e4 = &pml4.entries()[index(39)];
+        visit(4, e4.value())?;
+        let pdpt = e4.table().map_err(|_| error_code)?;
+        let e3 = &pdpt.entries()[index(30)];
+        if visit(3, e3.value())? {
+            return Ok((e3.value(), 1 << 30));
+        }
+        let pd = e3.table().map_err(|_| error_code)?;
+        let e2 = &pd.entries()[index(21)];
+        if visit(2, e2.value())? {
+            return Ok((e2.value(), 1 << 21));
+        }
+        let pt = e2.table().map_err(|_| error_code)?;
+        let e1 = &pt.entries()[index(12)];
+        visit(1, e1.value())?;
+        Ok((e1.value(), 1 << 12))
+    };
+    let (value, page_size) = match walk() {
+        Ok(leaf) => leaf,
+        Err(error_code) => return ModelResult::PageFault { error_code },
+    };
+    let allowed = match (access.kind, access.user) {
+        (AccessKind::Read, true) => rights.user,
+        (AccessKind::Write, true) => rights.user && rights.writable,
+        (AccessKind::Execute, true) => rights.user && rights.executable,
+        (AccessKind::Read, false) => !(config.smap && rights.user),
+        (AccessKind::Write, false) => {
+            !(config.smap && rights.user) && (rights.writable || !config.write_protect)
+        }
+        (AccessKind::Execute, false) => !(config.smep && rights.user) && rights.executable,
+    };
+    if !allowed {
+        return ModelResult::PageFault {
+            error_code: error_code | PF_ERROR_PRESENT,
+        };
+    }
+    ModelResult::Mapped {
+        phys: (value & ENTRY_ADDR_MASK & !(page_size - 1)) | (virt & (page_size - 1)),
+        page_size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::PD;
+    use crate::x86::PDPT;
+    use crate::x86::PT;
+    use alloc::boxed::Box;
+
+    const KERNEL_RW: u64 = ATTR_PRESENT | ATTR_WRITABLE;
+    const USER_RW: u64 = ATTR_PRESENT | ATTR_WRITABLE | ATTR_USER;
+    // Translated addresses are never dereferenced
+    const PAGE_PHYS: u64 = 0x0000_00AB_CDE0_0000;
+
+    // PML4[0] -> PDPT[0] -> PD[0] -> PT, all of them user and writable so
+    // that the leaf decides the rights.
+    struct Tables {
+        pml4: Box<PML4>,
+        pdpt: Box<PDPT>,
+        pd: Box<PD>,
+        pt: Box<PT>,
+    }
+    impl Tables {
+        fn new() -> Self {
+            let mut t = Self {
+                pml4: PML4::new_for_test(),
+                pdpt: PDPT::new_for_test(),
+                pd: PD::new_for_test(),
+                pt: PT::new_for_test(),
+            };
+            t.pml4
+                .set_entry_for_test(0, t.pdpt.phys_for_test() | USER_RW);
+            t.pdpt.set_entry_for_test(0, t.pd.phys_for_test() | USER_RW);
+            t.pd.set_entry_for_test(0, t.pt.phys_for_test() | USER_RW);
+            t
+        }
+        fn translate(&self, virt: u64, kind: AccessKind, user: bool) -> ModelResult {
+            translate(&self.pml4, virt, Access { kind, user }, &config())
+        }
+    }
+
+    fn config() -> MmuConfig {
+        MmuConfig {
+            phys_addr_bits: 48,
+            nx_enabled: true,
+            page_1g_supported: true,
+            write_protect: true,
+            smep: false,
+            smap: false,
+        }
+    }
+
+    fn fault(error_code: u64) -> ModelResult {
+        ModelResult::PageFault { error_code }
+    }
+
+    #[test]
+    fn present_4k_page() {
+        let mut t = Tables::new();
+        t.pt.set_entry_for_test(1, PAGE_PHYS | USER_RW);
+        assert_eq!(
+            t.translate(0x1234, AccessKind::Read, true),
+            ModelResult::Mapped {
+                phys: PAGE_PHYS | 0x234,
+                page_size: 4096
+            }
+        );
+        // Not present
+        assert_eq!(t.translate(0x2000, AccessKind::Read, false), fault(0));
+        assert_eq!(
+            t.translate(0x2000, AccessKind::Write, true),
+            fault(PF_ERROR_WRITE | PF_ERROR_USER)
+        );
+        assert_eq!(
+            t.translate(1 << 47, AccessKind::Read, false),
+            ModelResult::GeneralProtection
+        );
+    }
+
+    #[test]
+    fn reserved_bits() {
+        let mut t = Tables::new();
+        // Beyond MAXPHYADDR
+        t.pt.set_entry_for_test(1, (1 << 50) | USER_RW);
+        assert_eq!(
+            t.translate(0x1000, AccessKind::Read, false),
+            fault(PF_ERROR_PRESENT | PF_ERROR_RESERVED)
+        );
+        // PS in a PML4E
+        let mut t = Tables::new();
+        t.pml4.set_entry_for_test(1, ATTR_PAGE_SIZE | USER_RW);
+        assert_eq!(
+            t.translate(1 << 39, AccessKind::Write, true),
+            fault(PF_ERROR_PRESENT | PF_ERROR_RESERVED | PF_ERROR_WRITE | PF_ERROR_USER)
+        );
+    }
+
+    #[test]
+    fn user_supervisor() {
+        let mut t = Tables::new();
+        t.pt.set_entry_for_test(1, PAGE_PHYS | KERNEL_RW);
+        assert_eq!(
+            t.translate(0x1000, AccessKind::Read, true),
+            fault(PF_ERROR_PRESENT | PF_ERROR_USER)
+        );
+        assert!(matches!(
+            t.translate(0x1000, AccessKind::Read, false),
+            ModelResult::Mapped { .. }
+        ));
+        // A supervisor upper level entry wins over a user leaf
+        t.pt.set_entry_for_test(1, PAGE_PHYS | USER_RW);
+        t.pd.set_entry_for_test(0, t.pt.phys_for_test() | KERNEL_RW);
+        assert_eq!(
+            t.translate(0x1000, AccessKind::Read, true),
+            fault(PF_ERROR_PRESENT | PF_ERROR_USER)
+        );
+    }
+
+    #[test]
+    fn smap_and_smep() {
+        let mut t = Tables::new();
+        t.pt.set_entry_for_test(1, PAGE_PHYS | USER_RW);
+        let config = MmuConfig {
+            smep: true,
+            smap: true,
+            ..config()
+        };
+        let kernel = |kind| Access { kind, user: false };
+        assert_eq!(
+            translate(&t.pml4, 0x1000, kernel(AccessKind::Read), &config),
+            fault(PF_ERROR_PRESENT)
+        );
+        assert_eq!(
+            translate(&t.pml4, 0x1000, kernel(AccessKind::Execute), &config),
+            fault(PF_ERROR_PRESENT | PF_ERROR_FETCH)
+        );
+    }
+
+    #[test]
+    fn read_write() {
+        let mut t = Tables::new();
+        t.pt.set_entry_for_test(1, PAGE_PHYS | ATTR_PRESENT | ATTR_USER);
+        assert_eq!(
+            t.translate(0x1000, AccessKind::Write, true),
+            fault(PF_ERROR_PRESENT | PF_ERROR_WRITE | PF_ERROR_USER)
+        );
+        assert_eq!(
+            t.translate(0x1000, AccessKind::Write, false),
+            fault(PF_ERROR_PRESENT | PF_ERROR_WRITE)
+        );
+        // Without CR0.WP, the kernel can write to read-only pages
+        let config = MmuConfig {
+            write_protect: false,
+            ..config()
+        };
+        let access = Access {
+            kind: AccessKind::Write,
+            user: false,
+        };
+        assert!(matches!(
+            translate(&t.pml4, 0x1000, access, &config),
+            ModelResult::Mapped { .. }
+        ));
+    }
+
+    #[test]
+    fn no_execute() {
+        let mut t = Tables::new();
+        t.pt.set_entry_for_test(1, PAGE_PHYS | USER_RW | ATTR_NO_EXECUTE);
+        assert_eq!(
+            t.translate(0x1000, AccessKind::Execute, true),
+            fault(PF_ERROR_PRESENT | PF_ERROR_USER | PF_ERROR_FETCH)
+        );
+        assert!(matches!(
+            t.translate(0x1000, AccessKind::Read, true),
+            ModelResult::Mapped { .. }
+        ));
+        // XD is reserved if IA32_EFER.NXE is 0
+        let config = MmuConfig {
+            nx_enabled: false,
+            ..config()
+        };
+        let access = Access {
+            kind: AccessKind::Read,
+            user: false,
+        };
+        assert_eq!(
+            translate(&t.pml4, 0x1000, access, &config),
+            fault(PF_ERROR_PRESENT | PF_ERROR_RESERVED)
+        );
+    }
+
+    #[test]
+    fn large_pages() {
+        let mut t = Tables::new();
+        t.pd.set_entry_for_test(1, PAGE_PHYS | USER_RW | ATTR_PAGE_SIZE);
+        assert_eq!(
+            t.translate(0x0023_4567, AccessKind::Write, true),
+            ModelResult::Mapped {
+                phys: PAGE_PHYS | 0x3_4567,
+                page_size: 1 << 21
+            }
+        );
+        // Bits 20:13 of a 2MiB page entry are reserved
+        t.pd.set_entry_for_test(1, PAGE_PHYS | (1 << 13) | USER_RW | ATTR_PAGE_SIZE);
+        assert_eq!(
+            t.translate(0x0020_0000, AccessKind::Read, false),
+            fault(PF_ERROR_PRESENT | PF_ERROR_RESERVED)
+        );
+        t.pdpt
+            .set_entry_for_test(1, (1 << 30) | USER_RW | ATTR_PAGE_SIZE);
+        assert_eq!(
+            t.translate(0x7654_3210, AccessKind::Read, false),
+            ModelResult::Mapped {
+                phys: 0x7654_3210,
+                page_size: 1 << 30
+            }
+        );
+        let config = MmuConfig {
+            page_1g_supported: false,
+            ..config()
+        };
+        let access = Access {
+            kind: AccessKind::Read,
+            user: false,
+        };
+        assert_eq!(
+            translate(&t.pml4, 0x7654_3210, access, &config),
+            fault(PF_ERROR_PRESENT | PF_ERROR_RESERVED)
+        );
+    }
+
+    #[test]
+    fn five_level_paging() {
+        let mut t = Tables::new();
+        t.pt.set_entry_for_test(1, PAGE_PHYS | USER_RW);
+        // The tables are under PML5[1], which is beyond 48-bit addresses
+        let mut pml5 = PML5::new_for_test();
+        pml5.set_entry_for_test(1, t.pml4.phys_for_test() | USER_RW);
+        let access = Access {
+            kind: AccessKind::Read,
+            user: true,
+        };
+        assert_eq!(
+            translate_la57(&pml5, (1 << 48) | 0x1234, access, &config()),
+            ModelResult::Mapped {
+                phys: PAGE_PHYS | 0x234,
+                page_size: 4096
+            }
+        );
+        assert_eq!(
+            translate_la57(&pml5, 0x1234, access, &config()),
+            fault(PF_ERROR_USER)
+        );
+        assert_eq!(
+            translate_la57(&pml5, 1 << 56, access, &config()),
+            ModelResult::GeneralProtection
+        );
+        // A supervisor PML5E denies the user access
+        pml5.set_entry_for_test(1, t.pml4.phys_for_test() | KERNEL_RW);
+        assert_eq!(
+            translate_la57(&pml5, (1 << 48) | 0x1234, access, &config()),
+            fault(PF_ERROR_PRESENT | PF_ERROR_USER)
+        );
+    }
+}
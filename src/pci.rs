@@ -0,0 +1,106 @@
+use crate::x86::read_io_port_u32;
+use crate::x86::write_io_port_u32;
+use core::fmt;
+
+// PCIコンフィグレーション空間へのアクセス（I/Oポート0xCF8/0xCFCを使う方式）
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+pub const PCI_COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const PCI_COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BusDeviceFunction {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl BusDeviceFunction {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        assert!(device < 32 && function < 8);
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+    pub fn read_config_u32(&self, offset: u8) -> u32 {
+        write_io_port_u32(CONFIG_ADDRESS, self.config_address(offset));
+        read_io_port_u32(CONFIG_DATA)
+    }
+    pub fn write_config_u32(&self, offset: u8, data: u32) {
+        write_io_port_u32(CONFIG_ADDRESS, self.config_address(offset));
+        write_io_port_u32(CONFIG_DATA, data)
+    }
+    pub fn read_config_u16(&self, offset: u8) -> u16 {
+        (self.read_config_u32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+    pub fn write_config_u16(&self, offset: u8, data: u16) {
+        let shift = (offset & 2) * 8;
+        let value = self.read_config_u32(offset & !3) & !(0xFFFF << shift);
+        self.write_config_u32(offset & !3, value | (data as u32) << shift)
+    }
+    pub fn vendor_id(&self) -> u16 {
+        self.read_config_u16(0x00)
+    }
+    pub fn device_id(&self) -> u16 {
+        self.read_config_u16(0x02)
+    }
+    pub fn subsystem_id(&self) -> u16 {
+        self.read_config_u16(0x2E)
+    }
+    pub fn is_multi_function(&self) -> bool {
+        self.read_config_u32(0x0C) & (1 << 23) != 0
+    }
+    /// Raw value of the Base Address Register (0-5)
+    pub fn bar(&self, index: u8) -> u32 {
+        assert!(index < 6);
+        self.read_config_u32(0x10 + index * 4)
+    }
+    /// Sets bits in the command register
+    pub fn enable(&self, command_bits: u16) {
+        let command = self.read_config_u16(0x04);
+        self.write_config_u16(0x04, command | command_bits)
+    }
+    fn config_address(&self, offset: u8) -> u32 {
+        assert_eq!(offset & 3, 0, "Unaligned config space access");
+        (1 << 31)
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | offset as u32
+    }
+}
+
+impl fmt::Debug for BusDeviceFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02X}:{:02X}.{:X}",
+            self.bus, self.device, self.function
+        )
+    }
+}
+
+/// Returns the first function which satisfies pred.
+pub fn find_device(pred: impl Fn(&BusDeviceFunction) -> bool) -> Option<BusDeviceFunction> {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let bdf = BusDeviceFunction::new(bus, device, 0);
+            if bdf.vendor_id() == 0xFFFF {
+                continue;
+            }
+            let num_functions = if bdf.is_multi_function() { 8 } else { 1 };
+            for function in 0..num_functions {
+                let bdf = BusDeviceFunction::new(bus, device, function);
+                if bdf.vendor_id() != 0xFFFF && pred(&bdf) {
+                    return Some(bdf);
+                }
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,471 @@
+extern crate alloc;
+
+use crate::kaslr::direct_map_range;
+use crate::mmu_model::MmuConfig;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryMapHolder;
+use crate::x86::is_la57_enabled;
+use crate::x86::read_cr3;
+use crate::x86::PageMapper;
+use crate::x86::TranslationResult;
+use crate::x86::ATTR_CACHE_DISABLE;
+use crate::x86::ATTR_NO_EXECUTE;
+use crate::x86::ATTR_PAGE_SIZE;
+use crate::x86::ATTR_PRESENT;
+use crate::x86::ATTR_WRITABLE;
+use crate::x86::ATTR_WRITE_THROUGH;
+use crate::x86::ENTRY_ADDR_MASK;
+use crate::x86::PML4;
+use crate::x86::PML5;
+use crate::x86::VIRT_ADDR_BITS;
+use crate::x86::VIRT_ADDR_BITS_LA57;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+// ページテーブルの整合性チェッカー
+// PML4全体を辿って、壊れたエントリや危険なマッピングを報告する
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageTableIssueKind {
+    ReservedBitsSet,
+    OutsideOfRam,
+    TableNotMapped,
+    TableReferencedTwice,
+    WritableAndExecutable,
+}
+
+#[derive(Debug, Clone)]
+pub struct PageTableIssue {
+    pub kind: PageTableIssueKind,
+    /// Level of the offending entry (1: PT, 2: PD, 3: PDPT, 4: PML4, 5: PML5)
+    pub level: usize,
+    /// Virtual address range covered by the offending entries.
+    /// Adjacent entries with the same kind of issue are merged into one.
+    pub virt: Range<u64>,
+    /// Raw value of the first offending entry
+    pub entry: u64,
+}
+
+pub struct PageTableCheckConfig {
+    /// Physical address ranges backed by RAM (sorted, non-overlapping)
+    pub ram: Vec<Range<u64>>,
+    /// Report leaves that are both writable and executable
+    pub wx_strict: bool,
+    /// Processor state which decides the reserved bits and NX
+    pub mmu: MmuConfig,
+    /// Virtual ranges which share the tables of another range (e.g. the
+    /// direct map). They are not walked.
+    pub aliases: Vec<Range<u64>>,
+}
+
+impl PageTableCheckConfig {
+    pub fn from_memory_map(memory_map: &MemoryMapHolder) -> Self {
+        let mut ram: Vec<Range<u64>> = Vec::new();
+        for e in memory_map.iter() {
+            match e.memory_type() {
+                EfiMemoryType::MEMORY_MAPPED_IO | EfiMemoryType::MEMORY_MAPPED_IO_PORT_SPACE => {
+                    continue;
+                }
+                _ => {}
+            }
+            let start = e.physical_start();
+            let end = start + e.number_of_pages() * 4096;
+            ram.push(start..end);
+        }
+        ram.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<u64>> = Vec::new();
+        for r in ram {
+            match merged.last_mut() {
+                Some(last) if last.end >= r.start => {
+                    last.end = core::cmp::max(last.end, r.end);
+                }
+                _ => merged.push(r),
+            }
+        }
+        Self {
+            ram: merged,
+            wx_strict: false,
+            mmu: MmuConfig::current(),
+            aliases: direct_map_range().into_iter().collect(),
+        }
+    }
+    pub fn is_ram(&self, phys: u64, size: u64) -> bool {
+        let i = self.ram.partition_point(|r| r.start <= phys);
+        i > 0 && phys + size <= self.ram[i - 1].end
+    }
+}
+
+pub struct PageTableCheckReport {
+    pub issues: Vec<PageTableIssue>,
+    pub num_tables: usize,
+    /// Number of tables indexed by their level
+    pub tables_per_level: [usize; 6],
+    pub num_leaves: usize,
+}
+
+impl PageTableCheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+    pub fn count(&self, kind: PageTableIssueKind) -> usize {
+        self.issues.iter().filter(|e| e.kind == kind).count()
+    }
+}
+
+impl fmt::Display for PageTableCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "PageTableCheckReport: {} tables, {} leaves, {} issues",
+            self.num_tables,
+            self.num_leaves,
+            self.issues.len()
+        )?;
+        for e in &self.issues {
+            writeln!(
+                f,
+                "  {:?} @ L{} {:#018X}-{:#018X} (entry = {:#018X})",
+                e.kind, e.level, e.virt.start, e.virt.end, e.entry
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn level_page_size(level: usize) -> u64 {
+    1 << (12 + 9 * (level - 1))
+}
+
+fn sign_extend(addr: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((addr << shift) as i64) >> shift) as u64
+}
+
+struct PageTableChecker<'a> {
+    root: &'a dyn PageMapper,
+    virt_addr_bits: u32,
+    config: &'a PageTableCheckConfig,
+    seen_tables: BTreeSet<u64>,
+    report: PageTableCheckReport,
+}
+
+impl<'a> PageTableChecker<'a> {
+    fn new(
+        root: &'a dyn PageMapper,
+        virt_addr_bits: u32,
+        config: &'a PageTableCheckConfig,
+    ) -> Self {
+        Self {
+            root,
+            virt_addr_bits,
+            config,
+            seen_tables: BTreeSet::new(),
+            report: PageTableCheckReport {
+                issues: Vec::new(),
+                num_tables: 0,
+                tables_per_level: [0; 6],
+                num_leaves: 0,
+            },
+        }
+    }
+    fn add_issue(&mut self, kind: PageTableIssueKind, level: usize, virt: u64, entry: u64) {
+        let end = virt.wrapping_add(level_page_size(level));
+        if let Some(last) = self.report.issues.last_mut() {
+            if last.kind == kind && last.level == level && last.virt.end == virt {
+                last.virt.end = end;
+                return;
+            }
+        }
+        self.report.issues.push(PageTableIssue {
+            kind,
+            level,
+            virt: virt..end,
+            entry,
+        })
+    }
+    fn is_mapped_at_identity(&self, phys: u64) -> bool {
+        matches!(
+            self.root.translate(phys),
+            Ok(TranslationResult::PageMapped4K { phys: p })
+            | Ok(TranslationResult::PageMapped2M { phys: p })
+            | Ok(TranslationResult::PageMapped1G { phys: p }) if p == phys
+        )
+    }
+    fn visit_table(&mut self, level: usize, virt: u64, value: u64, phys: u64) -> bool {
+        if !self.seen_tables.insert(phys) {
+            self.add_issue(PageTableIssueKind::TableReferencedTwice, level, virt, value);
+            return false;
+        }
+        self.report.num_tables += 1;
+        self.report.tables_per_level[level - 1] += 1;
+        if !self.config.is_ram(phys, 4096) {
+            self.add_issue(PageTableIssueKind::OutsideOfRam, level, virt, value);
+        }
+        // Tables are accessed through the identity mapping, so they should be
+        // reachable from the table itself.
+        if !self.is_mapped_at_identity(phys) {
+            self.add_issue(PageTableIssueKind::TableNotMapped, level, virt, value);
+        }
+        true
+    }
+    // Checks an entry and returns the effective (writable, executable) for
+    // the next level if it points to a table that should be visited.
+    fn visit_entry(
+        &mut self,
+        level: usize,
+        virt: u64,
+        value: u64,
+        perm: (bool, bool),
+    ) -> Option<(bool, bool)> {
+        if value & ATTR_PRESENT == 0 {
+            return None;
+        }
+        let virt = sign_extend(virt, self.virt_addr_bits);
+        if self.config.aliases.iter().any(|r| r.contains(&virt)) {
+            return None;
+        }
+        if self.config.mmu.reserved_bits(level, value) != 0 {
+            self.add_issue(PageTableIssueKind::ReservedBitsSet, level, virt, value);
+            return None;
+        }
+        let writable = perm.0 && value & ATTR_WRITABLE != 0;
+        let executable = perm.1 && !(self.config.mmu.nx_enabled && value & ATTR_NO_EXECUTE != 0);
+        let is_leaf = level == 1 || (level <= 3 && value & ATTR_PAGE_SIZE != 0);
+        let size = level_page_size(level);
+        let phys = if is_leaf {
+            // Bit 12 of large page entries is PAT, not a part of address
+            value & ENTRY_ADDR_MASK & !(size - 1)
+        } else {
+            value & ENTRY_ADDR_MASK
+        };
+        if !is_leaf {
+            return self
+                .visit_table(level, virt, value, phys)
+                .then_some((writable, executable));
+        }
+        self.report.num_leaves += 1;
+        // Non write-back mappings are treated as intentional MMIO mappings
+        let is_write_back = value & (ATTR_CACHE_DISABLE | ATTR_WRITE_THROUGH) == 0;
+        if is_write_back && !self.config.is_ram(phys, size) {
+            self.add_issue(PageTableIssueKind::OutsideOfRam, level, virt, value);
+        }
+        if self.config.wx_strict && writable && executable {
+            self.add_issue(
+                PageTableIssueKind::WritableAndExecutable,
+                level,
+                virt,
+                value,
+            );
+        }
+        None
+    }
+    fn check_root(&mut self, root_phys: u64, level: usize) {
+        self.seen_tables.insert(root_phys);
+        self.report.num_tables += 1;
+        self.report.tables_per_level[level] += 1;
+        if !self.is_mapped_at_identity(root_phys) {
+            self.add_issue(
+                PageTableIssueKind::TableNotMapped,
+                level,
+                root_phys,
+                root_phys,
+            );
+        }
+    }
+    fn check_pml4(&mut self, pml4: &PML4, va_base: u64, perm: (bool, bool)) {
+        for (i4, e4) in pml4.entries().iter().enumerate() {
+            let va4 = va_base | (i4 as u64) << 39;
+            let Some(perm) = self.visit_entry(4, va4, e4.value(), perm) else {
+                continue;
+            };
+            let Ok(pdpt) = e4.table() else { continue };
+            for (i3, e3) in pdpt.entries().iter().enumerate() {
+                let va3 = va4 | (i3 as u64) << 30;
+                let Some(perm) = self.visit_entry(3, va3, e3.value(), perm) else {
+                    continue;
+                };
+                let Ok(pd) = e3.table() else { continue };
+                for (i2, e2) in pd.entries().iter().enumerate() {
+                    let va2 = va3 | (i2 as u64) << 21;
+                    let Some(perm) = self.visit_entry(2, va2, e2.value(), perm) else {
+                        continue;
+                    };
+                    let Ok(pt) = e2.table() else { continue };
+                    for (i1, e1) in pt.entries().iter().enumerate() {
+                        let va1 = va2 | (i1 as u64) << 12;
+                        self.visit_entry(1, va1, e1.value(), perm);
+                    }
+                }
+            }
+        }
+    }
+    fn check_pml5(&mut self, pml5: &PML5) {
+        for (i5, e5) in pml5.entries().iter().enumerate() {
+            let va5 = (i5 as u64) << 48;
+            let Some(perm) = self.visit_entry(5, va5, e5.value(), (true, true)) else {
+                continue;
+            };
+            let Ok(pml4) = e5.table() else { continue };
+            self.check_pml4(pml4, va5, perm);
+        }
+    }
+}
+
+/// Walks the entire 4-level page table and reports any suspicious entries.
+pub fn check_page_table(table: &PML4, config: &PageTableCheckConfig) -> PageTableCheckReport {
+    let mut checker = PageTableChecker::new(table, VIRT_ADDR_BITS, config);
+    checker.check_root(table as *const PML4 as u64, 4);
+    checker.check_pml4(table, 0, (true, true));
+    checker.report
+}
+
+/// Walks the entire 5-level page table and reports any suspicious entries.
+pub fn check_page_table_la57(table: &PML5, config: &PageTableCheckConfig) -> PageTableCheckReport {
+    let mut checker = PageTableChecker::new(table, VIRT_ADDR_BITS_LA57, config);
+    checker.check_root(table as *const PML5 as u64, 5);
+    checker.check_pml5(table);
+    checker.report
+}
+
+/// Checks the page table loaded in CR3.
+pub fn check_current_page_table(config: &PageTableCheckConfig) -> PageTableCheckReport {
+    if is_la57_enabled() {
+        check_page_table_la57(unsafe { &*(read_cr3() as *const PML5) }, config)
+    } else {
+        check_page_table(unsafe { &*read_cr3() }, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::ATTR_USER;
+    use crate::x86::PD;
+    use crate::x86::PDPT;
+    use crate::x86::PT;
+    use alloc::boxed::Box;
+
+    const KERNEL_RW: u64 = ATTR_PRESENT | ATTR_WRITABLE;
+
+    // PML4[0] -> PDPT[0] -> PD[0] -> PT. The tables are not mapped by
+    // themselves, so TableNotMapped is always reported.
+    struct Tables {
+        pml4: Box<PML4>,
+        _pdpt: Box<PDPT>,
+        pd: Box<PD>,
+        pt: Box<PT>,
+    }
+    impl Tables {
+        fn new() -> Self {
+            let mut pml4 = PML4::new_for_test();
+            let mut pdpt = PDPT::new_for_test();
+            let mut pd = PD::new_for_test();
+            let pt = PT::new_for_test();
+            pml4.set_entry_for_test(0, pdpt.phys_for_test() | KERNEL_RW | ATTR_USER);
+            pdpt.set_entry_for_test(0, pd.phys_for_test() | KERNEL_RW | ATTR_USER);
+            pd.set_entry_for_test(0, pt.phys_for_test() | KERNEL_RW | ATTR_USER);
+            Self {
+                pml4,
+                _pdpt: pdpt,
+                pd,
+                pt,
+            }
+        }
+        fn check(&self, wx_strict: bool) -> PageTableCheckReport {
+            let config = PageTableCheckConfig {
+                ram: core::iter::once(0..1 << 48).collect(),
+                wx_strict,
+                mmu: MmuConfig {
+                    phys_addr_bits: 48,
+                    nx_enabled: true,
+                    page_1g_supported: true,
+                    write_protect: true,
+                    smep: false,
+                    smap: false,
+                },
+                aliases: Vec::new(),
+            };
+            check_page_table(&self.pml4, &config)
+        }
+    }
+
+    fn issues(report: &PageTableCheckReport, kind: PageTableIssueKind) -> Vec<(usize, u64)> {
+        report
+            .issues
+            .iter()
+            .filter(|e| e.kind == kind)
+            .map(|e| (e.level, e.virt.start))
+            .collect()
+    }
+
+    #[test]
+    fn valid_tables() {
+        let mut t = Tables::new();
+        t.pt.set_entry_for_test(1, 0x0010_0000 | KERNEL_RW | ATTR_NO_EXECUTE);
+        let report = t.check(true);
+        assert_eq!(report.num_tables, 4);
+        assert_eq!(report.num_leaves, 1);
+        assert_eq!(report.count(PageTableIssueKind::TableNotMapped), 4);
+        assert_eq!(report.issues.len(), 4);
+    }
+
+    #[test]
+    fn reserved_bit() {
+        let mut t = Tables::new();
+        // Beyond MAXPHYADDR
+        t.pt.set_entry_for_test(1, (1 << 50) | KERNEL_RW);
+        let report = t.check(false);
+        assert_eq!(
+            issues(&report, PageTableIssueKind::ReservedBitsSet),
+            [(1, 0x1000)]
+        );
+        assert_eq!(report.num_leaves, 0);
+    }
+
+    #[test]
+    fn misaligned_huge_page() {
+        let mut t = Tables::new();
+        t.pd.set_entry_for_test(1, 0x0020_2000 | KERNEL_RW | ATTR_PAGE_SIZE);
+        // Bit 12 is PAT, so a 4KiB offset alone is not an error
+        t.pd.set_entry_for_test(2, 0x0040_1000 | KERNEL_RW | ATTR_PAGE_SIZE);
+        let report = t.check(false);
+        assert_eq!(
+            issues(&report, PageTableIssueKind::ReservedBitsSet),
+            [(2, 0x0020_0000)]
+        );
+        assert_eq!(report.num_leaves, 1);
+    }
+
+    #[test]
+    fn writable_and_executable() {
+        let mut t = Tables::new();
+        t.pt.set_entry_for_test(1, 0x0010_0000 | KERNEL_RW);
+        t.pt.set_entry_for_test(2, 0x0010_1000 | KERNEL_RW);
+        t.pt.set_entry_for_test(3, 0x0010_2000 | KERNEL_RW | ATTR_NO_EXECUTE);
+        t.pt.set_entry_for_test(4, 0x0010_3000 | ATTR_PRESENT);
+        let report = t.check(true);
+        // Adjacent entries are merged
+        let wx: Vec<_> = report
+            .issues
+            .iter()
+            .filter(|e| e.kind == PageTableIssueKind::WritableAndExecutable)
+            .map(|e| e.virt.clone())
+            .collect();
+        assert_eq!(wx.len(), 1);
+        assert_eq!(wx[0], 0x1000..0x3000);
+        assert_eq!(
+            t.check(false)
+                .count(PageTableIssueKind::WritableAndExecutable),
+            0
+        );
+        // A read-only upper level makes the leaves read-only
+        t.pd.set_entry_for_test(0, t.pt.phys_for_test() | ATTR_PRESENT);
+        assert_eq!(
+            t.check(true)
+                .count(PageTableIssueKind::WritableAndExecutable),
+            0
+        );
+    }
+}
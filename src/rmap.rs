@@ -0,0 +1,103 @@
+extern crate alloc;
+
+use crate::address_space::flush_tlb_page_all_address_spaces;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::result::Result;
+use crate::x86::page_table_at;
+use crate::x86::PageMapper;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+// 逆マッピング（rmap）: 物理フレームから、それをマップしているページテーブルエントリを引く
+// ページテーブルのエントリを書き換える箇所（create_mappingとwrite_pte）で更新される
+// 恒等マッピングは記録しない。また、複数のアドレス空間で共有されている下位のテーブル
+// （カーネル部分など）のマッピングは、書き換えに使われたルートで1回だけ記録される
+
+/// A 4KiB page table entry which maps a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// Physical address of the root table (the value of CR3)
+    pub root: u64,
+    pub virt: u64,
+}
+
+struct ReverseMap {
+    // Keyed by the physical address of the frame
+    inner: RefCell<BTreeMap<u64, Vec<Mapping>>>,
+}
+
+unsafe impl Sync for ReverseMap {}
+
+static RMAP: ReverseMap = ReverseMap {
+    inner: RefCell::new(BTreeMap::new()),
+};
+
+pub(crate) fn rmap_add(phys: u64, root: u64, virt: u64) {
+    RMAP.inner
+        .borrow_mut()
+        .entry(phys)
+        .or_default()
+        .push(Mapping { root, virt });
+}
+
+pub(crate) fn rmap_remove(phys: u64, root: u64, virt: u64) {
+    let mut rmap = RMAP.inner.borrow_mut();
+    let Some(mappings) = rmap.get_mut(&phys) else {
+        return;
+    };
+    if let Some(i) = mappings.iter().position(|m| *m == Mapping { root, virt }) {
+        mappings.swap_remove(i);
+    }
+    if mappings.is_empty() {
+        rmap.remove(&phys);
+    }
+}
+
+/// Forgets the mappings made through the root table at root, which is being
+/// dropped.
+pub(crate) fn rmap_forget_root(root: u64) {
+    let mut rmap = RMAP.inner.borrow_mut();
+    rmap.retain(|phys, mappings| {
+        mappings.retain(|m| {
+            if m.root == root {
+                FRAME_ALLOCATOR.dec_map_count(*phys);
+            }
+            m.root != root
+        });
+        !mappings.is_empty()
+    });
+}
+
+/// Returns the page table entries which map the frame at phys.
+pub fn mappings_of(phys: u64) -> Vec<Mapping> {
+    RMAP.inner.borrow().get(&phys).cloned().unwrap_or_default()
+}
+
+/// Calls f(table, virt, pte) for each page table entry which maps the frame
+/// at phys, where table is the root table and pte is the value of the
+/// entry. f may change the mappings through table.
+pub fn for_each_mapping(phys: u64, mut f: impl FnMut(&mut dyn PageMapper, u64, u64)) {
+    for m in mappings_of(phys) {
+        let table = unsafe { &mut *page_table_at(m.root) };
+        if let Ok(pte) = table.read_pte(m.virt) {
+            f(table, m.virt, pte);
+        }
+    }
+}
+
+/// Unmaps the frame at phys from all the page tables, and returns the number
+/// of the entries cleared. The frame itself is not freed.
+pub fn unmap_frame(phys: u64) -> Result<usize> {
+    let mut result = Ok(0);
+    for_each_mapping(phys, |table, virt, _| {
+        if let Ok(count) = &mut result {
+            match table.write_pte(virt, 0) {
+                Ok(()) => *count += 1,
+                Err(e) => result = Err(e),
+            }
+            flush_tlb_page_all_address_spaces(virt);
+        }
+    });
+    result
+}
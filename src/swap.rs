@@ -0,0 +1,592 @@
+extern crate alloc;
+
+use crate::address_space::flush_tlb_page_all_address_spaces;
+use crate::frame::frame_info;
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::frame::FRAME_FLAG_COW;
+use crate::frame::FRAME_FLAG_ZERO;
+use crate::info;
+use crate::result::Result;
+use crate::thp::split_huge_page;
+use crate::virtio_blk::VirtioBlk;
+use crate::virtio_blk::SECTOR_SIZE;
+use crate::warn;
+use crate::x86::current_page_table;
+use crate::x86::register_exception_handler;
+use crate::x86::ExceptionInfo;
+use crate::x86::PageAttr;
+use crate::x86::ATTR_ACCESSED;
+use crate::x86::ATTR_PRESENT;
+use crate::x86::ATTR_WRITABLE;
+use crate::x86::ENTRY_ADDR_MASK;
+use crate::x86::HUGE_PAGE_SIZE;
+use crate::x86::PAGE_SIZE;
+use crate::x86::PF_ERROR_WRITE;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::BTreeSet;
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+
+// 無名メモリとスワップ
+// 無名メモリは専用の仮想アドレス領域に確保され、ページは初回アクセス時に割り当てる
+// 読み込みだけのページは共有のゼロページを読み込み専用でマップし、書き込み時にコピーする
+// 物理フレームが足りなくなると、アクセスビットを見るクロック（セカンドチャンス）方式で
+// 追い出すページを選び、スワップ領域に書き出す
+
+pub const ANON_START: u64 = 0xFFFF_D800_0000_0000;
+pub const ANON_SIZE: u64 = 1 << 36;
+
+// A swapped-out page has a non-present entry with this bit set, and the slot
+// number in bits 51:12 (where the frame address would be).
+const SWAP_ENTRY_MARKER: u64 = 1 << 9;
+
+fn swap_entry(slot: usize) -> u64 {
+    (slot as u64) << 12 | SWAP_ENTRY_MARKER
+}
+fn swap_slot(pte: u64) -> Option<usize> {
+    (pte & (ATTR_PRESENT | SWAP_ENTRY_MARKER) == SWAP_ENTRY_MARKER)
+        .then_some(((pte & ENTRY_ADDR_MASK) >> 12) as usize)
+}
+
+/// A backing store of swapped-out pages. Each slot holds one page.
+pub trait SwapDevice {
+    fn name(&self) -> &'static str;
+    fn num_slots(&self) -> usize;
+    /// page is identity-mapped.
+    fn read_slot(&mut self, slot: usize, page: &mut [u8; PAGE_SIZE]) -> Result<()>;
+    /// page is identity-mapped.
+    fn write_slot(&mut self, slot: usize, page: &[u8; PAGE_SIZE]) -> Result<()>;
+    /// Called when the content of the slot is no longer needed.
+    fn discard_slot(&mut self, _slot: usize) {}
+}
+
+/// Keeps swapped-out pages on the heap, which is not managed by the frame
+/// allocator.
+pub struct RamSwap {
+    slots: Vec<Option<Box<[u8; PAGE_SIZE]>>>,
+}
+impl RamSwap {
+    pub fn new(num_slots: usize) -> Self {
+        let mut slots = Vec::with_capacity(num_slots);
+        slots.resize_with(num_slots, || None);
+        Self { slots }
+    }
+}
+impl SwapDevice for RamSwap {
+    fn name(&self) -> &'static str {
+        "ram"
+    }
+    fn num_slots(&self) -> usize {
+        self.slots.len()
+    }
+    fn read_slot(&mut self, slot: usize, page: &mut [u8; PAGE_SIZE]) -> Result<()> {
+        let data = self.slots[slot].as_ref().ok_or("RamSwap: empty slot")?;
+        page.copy_from_slice(data.as_ref());
+        Ok(())
+    }
+    fn write_slot(&mut self, slot: usize, page: &[u8; PAGE_SIZE]) -> Result<()> {
+        self.slots[slot] = Some(Box::new(*page));
+        Ok(())
+    }
+    fn discard_slot(&mut self, slot: usize) {
+        self.slots[slot] = None;
+    }
+}
+
+impl SwapDevice for VirtioBlk {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+    fn num_slots(&self) -> usize {
+        (self.num_sectors() / (PAGE_SIZE / SECTOR_SIZE) as u64) as usize
+    }
+    fn read_slot(&mut self, slot: usize, page: &mut [u8; PAGE_SIZE]) -> Result<()> {
+        self.read((slot * PAGE_SIZE / SECTOR_SIZE) as u64, page)
+    }
+    fn write_slot(&mut self, slot: usize, page: &[u8; PAGE_SIZE]) -> Result<()> {
+        self.write((slot * PAGE_SIZE / SECTOR_SIZE) as u64, page)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SwapStats {
+    pub resident_pages: usize,
+    pub swapped_pages: usize,
+    pub num_slots: usize,
+    pub swap_outs: u64,
+    pub swap_ins: u64,
+    pub zero_fills: u64,
+    /// Pages mapped to the shared zero frame
+    pub zero_mapped_pages: usize,
+    pub cow_faults: u64,
+}
+impl fmt::Display for SwapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "resident: {}, swapped: {} / {} slots, swap out: {}, swap in: {}, zero fill: {}, \
+             zero mapped: {}, COW: {}",
+            self.resident_pages,
+            self.swapped_pages,
+            self.num_slots,
+            self.swap_outs,
+            self.swap_ins,
+            self.zero_fills,
+            self.zero_mapped_pages,
+            self.cow_faults
+        )
+    }
+}
+
+struct SwapState {
+    device: Option<Box<dyn SwapDevice>>,
+    // 1 bit per slot (1: used)
+    slot_bitmap: Vec<u64>,
+    // Anonymous areas keyed by the start address
+    areas: BTreeMap<u64, u64>,
+    // Resident anonymous pages in the clock order. The front is the hand.
+    // Pages mapped to the zero frame are not included.
+    resident: VecDeque<u64>,
+    // Read-only frame filled with zero, shared by the pages only read so far
+    zero_frame: u64,
+    // Frames mapped read-only by multiple pages (e.g. merged by KSM). The
+    // number of the pages is the reference count of the frame.
+    shared_frames: BTreeSet<u64>,
+    // Maximum number of resident anonymous pages (0: no limit)
+    resident_limit: usize,
+    stats: SwapStats,
+}
+
+impl SwapState {
+    fn is_anonymous(&self, virt: u64) -> bool {
+        self.areas
+            .range(..=virt)
+            .next_back()
+            .is_some_and(|(_, end)| virt < *end)
+    }
+    fn alloc_slot(&mut self) -> Result<usize> {
+        let num_slots = self.stats.num_slots;
+        let (i, word) = self
+            .slot_bitmap
+            .iter_mut()
+            .enumerate()
+            .find(|(_, w)| **w != !0)
+            .ok_or("Swap is full")?;
+        let slot = i * 64 + word.trailing_ones() as usize;
+        if slot >= num_slots {
+            return Err("Swap is full");
+        }
+        *word |= 1 << (slot % 64);
+        self.stats.swapped_pages += 1;
+        Ok(slot)
+    }
+    fn free_slot(&mut self, slot: usize) {
+        self.slot_bitmap[slot / 64] &= !(1 << (slot % 64));
+        self.stats.swapped_pages -= 1;
+        if let Some(device) = self.device.as_mut() {
+            device.discard_slot(slot);
+        }
+    }
+    // Picks a victim with the clock algorithm: pages accessed since the hand
+    // passed them last time get a second chance.
+    fn pick_victim(&mut self) -> Result<u64> {
+        let table = unsafe { &mut *current_page_table() };
+        for _ in 0..self.resident.len() * 2 {
+            let virt = self.resident.pop_front().ok_or("No resident pages")?;
+            let Ok(pte) = table.read_pte(virt) else {
+                // Pages in 2MiB pages are not swapped out
+                self.resident.push_back(virt);
+                continue;
+            };
+            if pte & ATTR_ACCESSED == 0 {
+                return Ok(virt);
+            }
+            table.write_pte(virt, pte & !ATTR_ACCESSED)?;
+            flush_tlb_page_all_address_spaces(virt);
+            self.resident.push_back(virt);
+        }
+        Err("No page to evict")
+    }
+    // Writes the page at virt to slot and unmaps it. The page is left mapped
+    // if this fails.
+    fn swap_out(&mut self, virt: u64, slot: usize) -> Result<()> {
+        let table = unsafe { &mut *current_page_table() };
+        let pte = table.read_pte(virt)?;
+        let phys = pte & ENTRY_ADDR_MASK;
+        // Unmap first so that the page is not modified while it is written
+        table.write_pte(virt, swap_entry(slot))?;
+        flush_tlb_page_all_address_spaces(virt);
+        let result = match self.device.as_mut() {
+            Some(device) => device.write_slot(slot, unsafe { &*(phys as *const [u8; PAGE_SIZE]) }),
+            None => Err("No swap device"),
+        };
+        if let Err(e) = result {
+            table.write_pte(virt, pte)?;
+            flush_tlb_page_all_address_spaces(virt);
+            return Err(e);
+        }
+        self.put_frame(phys);
+        self.stats.swap_outs += 1;
+        Ok(())
+    }
+    fn evict_one(&mut self) -> Result<()> {
+        // Nothing is touched if the page can not be swapped out anyway
+        if self.device.is_none() {
+            return Err("No swap device");
+        }
+        let slot = self.alloc_slot()?;
+        let result = self.pick_victim().and_then(|virt| {
+            self.swap_out(virt, slot).map_err(|e| {
+                self.resident.push_back(virt);
+                e
+            })
+        });
+        if result.is_err() {
+            self.free_slot(slot);
+        }
+        result
+    }
+    // Allocates a frame, evicting a page if the limit is reached or the frame
+    // allocator is out of memory.
+    fn alloc_frame(&mut self) -> Result<u64> {
+        if self.resident_limit != 0 && self.resident.len() >= self.resident_limit {
+            self.evict_one()?;
+        }
+        match FRAME_ALLOCATOR.alloc_frame_as(FrameState::User) {
+            Ok(phys) => Ok(phys),
+            Err(_) => {
+                self.evict_one()?;
+                FRAME_ALLOCATOR.alloc_frame_as(FrameState::User)
+            }
+        }
+    }
+    // Drops a reference to a frame mapped by an anonymous page. The zero
+    // frame is never freed since init_swap() holds a reference to it.
+    fn put_frame(&mut self, phys: u64) {
+        if FRAME_ALLOCATOR.put_frame(phys) {
+            self.shared_frames.remove(&phys);
+        }
+    }
+    fn map_page(&mut self, virt: u64, phys: u64, attr: PageAttr) -> Result<()> {
+        unsafe {
+            (*current_page_table()).create_mapping(virt, virt + PAGE_SIZE as u64, phys, attr)?;
+        }
+        flush_tlb_page_all_address_spaces(virt);
+        Ok(())
+    }
+    fn map_resident(&mut self, virt: u64, phys: u64) -> Result<()> {
+        self.map_page(virt, phys, PageAttr::ReadWriteKernel)?;
+        self.resident.push_back(virt);
+        Ok(())
+    }
+    // Handles a write to a read-only anonymous page
+    fn break_cow(&mut self, virt: u64, pte: u64) -> Result<()> {
+        let phys = pte & ENTRY_ADDR_MASK;
+        if phys == self.zero_frame {
+            let new = self.alloc_frame()?;
+            self.map_resident(virt, new)?;
+            self.put_frame(phys);
+            self.stats.zero_mapped_pages -= 1;
+        } else if frame_info(phys).is_some_and(|info| info.ref_count > 1) {
+            let new = self.alloc_frame()?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(phys as *const u8, new as *mut u8, PAGE_SIZE);
+            }
+            // The page stays in the resident list
+            self.map_page(virt, new, PageAttr::ReadWriteKernel)?;
+            self.put_frame(phys);
+        } else {
+            // The last user of the frame can just take it
+            self.shared_frames.remove(&phys);
+            FRAME_ALLOCATOR.set_frame_flags(phys, FRAME_FLAG_COW, false);
+            self.map_page(virt, phys, PageAttr::ReadWriteKernel)?;
+        }
+        self.stats.cow_faults += 1;
+        Ok(())
+    }
+    fn handle_fault(&mut self, virt: u64, error_code: u64) -> Result<()> {
+        let pte = unsafe { (*current_page_table()).read_pte(virt) }.unwrap_or(0);
+        if pte & ATTR_PRESENT != 0 {
+            if error_code & PF_ERROR_WRITE == 0 || pte & ATTR_WRITABLE != 0 {
+                return Err("Unexpected fault on an anonymous page");
+            }
+            self.break_cow(virt, pte)
+        } else if let Some(slot) = swap_slot(pte) {
+            let phys = self.alloc_frame()?;
+            let page = unsafe { &mut *(phys as *mut [u8; PAGE_SIZE]) };
+            // The slot keeps the content until the page is mapped again
+            let result = match self.device.as_mut() {
+                Some(device) => device.read_slot(slot, page),
+                None => Err("No swap device"),
+            }
+            .and_then(|_| self.map_resident(virt, phys));
+            if let Err(e) = result {
+                FRAME_ALLOCATOR.free_frame(phys);
+                return Err(e);
+            }
+            self.free_slot(slot);
+            self.stats.swap_ins += 1;
+            Ok(())
+        } else if pte == 0 && error_code & PF_ERROR_WRITE == 0 {
+            // First access to the page is a read
+            self.map_page(virt, self.zero_frame, PageAttr::ReadOnlyKernel)?;
+            FRAME_ALLOCATOR.get_frame(self.zero_frame);
+            self.stats.zero_mapped_pages += 1;
+            Ok(())
+        } else if pte == 0 {
+            let phys = self.alloc_frame()?;
+            self.map_resident(virt, phys)?;
+            self.stats.zero_fills += 1;
+            Ok(())
+        } else {
+            Err("Unexpected entry in an anonymous area")
+        }
+    }
+    // Makes a resident page read-only so that its frame can be shared, and
+    // returns the frame.
+    fn share_page(&mut self, virt: u64) -> Result<u64> {
+        let pte = unsafe { (*current_page_table()).read_pte(virt)? };
+        let phys = pte & ENTRY_ADDR_MASK;
+        if pte & ATTR_PRESENT == 0 || phys == self.zero_frame {
+            return Err("Page is not resident");
+        }
+        if pte & ATTR_WRITABLE != 0 {
+            self.map_page(virt, phys, PageAttr::ReadOnlyKernel)?;
+            self.shared_frames.insert(phys);
+            FRAME_ALLOCATOR.set_frame_flags(phys, FRAME_FLAG_COW, true);
+        }
+        Ok(phys)
+    }
+    // Maps virt to the frame shared by another page, or the zero frame if
+    // target is None, and releases the frame of virt.
+    fn merge_page(&mut self, virt: u64, target: Option<u64>) -> Result<()> {
+        let pte = unsafe { (*current_page_table()).read_pte(virt)? };
+        let phys = pte & ENTRY_ADDR_MASK;
+        if pte & ATTR_PRESENT == 0 || phys == self.zero_frame {
+            return Err("Page is not resident");
+        }
+        match target {
+            Some(target) => {
+                if !self.shared_frames.contains(&target) {
+                    return Err("Target is not shared");
+                }
+                self.map_page(virt, target, PageAttr::ReadOnlyKernel)?;
+                FRAME_ALLOCATOR.get_frame(target);
+            }
+            None => {
+                self.map_page(virt, self.zero_frame, PageAttr::ReadOnlyKernel)?;
+                FRAME_ALLOCATOR.get_frame(self.zero_frame);
+                self.resident.retain(|v| *v != virt);
+                self.stats.zero_mapped_pages += 1;
+            }
+        }
+        self.put_frame(phys);
+        Ok(())
+    }
+}
+
+struct Swap {
+    state: RefCell<SwapState>,
+}
+
+unsafe impl Sync for Swap {}
+
+static SWAP: Swap = Swap {
+    state: RefCell::new(SwapState {
+        device: None,
+        slot_bitmap: Vec::new(),
+        areas: BTreeMap::new(),
+        resident: VecDeque::new(),
+        zero_frame: 0,
+        shared_frames: BTreeSet::new(),
+        resident_limit: 0,
+        stats: SwapStats {
+            resident_pages: 0,
+            swapped_pages: 0,
+            num_slots: 0,
+            swap_outs: 0,
+            swap_ins: 0,
+            zero_fills: 0,
+            zero_mapped_pages: 0,
+            cow_faults: 0,
+        },
+    }),
+};
+
+fn swap_page_fault_handler(e: &mut ExceptionInfo) -> bool {
+    // A fault while the state is borrowed is a bug of this module
+    let Ok(mut state) = SWAP.state.try_borrow_mut() else {
+        return false;
+    };
+    let virt = e.fault_addr & !(PAGE_SIZE as u64 - 1);
+    state.is_anonymous(virt) && state.handle_fault(virt, e.error_code).is_ok()
+}
+
+/// Returns a virtio-blk device if found, or a RAM-backed store of
+/// ram_slots pages otherwise.
+pub fn probe_swap_device(ram_slots: usize) -> Box<dyn SwapDevice> {
+    match VirtioBlk::probe() {
+        Some(Ok(blk)) => {
+            info!(
+                "virtio-blk @ {:?}: {} sectors",
+                blk.bdf(),
+                blk.num_sectors()
+            );
+            return Box::new(blk);
+        }
+        Some(Err(e)) => warn!("Failed to initialize virtio-blk: {e}"),
+        None => {}
+    }
+    Box::new(RamSwap::new(ram_slots))
+}
+
+/// Sets up the swap with the given device and installs the page fault
+/// handler for anonymous memory. Should be called once, after
+/// init_exceptions().
+pub fn init_swap(device: Box<dyn SwapDevice>) -> Result<()> {
+    {
+        let mut state = SWAP.state.borrow_mut();
+        if state.device.is_some() {
+            return Err("Swap is already initialized");
+        }
+        state.zero_frame = FRAME_ALLOCATOR.alloc_frame_as(FrameState::User)?;
+        FRAME_ALLOCATOR.set_frame_flags(state.zero_frame, FRAME_FLAG_ZERO, true);
+        state.stats.num_slots = device.num_slots();
+        state.slot_bitmap = vec![0; device.num_slots().div_ceil(64)];
+        state.device = Some(device);
+    }
+    register_exception_handler(14, swap_page_fault_handler)
+}
+
+/// Limits the number of resident anonymous pages (0: no limit). Useful to
+/// exercise the swap without exhausting the frame allocator.
+pub fn set_resident_limit(num_pages: usize) {
+    SWAP.state.borrow_mut().resident_limit = num_pages;
+}
+
+pub fn swap_stats() -> SwapStats {
+    let state = SWAP.state.borrow();
+    SwapStats {
+        resident_pages: state.resident.len(),
+        ..state.stats
+    }
+}
+
+/// Frames shared by multiple anonymous pages
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SharingStats {
+    pub shared_frames: usize,
+    /// Pages mapped to the shared frames
+    pub sharing_pages: usize,
+    pub zero_mapped_pages: usize,
+}
+impl SharingStats {
+    /// Number of frames which would be used without sharing, minus the
+    /// number of frames used now
+    pub fn frames_saved(&self) -> usize {
+        self.sharing_pages - self.shared_frames + self.zero_mapped_pages
+    }
+}
+
+pub fn sharing_stats() -> SharingStats {
+    let state = SWAP.state.borrow();
+    SharingStats {
+        shared_frames: state.shared_frames.len(),
+        sharing_pages: state
+            .shared_frames
+            .iter()
+            .filter_map(|phys| frame_info(*phys))
+            .map(|info| info.ref_count as usize)
+            .sum(),
+        zero_mapped_pages: state.stats.zero_mapped_pages,
+    }
+}
+
+// Hooks for the same-page merging (ksm.rs)
+pub(crate) fn resident_anonymous_pages() -> Vec<u64> {
+    SWAP.state.borrow().resident.iter().copied().collect()
+}
+pub(crate) fn share_anonymous_page(virt: u64) -> Result<u64> {
+    SWAP.state.borrow_mut().share_page(virt)
+}
+// Called when a frame of anonymous memory is moved by the compaction
+pub(crate) fn frame_migrated(old: u64, new: u64) {
+    let mut state = SWAP.state.borrow_mut();
+    if state.shared_frames.remove(&old) {
+        state.shared_frames.insert(new);
+    }
+}
+pub(crate) fn merge_anonymous_page(virt: u64, target: Option<u64>) -> Result<()> {
+    SWAP.state.borrow_mut().merge_page(virt, target)
+}
+
+/// Reserves size bytes of anonymous memory. Pages are allocated (zero-filled)
+/// on the first write and may be swapped out. Pages only read are mapped to
+/// a shared zero frame.
+pub fn alloc_anonymous(size: usize) -> Result<*mut u8> {
+    if size == 0 {
+        return Err("alloc_anonymous: size is zero");
+    }
+    let size = size.next_multiple_of(PAGE_SIZE) as u64;
+    let mut state = SWAP.state.borrow_mut();
+    // Leave an unmapped page between areas to catch overruns
+    let start = state
+        .areas
+        .last_key_value()
+        .map_or(ANON_START, |(_, end)| end + PAGE_SIZE as u64);
+    if start + size > ANON_START + ANON_SIZE {
+        return Err("alloc_anonymous: region exhausted");
+    }
+    state.areas.insert(start, start + size);
+    Ok(start as *mut u8)
+}
+
+/// Frees anonymous memory returned by alloc_anonymous().
+pub fn free_anonymous(ptr: *mut u8) -> Result<()> {
+    let mut state = SWAP.state.borrow_mut();
+    let start = ptr as u64;
+    let end = *state
+        .areas
+        .get(&start)
+        .ok_or("free_anonymous: not allocated by alloc_anonymous")?;
+    let table = unsafe { &mut *current_page_table() };
+    let mut huge = start & !(HUGE_PAGE_SIZE - 1);
+    while huge < end {
+        split_huge_page(table, huge)?;
+        huge += HUGE_PAGE_SIZE;
+    }
+    // The area is kept until here so that it can be freed again on errors
+    state.areas.remove(&start);
+    for virt in (start..end).step_by(PAGE_SIZE) {
+        let Ok(pte) = table.read_pte(virt) else {
+            continue;
+        };
+        table.write_pte(virt, 0)?;
+        flush_tlb_page_all_address_spaces(virt);
+        if pte & ATTR_PRESENT != 0 {
+            let phys = pte & ENTRY_ADDR_MASK;
+            if phys == state.zero_frame {
+                state.stats.zero_mapped_pages -= 1;
+            }
+            state.put_frame(phys);
+        } else if let Some(slot) = swap_slot(pte) {
+            state.free_slot(slot);
+        }
+    }
+    state.resident.retain(|virt| !(start..end).contains(virt));
+    Ok(())
+}
+
+/// Evicts up to num_pages anonymous pages and returns the number of pages
+/// swapped out.
+pub fn swap_out_pages(num_pages: usize) -> usize {
+    let mut state = SWAP.state.borrow_mut();
+    (0..num_pages)
+        .take_while(|_| state.evict_one().is_ok())
+        .count()
+}
@@ -0,0 +1,181 @@
+extern crate alloc;
+
+use crate::address_space::flush_tlb_range_all_address_spaces;
+use crate::frame::frame_info;
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::result::Result;
+use crate::x86::PageMapper;
+use crate::x86::ATTR_ACCESSED;
+use crate::x86::ATTR_DIRTY;
+use crate::x86::ATTR_NO_EXECUTE;
+use crate::x86::ATTR_PAGE_SIZE;
+use crate::x86::ATTR_PRESENT;
+use crate::x86::ENTRY_ADDR_MASK;
+use crate::x86::HUGE_PAGE_SIZE;
+use crate::x86::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use core::cell::RefCell;
+use core::fmt;
+use core::ops::Range;
+
+// Transparent Huge Page（THP）
+// 2MiB境界に揃った512個の4KiBページが同じ属性でマップされていれば、1つの2MiBページに
+// まとめてPTを解放する（Linuxのkhugepagedのようなもの）
+// フレームが物理的に連続していなければ、2MiBのブロックを確保してコピーする
+// 2MiBページの一部だけをマップし直す場合は、create_mappingの中で4KiBページに分割される
+
+const PAGES_PER_HUGE_PAGE: usize = 512;
+// Bits which should be the same in all the entries to be collapsed
+const ATTR_COMPARE_MASK: u64 = (0xFFF | ATTR_NO_EXECUTE) & !(ATTR_ACCESSED | ATTR_DIRTY);
+
+struct HugePageCounts {
+    // Number of 2MiB pages keyed by the root table they are mapped through
+    counts: RefCell<BTreeMap<u64, usize>>,
+}
+
+unsafe impl Sync for HugePageCounts {}
+
+static HUGE_PAGES: HugePageCounts = HugePageCounts {
+    counts: RefCell::new(BTreeMap::new()),
+};
+
+pub(crate) fn huge_page_mapped(root: u64) {
+    *HUGE_PAGES.counts.borrow_mut().entry(root).or_default() += 1;
+}
+
+pub(crate) fn huge_page_unmapped(root: u64) {
+    let mut counts = HUGE_PAGES.counts.borrow_mut();
+    if let Some(count) = counts.get_mut(&root) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&root);
+        }
+    }
+}
+
+/// Returns the number of 2MiB pages mapped by collapse through the root
+/// table at root.
+pub fn num_huge_pages(root: u64) -> usize {
+    HUGE_PAGES.counts.borrow().get(&root).copied().unwrap_or(0)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PromotionReport {
+    pub ranges_scanned: usize,
+    /// The frames were already contiguous and aligned
+    pub collapsed_in_place: usize,
+    /// The pages were copied to a newly allocated 2MiB block
+    pub collapsed_by_copy: usize,
+}
+impl PromotionReport {
+    pub fn num_collapsed(&self) -> usize {
+        self.collapsed_in_place + self.collapsed_by_copy
+    }
+}
+impl fmt::Display for PromotionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "THP: {} ranges scanned, {} collapsed in place, {} collapsed by copy",
+            self.ranges_scanned, self.collapsed_in_place, self.collapsed_by_copy
+        )
+    }
+}
+
+// Returns the frames and the common attributes of the 4KiB pages in the 2MiB
+// range at base if they can be collapsed.
+fn collapsible_pages(table: &dyn PageMapper, base: u64) -> Option<([u64; 512], u64)> {
+    let pde = table.read_pde(base).ok()?;
+    if pde & ATTR_PRESENT == 0 || pde & ATTR_PAGE_SIZE != 0 {
+        return None;
+    }
+    let mut frames = [0; PAGES_PER_HUGE_PAGE];
+    let mut attr = 0;
+    let mut accessed_dirty = 0;
+    for (i, frame) in frames.iter_mut().enumerate() {
+        let pte = table.read_pte(base + (i * PAGE_SIZE) as u64).ok()?;
+        if pte & ATTR_PRESENT == 0 {
+            return None;
+        }
+        if i == 0 {
+            attr = pte & ATTR_COMPARE_MASK;
+        } else if pte & ATTR_COMPARE_MASK != attr {
+            return None;
+        }
+        accessed_dirty |= pte & (ATTR_ACCESSED | ATTR_DIRTY);
+        *frame = pte & ENTRY_ADDR_MASK;
+        // Only private anonymous frames can be collapsed
+        let info = frame_info(*frame)?;
+        if info.state != FrameState::User || info.ref_count != 1 || info.flags != 0 {
+            return None;
+        }
+    }
+    Some((frames, attr | accessed_dirty))
+}
+
+fn flush_range(base: u64) {
+    flush_tlb_range_all_address_spaces(base..base + HUGE_PAGE_SIZE);
+}
+
+// Collapses the 4KiB pages in the 2MiB range at base into a 2MiB page.
+// Returns true if the pages are copied.
+fn collapse(table: &mut dyn PageMapper, base: u64, frames: &[u64; 512], attr: u64) -> Result<bool> {
+    let contiguous = frames[0] % HUGE_PAGE_SIZE == 0
+        && frames
+            .iter()
+            .enumerate()
+            .all(|(i, phys)| *phys == frames[0] + (i * PAGE_SIZE) as u64);
+    if contiguous {
+        table.collapse_huge_page(base, frames[0], attr)?;
+        flush_range(base);
+        return Ok(false);
+    }
+    let block = FRAME_ALLOCATOR.alloc_contiguous_as(
+        PAGES_PER_HUGE_PAGE,
+        PAGES_PER_HUGE_PAGE,
+        FrameState::User,
+    )?;
+    for (i, phys) in frames.iter().enumerate() {
+        let dst = block + (i * PAGE_SIZE) as u64;
+        unsafe { core::ptr::copy_nonoverlapping(*phys as *const u8, dst as *mut u8, PAGE_SIZE) };
+    }
+    if let Err(e) = table.collapse_huge_page(base, block, attr) {
+        FRAME_ALLOCATOR.free_contiguous(block, PAGES_PER_HUGE_PAGE);
+        return Err(e);
+    }
+    flush_range(base);
+    for phys in frames {
+        FRAME_ALLOCATOR.put_frame(*phys);
+    }
+    Ok(true)
+}
+
+/// Collapses each 2MiB-aligned range in range which is mapped by 512 private
+/// anonymous 4KiB pages with the same attributes into a 2MiB page.
+pub fn promote_huge_pages(table: &mut dyn PageMapper, range: Range<u64>) -> PromotionReport {
+    let mut report = PromotionReport::default();
+    let mut base = range.start.next_multiple_of(HUGE_PAGE_SIZE);
+    while base + HUGE_PAGE_SIZE <= range.end {
+        report.ranges_scanned += 1;
+        if let Some((frames, attr)) = collapsible_pages(table, base) {
+            match collapse(table, base, &frames, attr) {
+                Ok(false) => report.collapsed_in_place += 1,
+                Ok(true) => report.collapsed_by_copy += 1,
+                // e.g. no free 2MiB block. Try the compaction later.
+                Err(_) => {}
+            }
+        }
+        base += HUGE_PAGE_SIZE;
+    }
+    report
+}
+
+/// Splits the 2MiB page mapping virt into 4KiB pages, if any.
+pub fn split_huge_page(table: &mut dyn PageMapper, virt: u64) -> Result<bool> {
+    let split = table.split_huge_page(virt)?;
+    if split {
+        flush_range(virt & !(HUGE_PAGE_SIZE - 1));
+    }
+    Ok(split)
+}
@@ -0,0 +1,90 @@
+use crate::ex_table_entry;
+use crate::result::Result;
+use crate::x86::clac;
+use crate::x86::current_page_table;
+use crate::x86::stac;
+use crate::x86::virt_addr_bits;
+use crate::x86::PAGE_SIZE;
+use core::arch::asm;
+
+// カーネルからユーザー空間へのアクセス
+// SMAPが有効な場合、カーネルはEFLAGS.ACが立っている間（stac()からclac()まで）しか
+// ユーザーページを読み書きできない。copy_from_user/copy_to_userは、範囲がユーザー
+// 空間にあり、カーネルのページを含まないことを確かめてから、コピーの間だけACを立てる
+// マップされていないページでの#PFは例外修正テーブルで拾われ、EFAULTが返る
+// コピー中に割り込みが入っても、inthandler_commonがACをクリアするので、ハンドラは
+// ユーザーページにアクセスできない（ACはiretqで割り込み前の値に戻る）
+
+/// Returned if the user memory can not be accessed
+pub const EFAULT: &str = "Bad address";
+
+/// End of the lower half of the virtual address space, which is for users
+pub fn user_space_end() -> u64 {
+    1 << (virt_addr_bits() - 1)
+}
+
+/// Checks that [addr, addr + len) is in the user space and has no pages
+/// which are mapped but not accessible (writable if write is true) by the
+/// user. Pages which are not mapped are left to the fault handling.
+pub fn access_ok(addr: u64, len: usize, write: bool) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len as u64).ok_or(EFAULT)?;
+    if end > user_space_end() {
+        return Err(EFAULT);
+    }
+    let table = unsafe { &*current_page_table() };
+    let start = addr & !(PAGE_SIZE as u64 - 1);
+    // Unmapped pages are let through on purpose: the copy faults on them
+    // and the exception fixup table turns the fault into EFAULT, so we do
+    // not have to walk the tables again for the partially copied case.
+    // Only the pages the kernel could access silently (mapped without U/S,
+    // or read-only for a write) must be rejected here.
+    if (start..end)
+        .step_by(PAGE_SIZE)
+        .all(|page| table.translate(page).is_err() || table.is_user_accessible(page, write))
+    {
+        Ok(())
+    } else {
+        Err(EFAULT)
+    }
+}
+
+// Copies len bytes and returns the number of bytes not copied due to a
+// fault.
+unsafe fn copy_user_generic(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let remaining: usize;
+    stac();
+    // REP MOVSB can be resumed, so RCX holds the remaining count on a fault
+    asm!(
+        "3:",
+        "rep movsb",
+        "4:",
+        ex_table_entry!("3b", "4b"),
+        inout("rcx") len => remaining,
+        inout("rdi") dst => _,
+        inout("rsi") src => _,
+        options(att_syntax),
+    );
+    clac();
+    remaining
+}
+
+/// Copies dst.len() bytes from the user address src to dst.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<()> {
+    access_ok(src, dst.len(), false)?;
+    match unsafe { copy_user_generic(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+/// Copies src to the user address dst.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<()> {
+    access_ok(dst, src.len(), true)?;
+    match unsafe { copy_user_generic(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
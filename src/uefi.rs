@@ -1,6 +1,11 @@
 use core::ptr::null_mut;
 use crate::graphics::draw_font_fg;
+use crate::ioremap::CacheMode;
+use crate::memblock::reserve;
 use crate::result::Result;
+use crate::x86::current_page_table;
+use crate::x86::flush_tlb;
+use crate::x86::PAGE_SIZE;
 use core::fmt;
 
 type EfiVoid = u8;
@@ -254,11 +259,17 @@ pub fn init_vram(efi_system_table: &EfiSystemTable) -> Result<VramBufferInfo> {
 
     let gp = unsafe { &*(gp_ptr as *const EfiGraphicsOutputProtocol) };
 
+    reserve(
+        gp.mode.frame_buffer_base as u64,
+        gp.mode.frame_buffer_size as u64,
+        "GOP framebuffer",
+    )?;
     Ok(VramBufferInfo {
         buf: gp.mode.frame_buffer_base as *mut u8,
         width: gp.mode.info.horizontal_resolution as i64,
         height: gp.mode.info.vertical_resolution as i64,
         pixels_per_line: gp.mode.info.pixels_per_scan_line as i64,
+        size: gp.mode.frame_buffer_size,
     })
 }
 
@@ -268,6 +279,27 @@ pub struct VramBufferInfo {
     width: i64,
     height: i64,
     pixels_per_line: i64,
+    size: usize,
+}
+
+impl VramBufferInfo {
+    // 恒等マップされたフレームバッファをWrite-Combiningでマップし直す
+    // 同じ物理メモリを異なるメモリタイプでマップしないよう、ioremapは使わずに
+    // 既存のマッピングの属性を置き換える（ページング初期化後に呼ぶ）
+    pub fn remap_write_combining(&self) -> Result<()> {
+        let start = self.buf as u64;
+        let end = (start + self.size as u64 + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+        unsafe {
+            (*current_page_table()).create_mapping(
+                start,
+                end,
+                start,
+                CacheMode::WriteCombining.page_attr(),
+            )?;
+        }
+        flush_tlb();
+        Ok(())
+    }
 }
 
 impl crate::graphics::Bitmap for VramBufferInfo {
@@ -0,0 +1,292 @@
+extern crate alloc;
+
+use crate::frame::FRAME_ALLOCATOR;
+use crate::frame::FRAME_FLAG_PINNED;
+use crate::kaslr::virt_to_phys;
+use crate::pci::find_device;
+use crate::pci::BusDeviceFunction;
+use crate::pci::PCI_COMMAND_BUS_MASTER;
+use crate::pci::PCI_COMMAND_IO_SPACE;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::rdtsc;
+use crate::x86::read_io_port_u16;
+use crate::x86::read_io_port_u32;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u16;
+use crate::x86::write_io_port_u32;
+use crate::x86::write_io_port_u8;
+use crate::x86::PAGE_SIZE;
+use alloc::boxed::Box;
+use core::mem::size_of;
+use core::sync::atomic::fence;
+use core::sync::atomic::Ordering;
+
+// virtio-blkドライバ（レガシーインターフェース、I/Oポート経由）
+// 割り込みは使わず、1リクエストずつ完了をポーリングで待つ
+
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+// Transitional virtio-blk device, which has the legacy I/O BAR
+const VIRTIO_BLK_DEVICE_ID: u16 = 0x1001;
+const VIRTIO_SUBSYSTEM_BLK: u16 = 2;
+
+// Offsets of the legacy virtio header in BAR0
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+// Device specific config (without MSI-X): capacity in sectors
+const REG_BLK_CAPACITY: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 0x80;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Time to wait for the completion of a request in TSC cycles. The TSC
+// frequency is not known, so this is a few seconds on typical processors.
+const REQUEST_TIMEOUT_CYCLES: u64 = 10_000_000_000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct BlkRequest {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+    // Written by the device
+    status: u8,
+}
+
+pub struct VirtioBlk {
+    bdf: BusDeviceFunction,
+    io_base: u16,
+    queue_size: u16,
+    // Legacy layout: descriptor table, available ring, (padding), used ring
+    queue: *mut u8,
+    queue_pages: usize,
+    used_offset: usize,
+    next_avail_idx: u16,
+    last_used_idx: u16,
+    num_sectors: u64,
+    // Accessed by the device, so it should not move
+    request: Box<BlkRequest>,
+    // The device is reset on a timeout and no more requests are accepted
+    timed_out: bool,
+}
+
+impl VirtioBlk {
+    /// Finds a virtio-blk device on the PCI bus and initializes it.
+    pub fn probe() -> Option<Result<Self>> {
+        let bdf = find_device(|bdf| {
+            bdf.vendor_id() == VIRTIO_VENDOR_ID
+                && bdf.device_id() == VIRTIO_BLK_DEVICE_ID
+                && bdf.subsystem_id() == VIRTIO_SUBSYSTEM_BLK
+        })?;
+        Some(Self::new(bdf))
+    }
+    fn new(bdf: BusDeviceFunction) -> Result<Self> {
+        let bar0 = bdf.bar(0);
+        if bar0 & 1 == 0 {
+            return Err("virtio-blk: BAR0 is not an I/O space");
+        }
+        bdf.enable(PCI_COMMAND_IO_SPACE | PCI_COMMAND_BUS_MASTER);
+        let io_base = (bar0 & !3) as u16;
+        write_io_port_u8(io_base + REG_DEVICE_STATUS, 0);
+        write_io_port_u8(io_base + REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        write_io_port_u8(
+            io_base + REG_DEVICE_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER,
+        );
+        // No optional features are used
+        let _ = read_io_port_u32(io_base + REG_DEVICE_FEATURES);
+        write_io_port_u32(io_base + REG_GUEST_FEATURES, 0);
+
+        write_io_port_u16(io_base + REG_QUEUE_SELECT, 0);
+        let queue_size = read_io_port_u16(io_base + REG_QUEUE_SIZE);
+        if queue_size < 3 {
+            write_io_port_u8(io_base + REG_DEVICE_STATUS, STATUS_FAILED);
+            return Err("virtio-blk: queue is too small");
+        }
+        let n = queue_size as usize;
+        let avail_size = 2 * (3 + n);
+        let used_offset = (n * size_of::<VirtqDesc>() + avail_size).next_multiple_of(PAGE_SIZE);
+        let used_size = 2 * 3 + 8 * n;
+        let num_pages = (used_offset + used_size).div_ceil(PAGE_SIZE);
+        // The queue is accessed with physical addresses by the device, and
+        // with the same addresses by us thanks to the identity mapping.
+        let queue = FRAME_ALLOCATOR.alloc_contiguous(num_pages, 1)? as *mut u8;
+        for i in 0..num_pages {
+            FRAME_ALLOCATOR.set_frame_flags(
+                (queue as u64) + (i * PAGE_SIZE) as u64,
+                FRAME_FLAG_PINNED,
+                true,
+            );
+        }
+        unsafe { queue.write_bytes(0, num_pages * PAGE_SIZE) };
+        write_io_port_u32(
+            io_base + REG_QUEUE_ADDRESS,
+            (queue as u64 / PAGE_SIZE as u64) as u32,
+        );
+
+        let num_sectors = read_io_port_u32(io_base + REG_BLK_CAPACITY) as u64
+            | (read_io_port_u32(io_base + REG_BLK_CAPACITY + 4) as u64) << 32;
+        write_io_port_u8(
+            io_base + REG_DEVICE_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+        Ok(Self {
+            bdf,
+            io_base,
+            queue_size,
+            queue,
+            queue_pages: num_pages,
+            used_offset,
+            next_avail_idx: 0,
+            last_used_idx: 0,
+            num_sectors,
+            request: Box::new(BlkRequest {
+                kind: 0,
+                reserved: 0,
+                sector: 0,
+                status: 0xFF,
+            }),
+            timed_out: false,
+        })
+    }
+    pub fn bdf(&self) -> BusDeviceFunction {
+        self.bdf
+    }
+    pub fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+    /// Reads sectors into buf, whose length should be a multiple of
+    /// SECTOR_SIZE. buf should be physically contiguous and in the identity
+    /// mapping or the direct map (e.g. heap or a frame).
+    pub fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        self.request(VIRTIO_BLK_T_IN, sector, buf.as_mut_ptr(), buf.len())
+    }
+    /// Writes buf to sectors. The same restrictions as read() apply.
+    pub fn write(&mut self, sector: u64, buf: &[u8]) -> Result<()> {
+        self.request(VIRTIO_BLK_T_OUT, sector, buf.as_ptr() as *mut u8, buf.len())
+    }
+    fn desc(&self, index: usize) -> *mut VirtqDesc {
+        unsafe { (self.queue as *mut VirtqDesc).add(index) }
+    }
+    fn avail(&self) -> *mut u16 {
+        unsafe {
+            self.queue
+                .add(self.queue_size as usize * size_of::<VirtqDesc>()) as *mut u16
+        }
+    }
+    fn used(&self) -> *mut u16 {
+        unsafe { self.queue.add(self.used_offset) as *mut u16 }
+    }
+    // Stops the device from accessing the queue and the buffers
+    fn reset(&self) {
+        write_io_port_u8(self.io_base + REG_DEVICE_STATUS, 0);
+        let _ = read_io_port_u8(self.io_base + REG_DEVICE_STATUS);
+    }
+    fn request(&mut self, kind: u32, sector: u64, buf: *mut u8, len: usize) -> Result<()> {
+        if self.timed_out {
+            return Err("virtio-blk: device was reset after a timeout");
+        }
+        if len == 0 || len % SECTOR_SIZE != 0 || len > u32::MAX as usize {
+            return Err("virtio-blk: invalid buffer length");
+        }
+        if sector + (len / SECTOR_SIZE) as u64 > self.num_sectors {
+            return Err("virtio-blk: out of range");
+        }
+        self.request.kind = kind;
+        self.request.sector = sector;
+        self.request.status = 0xFF;
+        let data_flags = if kind == VIRTIO_BLK_T_IN {
+            VIRTQ_DESC_F_WRITE
+        } else {
+            0
+        };
+        let request = self.request.as_mut() as *mut BlkRequest;
+        // The request is on the heap, which is in the direct map
+        let descs = [
+            VirtqDesc {
+                addr: virt_to_phys(request as u64),
+                len: 16,
+                flags: VIRTQ_DESC_F_NEXT,
+                next: 1,
+            },
+            VirtqDesc {
+                addr: virt_to_phys(buf as u64),
+                len: len as u32,
+                flags: data_flags | VIRTQ_DESC_F_NEXT,
+                next: 2,
+            },
+            VirtqDesc {
+                addr: virt_to_phys(unsafe { core::ptr::addr_of_mut!((*request).status) } as u64),
+                len: 1,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            },
+        ];
+        unsafe {
+            for (i, desc) in descs.iter().enumerate() {
+                self.desc(i).write_volatile(*desc);
+            }
+            // avail: flags, idx, ring[queue_size]
+            let avail = self.avail();
+            let slot = self.next_avail_idx % self.queue_size;
+            avail.add(2 + slot as usize).write_volatile(0);
+            fence(Ordering::SeqCst);
+            self.next_avail_idx = self.next_avail_idx.wrapping_add(1);
+            avail.add(1).write_volatile(self.next_avail_idx);
+            fence(Ordering::SeqCst);
+            write_io_port_u16(self.io_base + REG_QUEUE_NOTIFY, 0);
+            // used: flags, idx, ring[queue_size]
+            let used = self.used();
+            let deadline = rdtsc().saturating_add(REQUEST_TIMEOUT_CYCLES);
+            while used.add(1).read_volatile() == self.last_used_idx {
+                if rdtsc() >= deadline {
+                    // The device could complete the request later and write
+                    // to buf after it is reused
+                    self.reset();
+                    self.timed_out = true;
+                    return Err("virtio-blk: timeout");
+                }
+                busy_loop_hint();
+            }
+            fence(Ordering::SeqCst);
+        }
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        let status = unsafe { core::ptr::addr_of!(self.request.status).read_volatile() };
+        if status == VIRTIO_BLK_S_OK {
+            Ok(())
+        } else {
+            Err("virtio-blk: request failed")
+        }
+    }
+}
+
+impl Drop for VirtioBlk {
+    fn drop(&mut self) {
+        self.reset();
+        FRAME_ALLOCATOR.free_contiguous(self.queue as u64, self.queue_pages);
+    }
+}
@@ -0,0 +1,116 @@
+extern crate alloc;
+
+use crate::address_space::flush_tlb_range_all_address_spaces;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::result::Result;
+use crate::x86::current_page_table;
+use crate::x86::PageAttr;
+use crate::x86::PAGE_SIZE;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+// vmalloc: 物理的に連続していないフレームを集めて、専用の仮想アドレス領域上で
+// 連続したバッファとしてマップする。各領域の前後にはガードページを置く
+pub const VMALLOC_START: u64 = 0xFFFF_D000_0000_0000;
+pub const VMALLOC_SIZE: u64 = 1 << 36;
+const GUARD_SIZE: u64 = PAGE_SIZE as u64;
+
+struct VmArea {
+    // Virtual address range including the guard pages on both sides
+    start: u64,
+    end: u64,
+    frames: Vec<u64>,
+}
+impl VmArea {
+    fn mapped_start(&self) -> u64 {
+        self.start + GUARD_SIZE
+    }
+    fn unmap(&self) {
+        let virt = self.mapped_start();
+        let end = virt + (self.frames.len() * PAGE_SIZE) as u64;
+        unsafe {
+            (*current_page_table())
+                .create_mapping(virt, end, 0, PageAttr::NotPresent)
+                .expect("Failed to unmap a vmalloc area");
+        }
+        // The frames can be reused only after no TLB entry points to them
+        flush_tlb_range_all_address_spaces(virt..end);
+        for phys in &self.frames {
+            FRAME_ALLOCATOR.free_frame(*phys);
+        }
+    }
+}
+
+struct VmallocRegion {
+    // Keyed by the address returned by vmalloc()
+    areas: RefCell<BTreeMap<u64, VmArea>>,
+}
+
+unsafe impl Sync for VmallocRegion {}
+
+static VMALLOC: VmallocRegion = VmallocRegion {
+    areas: RefCell::new(BTreeMap::new()),
+};
+
+// Finds the first gap in the region which can hold size bytes
+fn find_free_range(areas: &BTreeMap<u64, VmArea>, size: u64) -> Option<u64> {
+    let mut candidate = VMALLOC_START;
+    for area in areas.values() {
+        if area.start - candidate >= size {
+            return Some(candidate);
+        }
+        candidate = area.end;
+    }
+    (VMALLOC_START + VMALLOC_SIZE - candidate >= size).then_some(candidate)
+}
+
+/// Allocates a virtually contiguous buffer of size bytes backed by
+/// individually allocated (zero-cleared) frames. The buffer is surrounded by
+/// unmapped guard pages.
+pub fn vmalloc(size: usize) -> Result<*mut u8> {
+    if size == 0 {
+        return Err("vmalloc: size is zero");
+    }
+    let num_pages = size.div_ceil(PAGE_SIZE);
+    let mut areas = VMALLOC.areas.borrow_mut();
+    let area_size = num_pages as u64 * PAGE_SIZE as u64 + GUARD_SIZE * 2;
+    let start = find_free_range(&areas, area_size).ok_or("vmalloc: region exhausted")?;
+    let mut area = VmArea {
+        start,
+        end: start + area_size,
+        frames: Vec::with_capacity(num_pages),
+    };
+    let virt = area.mapped_start();
+    for i in 0..num_pages {
+        let page = virt + (i * PAGE_SIZE) as u64;
+        let result = FRAME_ALLOCATOR.alloc_frame().and_then(|phys| {
+            area.frames.push(phys);
+            unsafe {
+                (*current_page_table()).create_mapping(
+                    page,
+                    page + PAGE_SIZE as u64,
+                    phys,
+                    PageAttr::ReadWriteKernel,
+                )
+            }
+        });
+        if let Err(e) = result {
+            area.unmap();
+            return Err(e);
+        }
+    }
+    areas.insert(virt, area);
+    Ok(virt as *mut u8)
+}
+
+/// Frees a buffer allocated by vmalloc() and returns its frames.
+pub fn vfree(ptr: *mut u8) -> Result<()> {
+    let area = VMALLOC
+        .areas
+        .borrow_mut()
+        .remove(&(ptr as u64))
+        .ok_or("vfree: not allocated by vmalloc")?;
+    area.unmap();
+    Ok(())
+}
@@ -0,0 +1,181 @@
+extern crate alloc;
+
+use crate::address_space::AddressSpace;
+use crate::x86::current_page_table;
+use crate::x86::flush_tlb_page;
+use crate::x86::rdtsc;
+use crate::x86::PageMapper;
+use alloc::collections::BTreeMap;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+// ワーキングセットの推定
+// ページテーブルのアクセスビット（A）を定期的に読み取ってクリアし、
+// 前回のスキャン以降に触られたページと、一定期間内に使われたメモリ量を求める
+
+// Number of per-scan samples kept for working_set_history()
+const MAX_SAMPLES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PageAccess {
+    pub virt: u64,
+    pub size: u64,
+    /// The dirty bit is not cleared by the scanner
+    pub dirty: bool,
+}
+
+/// Result of a single scan
+pub struct ScanResult {
+    /// Pages accessed since the previous scan
+    pub touched: Vec<PageAccess>,
+    /// Number of present pages in the range
+    pub num_pages: usize,
+    /// Total size of present pages in the range
+    pub mapped_bytes: u64,
+}
+impl ScanResult {
+    pub fn touched_bytes(&self) -> u64 {
+        self.touched.iter().map(|p| p.size).sum()
+    }
+}
+impl fmt::Display for ScanResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} / {} pages touched ({} / {} KiB)",
+            self.touched.len(),
+            self.num_pages,
+            self.touched_bytes() / 1024,
+            self.mapped_bytes / 1024
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WorkingSetSample {
+    /// TSC value at the scan
+    pub tsc: u64,
+    pub touched_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PageState {
+    size: u64,
+    // Generation of the last scan which found the page accessed (0: never)
+    last_accessed: u64,
+}
+
+/// Tracks accesses to pages in a virtual address range across scans.
+pub struct WorkingSetScanner {
+    range: Range<u64>,
+    generation: u64,
+    pages: BTreeMap<u64, PageState>,
+    samples: VecDeque<WorkingSetSample>,
+}
+
+impl WorkingSetScanner {
+    pub fn new(range: Range<u64>) -> Self {
+        Self {
+            range,
+            generation: 0,
+            pages: BTreeMap::new(),
+            samples: VecDeque::new(),
+        }
+    }
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+    /// Number of scans done so far
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    /// Scans the page table and clears the accessed bits. flush_tlb_page is
+    /// called for each page whose accessed bit was cleared, after the walk.
+    pub fn scan(
+        &mut self,
+        table: &mut dyn PageMapper,
+        mut flush_tlb_page: impl FnMut(u64),
+    ) -> ScanResult {
+        self.generation += 1;
+        let generation = self.generation;
+        let mut pages = BTreeMap::new();
+        let mut touched = Vec::new();
+        let mut mapped_bytes = 0;
+        table.for_each_leaf(self.range.clone(), &mut |mut e| {
+            let virt = e.virt();
+            let size = e.page_size();
+            let mut state = self.pages.get(&virt).copied().unwrap_or(PageState {
+                size,
+                last_accessed: 0,
+            });
+            state.size = size;
+            if e.test_and_clear_accessed() {
+                state.last_accessed = generation;
+                touched.push(PageAccess {
+                    virt,
+                    size,
+                    dirty: e.is_dirty(),
+                });
+            }
+            mapped_bytes += size;
+            pages.insert(virt, state);
+        });
+        // Pages which are no longer mapped are forgotten here
+        self.pages = pages;
+        for p in &touched {
+            flush_tlb_page(p.virt);
+        }
+        let result = ScanResult {
+            num_pages: self.pages.len(),
+            mapped_bytes,
+            touched,
+        };
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(WorkingSetSample {
+            tsc: rdtsc(),
+            touched_bytes: result.touched_bytes(),
+        });
+        result
+    }
+    /// Scans the page table loaded in CR3.
+    pub fn scan_current(&mut self) -> ScanResult {
+        self.scan(unsafe { &mut *current_page_table() }, flush_tlb_page)
+    }
+    /// Scans the given address space, which does not have to be active.
+    pub fn scan_address_space(&mut self, space: &mut AddressSpace) -> ScanResult {
+        let mut flushed = Vec::new();
+        let result = self.scan(space.page_table_mut(), |virt| flushed.push(virt));
+        for virt in flushed {
+            space.flush_tlb_page(virt);
+        }
+        result
+    }
+    /// Size of the pages accessed within the last `window` scans.
+    pub fn working_set_size(&self, window: u64) -> u64 {
+        self.pages
+            .values()
+            .filter(|p| p.last_accessed != 0 && p.last_accessed + window > self.generation)
+            .map(|p| p.size)
+            .sum()
+    }
+    /// Bytes touched in each of the recent scans, oldest first.
+    pub fn working_set_history(&self) -> impl Iterator<Item = &WorkingSetSample> {
+        self.samples.iter()
+    }
+    /// Pages which have not been accessed within the last `min_idle_scans`
+    /// scans, oldest first. These are good candidates for eviction.
+    pub fn idle_pages(&self, min_idle_scans: u64) -> Vec<u64> {
+        let mut idle: Vec<(u64, u64)> = self
+            .pages
+            .iter()
+            .filter(|(_, p)| p.last_accessed + min_idle_scans <= self.generation)
+            .map(|(virt, p)| (p.last_accessed, *virt))
+            .collect();
+        idle.sort();
+        idle.into_iter().map(|(_, virt)| virt).collect()
+    }
+}
@@ -1,18 +1,38 @@
 extern crate alloc;
 
+use crate::address_space::flush_tlb_range_all_address_spaces;
 use crate::error;
+use crate::extable::search_exception_table;
+use crate::frame::FrameState;
+use crate::frame::FRAME_ALLOCATOR;
+use crate::frame::FRAME_FLAG_HUGE;
 use crate::info;
+use crate::kaslr::alloc_at_random_addr;
+use crate::kstack::alloc_kernel_stack;
+use crate::kstack::find_stack_by_guard_page;
+use crate::kstack::KERNEL_STACK_SIZE;
 use crate::result::Result;
+use crate::rmap::rmap_add;
+use crate::rmap::rmap_remove;
+use crate::thp::huge_page_mapped;
+use crate::thp::huge_page_unmapped;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::arch::asm;
 use core::arch::global_asm;
+use core::arch::x86_64::CpuidResult;
+use core::arch::x86_64::__cpuid_count;
+use core::cell::RefCell;
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem::offset_of;
 use core::mem::size_of;
 use core::mem::size_of_val;
 use core::mem::MaybeUninit;
-use core::pin::Pin;
+use core::ops::Range;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 
 pub fn hlt() {
     unsafe { asm!("hlt") }
@@ -40,8 +60,193 @@ pub fn write_io_port_u8(port: u16, data: u8) {
     }
 }
 
-pub fn read_cr3() -> *mut PML4 {
-    let mut cr3: *mut PML4;
+pub fn read_io_port_u16(port: u16) -> u16 {
+    let mut data: u16;
+    unsafe {
+        asm!("in ax, dx",
+            out("ax") data,
+            in("dx") port)
+    }
+    data
+}
+
+pub fn write_io_port_u16(port: u16, data: u16) {
+    unsafe {
+        asm!("out dx, ax",
+            in("ax") data,
+            in("dx") port)
+    }
+}
+
+pub fn read_io_port_u32(port: u16) -> u32 {
+    let mut data: u32;
+    unsafe {
+        asm!("in eax, dx",
+            out("eax") data,
+            in("dx") port)
+    }
+    data
+}
+
+pub fn write_io_port_u32(port: u16, data: u32) {
+    unsafe {
+        asm!("out dx, eax",
+            in("eax") data,
+            in("dx") port)
+    }
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    // SAFETY: CPUID is always available on x86_64
+    unsafe { __cpuid_count(leaf, subleaf) }
+}
+
+pub const MSR_IA32_APIC_BASE: u32 = 0x1B;
+pub const MSR_IA32_PAT: u32 = 0x277;
+pub const MSR_IA32_EFER: u32 = 0xC000_0080;
+pub const EFER_NXE: u64 = 1 << 11;
+
+pub fn read_msr(msr: u32) -> u64 {
+    let mut high: u32;
+    let mut low: u32;
+    unsafe {
+        asm!("rdmsr",
+            in("ecx") msr,
+            out("edx") high,
+            out("eax") low)
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// # Safety
+/// Writing to MSRs can change the behavior of the CPU in any way so it is
+/// programmer's responsibility to write a valid value to a valid MSR.
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!("wrmsr",
+            in("ecx") msr,
+            in("edx") (value >> 32) as u32,
+            in("eax") value as u32)
+}
+
+// CPUID.80000008H:EAX[7:0]
+pub fn max_phys_addr_bits() -> u32 {
+    cpuid(0x8000_0008, 0).eax & 0xFF
+}
+
+// CPUID.80000001H:EDX[26]
+pub fn is_1g_page_supported() -> bool {
+    cpuid(0x8000_0001, 0).edx & (1 << 26) != 0
+}
+
+pub fn is_nx_enabled() -> bool {
+    read_msr(MSR_IA32_EFER) & EFER_NXE != 0
+}
+
+pub fn rdtsc() -> u64 {
+    let mut high: u32;
+    let mut low: u32;
+    unsafe {
+        asm!("rdtsc",
+            out("edx") high,
+            out("eax") low)
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+// CPUID.01H:ECX[30]
+pub fn is_rdrand_supported() -> bool {
+    cpuid(1, 0).ecx & (1 << 30) != 0
+}
+
+// CPUID.(EAX=07H,ECX=0H):EBX[18]
+pub fn is_rdseed_supported() -> bool {
+    cpuid(7, 0).ebx & (1 << 18) != 0
+}
+
+// Both instructions may fail (CF=0) if the entropy is exhausted temporarily
+const RANDOM_RETRIES: usize = 16;
+
+/// Returns a random number from the hardware RNG, or None if RDRAND is not
+/// supported or keeps failing.
+pub fn rdrand64() -> Option<u64> {
+    if !is_rdrand_supported() {
+        return None;
+    }
+    (0..RANDOM_RETRIES).find_map(|_| {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand rax",
+                "setc cl",
+                out("rax") value,
+                out("cl") ok)
+        }
+        (ok != 0).then_some(value)
+    })
+}
+
+/// Same as rdrand64() but returns a seed directly from the entropy source.
+pub fn rdseed64() -> Option<u64> {
+    if !is_rdseed_supported() {
+        return None;
+    }
+    (0..RANDOM_RETRIES).find_map(|_| {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed rax",
+                "setc cl",
+                out("rax") value,
+                out("cl") ok)
+        }
+        (ok != 0).then_some(value)
+    })
+}
+
+// PAT memory types (SDM Vol.3: 12.12.2 IA32_PAT MSR)
+const PAT_TYPE_UC: u64 = 0x00;
+const PAT_TYPE_WC: u64 = 0x01;
+const PAT_TYPE_WT: u64 = 0x04;
+const PAT_TYPE_WB: u64 = 0x06;
+const PAT_TYPE_UC_MINUS: u64 = 0x07;
+// PAT entry selected by PWT=1, PCD=0 (WT by default) is replaced with WC
+const PAT_INDEX_WC: u64 = 1;
+const PAT_VALUE: u64 = PAT_TYPE_WB
+    | PAT_TYPE_WC << 8
+    | PAT_TYPE_UC_MINUS << 16
+    | PAT_TYPE_UC << 24
+    | PAT_TYPE_WB << 32
+    | PAT_TYPE_WT << 40
+    | PAT_TYPE_UC_MINUS << 48
+    | PAT_TYPE_UC << 56;
+
+// CPUID.01H:EDX[16]
+pub fn is_pat_supported() -> bool {
+    cpuid(1, 0).edx & (1 << 16) != 0
+}
+
+pub fn is_write_combining_enabled() -> bool {
+    is_pat_supported() && (read_msr(MSR_IA32_PAT) >> (PAT_INDEX_WC * 8)) & 0xFF == PAT_TYPE_WC
+}
+
+/// Programs IA32_PAT to have a write-combining entry which is selected by
+/// PageAttr::ReadWriteWriteCombining.
+pub fn init_pat() {
+    if !is_pat_supported() {
+        return;
+    }
+    // SDM Vol.3: 12.12.4 Programming the PAT
+    // Caches should be flushed before changing the memory types, and TLBs
+    // should be flushed after that.
+    unsafe {
+        asm!("wbinvd");
+        write_msr(MSR_IA32_PAT, PAT_VALUE);
+    }
+    flush_tlb();
+}
+
+fn read_cr3_value() -> u64 {
+    let mut cr3: u64;
     unsafe {
         asm!("mov rax, cr3",
             out("rax") cr3)
@@ -49,20 +254,369 @@ pub fn read_cr3() -> *mut PML4 {
     cr3
 }
 
+/// Returns the root page table. Note that it is actually a PML5 if
+/// is_la57_enabled() is true. Use current_page_table() to handle both.
+pub fn read_cr3() -> *mut PML4 {
+    // Lower 12 bits hold the PCID (or PWT/PCD) and not a part of the address
+    (read_cr3_value() & !ATTR_MASK) as *mut PML4
+}
+
+pub fn read_cr0() -> u64 {
+    let mut cr0: u64;
+    unsafe {
+        asm!("mov rax, cr0",
+            out("rax") cr0)
+    }
+    cr0
+}
+
+pub fn read_rsp() -> u64 {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp",
+            out(reg) rsp)
+    }
+    rsp
+}
+
+// Supervisor writes to read-only pages fault if set
+pub const CR0_WP: u64 = 1 << 16;
+
+pub fn read_cr4() -> u64 {
+    let mut cr4: u64;
+    unsafe {
+        asm!("mov rax, cr4",
+            out("rax") cr4)
+    }
+    cr4
+}
+
+/// # Safety
+/// Changing CR4 can change the behavior of the CPU in any way so it is
+/// programmer's responsibility to write a valid value.
+pub unsafe fn write_cr4(cr4: u64) {
+    asm!("mov cr4, rax",
+            in("rax") cr4)
+}
+
+pub const CR4_PGE: u64 = 1 << 7;
+pub const CR4_UMIP: u64 = 1 << 11;
+pub const CR4_LA57: u64 = 1 << 12;
+pub const CR4_PCIDE: u64 = 1 << 17;
+pub const CR4_SMEP: u64 = 1 << 20;
+pub const CR4_SMAP: u64 = 1 << 21;
+pub const PCID_MASK: u64 = 0xFFF;
+// Setting this bit on writing CR3 prevents flushing TLB entries of the PCID
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+// CPUID.(EAX=07H,ECX=0H):EBX[7]
+pub fn is_smep_supported() -> bool {
+    cpuid(7, 0).ebx & (1 << 7) != 0
+}
+
+// CPUID.(EAX=07H,ECX=0H):EBX[20]
+pub fn is_smap_supported() -> bool {
+    cpuid(7, 0).ebx & (1 << 20) != 0
+}
+
+// CPUID.(EAX=07H,ECX=0H):ECX[2]
+pub fn is_umip_supported() -> bool {
+    cpuid(7, 0).ecx & (1 << 2) != 0
+}
+
+pub fn is_smep_enabled() -> bool {
+    read_cr4() & CR4_SMEP != 0
+}
+
+pub fn is_smap_enabled() -> bool {
+    read_cr4() & CR4_SMAP != 0
+}
+
+pub fn is_umip_enabled() -> bool {
+    read_cr4() & CR4_UMIP != 0
+}
+
+fn enable_cr4_feature(supported: bool, bit: u64) -> bool {
+    if supported && read_cr4() & bit == 0 {
+        unsafe { write_cr4(read_cr4() | bit) }
+    }
+    supported
+}
+
+/// Prevents the kernel from executing user pages if supported.
+pub fn enable_smep() -> bool {
+    enable_cr4_feature(is_smep_supported(), CR4_SMEP)
+}
+
+/// Prevents the kernel from accessing user pages outside of stac() / clac()
+/// if supported.
+pub fn enable_smap() -> bool {
+    enable_cr4_feature(is_smap_supported(), CR4_SMAP)
+}
+
+/// Makes SGDT, SIDT, SLDT, SMSW and STR fault in user mode if supported.
+pub fn enable_umip() -> bool {
+    enable_cr4_feature(is_umip_supported(), CR4_UMIP)
+}
+
+/// Sets EFLAGS.AC to allow the kernel to access user pages under SMAP. Does
+/// nothing if SMAP is not enabled.
+pub fn stac() {
+    if is_smap_enabled() {
+        unsafe { asm!("stac") }
+    }
+}
+
+/// Clears EFLAGS.AC set by stac().
+pub fn clac() {
+    if is_smap_enabled() {
+        unsafe { asm!("clac") }
+    }
+}
+
+// CPUID.(EAX=07H,ECX=0H):ECX[16]
+pub fn is_la57_supported() -> bool {
+    cpuid(7, 0).ecx & (1 << 16) != 0
+}
+
+// CR4.LA57 can only be changed while paging is disabled, so the kernel
+// switches to it through a trampoline in la57::switch_to_la57().
+pub fn is_la57_enabled() -> bool {
+    read_cr4() & CR4_LA57 != 0
+}
+
+// CPUID.01H:ECX[17]
+pub fn is_pcid_supported() -> bool {
+    cpuid(1, 0).ecx & (1 << 17) != 0
+}
+
+// CPUID.(EAX=07H,ECX=0H):EBX[10]
+pub fn is_invpcid_supported() -> bool {
+    cpuid(7, 0).ebx & (1 << 10) != 0
+}
+
+pub fn is_pcid_enabled() -> bool {
+    read_cr4() & CR4_PCIDE != 0
+}
+
+/// Enables PCID if both PCID and INVPCID are supported. Should be called
+/// while CR3[11:0] is zero (i.e. the current PCID is 0).
+pub fn enable_pcid() -> bool {
+    if !is_pcid_supported() || !is_invpcid_supported() {
+        return false;
+    }
+    if !is_pcid_enabled() {
+        assert_eq!(read_cr3_value() & PCID_MASK, 0);
+        unsafe { write_cr4(read_cr4() | CR4_PCIDE) }
+    }
+    true
+}
+
+pub fn current_pcid() -> u16 {
+    if is_pcid_enabled() {
+        (read_cr3_value() & PCID_MASK) as u16
+    } else {
+        0
+    }
+}
+
+#[repr(u64)]
+enum InvpcidType {
+    IndividualAddress = 0,
+    SingleContext = 1,
+    AllContextIncludingGlobal = 2,
+}
+
+fn invpcid(kind: InvpcidType, pcid: u16, addr: u64) {
+    let desc: [u64; 2] = [pcid as u64, addr];
+    unsafe {
+        asm!("invpcid rax, [rcx]",
+            in("rax") kind as u64,
+            in("rcx") &desc)
+    }
+}
+
+/// Flushes the TLB entry for virt that is tagged with pcid. Unlike invlpg,
+/// this works even if pcid is not the current one.
+pub fn flush_tlb_page_for_pcid(pcid: u16, virt: u64) {
+    if is_pcid_enabled() {
+        invpcid(InvpcidType::IndividualAddress, pcid, virt)
+    } else {
+        flush_tlb_page(virt)
+    }
+}
+
+/// Flushes all TLB entries tagged with pcid (except global ones).
+pub fn flush_tlb_for_pcid(pcid: u16) {
+    if is_pcid_enabled() {
+        invpcid(InvpcidType::SingleContext, pcid, 0)
+    } else {
+        flush_tlb()
+    }
+}
+
+/// Flushes all TLB entries of all PCIDs including global ones.
+pub fn flush_tlb_all() {
+    if is_pcid_enabled() {
+        invpcid(InvpcidType::AllContextIncludingGlobal, 0, 0)
+    } else {
+        let cr4 = read_cr4();
+        // Toggling CR4.PGE flushes global entries as well
+        unsafe {
+            write_cr4(cr4 ^ CR4_PGE);
+            write_cr4(cr4);
+        }
+    }
+}
+
 pub const PAGE_SIZE: usize = 4096;
+pub const HUGE_PAGE_SIZE: u64 = 1 << 21;
+const PML4_ENTRY_SIZE: u64 = 1 << 39;
 const ATTR_MASK: u64 = 0xFFF;
-const ATTR_PRESENT: u64 = 1 << 0;
-const ATTR_WRITABLE: u64 = 1 << 1;
-const ATTR_WRITE_THROUGH: u64 = 1 << 3;
-const ATTR_CACHE_DISABLE: u64 = 1 << 4;
+pub const ATTR_PRESENT: u64 = 1 << 0;
+pub const ATTR_WRITABLE: u64 = 1 << 1;
+pub const ATTR_USER: u64 = 1 << 2;
+pub const ATTR_WRITE_THROUGH: u64 = 1 << 3;
+pub const ATTR_CACHE_DISABLE: u64 = 1 << 4;
+// Set by the processor on any access / write through the entry
+pub const ATTR_ACCESSED: u64 = 1 << 5;
+pub const ATTR_DIRTY: u64 = 1 << 6;
+pub const ATTR_PAGE_SIZE: u64 = 1 << 7;
+// PAT bit is bit 7 in 4KiB page entries, and bit 12 in large page entries
+pub const ATTR_PAT_4K: u64 = 1 << 7;
+pub const ATTR_PAT_LARGE: u64 = 1 << 12;
+pub const ATTR_NO_EXECUTE: u64 = 1 << 63;
+// Bits 51:12 of an entry holds the physical address
+pub const ENTRY_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// Bits 63:47 (4-level paging) or 63:56 (5-level paging) of a virtual address
+// should be the same
+pub const VIRT_ADDR_BITS: u32 = 48;
+pub const VIRT_ADDR_BITS_LA57: u32 = 57;
+pub fn virt_addr_bits() -> u32 {
+    if is_la57_enabled() {
+        VIRT_ADDR_BITS_LA57
+    } else {
+        VIRT_ADDR_BITS
+    }
+}
+pub fn canonicalize_addr(addr: u64) -> u64 {
+    canonicalize_addr_with_bits(addr, virt_addr_bits())
+}
+pub fn is_canonical_addr(addr: u64) -> bool {
+    canonicalize_addr(addr) == addr
+}
+/// Same as canonicalize_addr() but for the given width of virtual addresses
+/// instead of the current paging mode, e.g. for a table not loaded yet.
+pub fn canonicalize_addr_with_bits(addr: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((addr << shift) as i64) >> shift) as u64
+}
+fn is_canonical_range(start: u64, end: u64, bits: u32) -> bool {
+    let last = end.wrapping_sub(1);
+    canonicalize_addr_with_bits(start, bits) == start
+        && canonicalize_addr_with_bits(last, bits) == last
+}
 
 #[derive(Debug, Copy, Clone)]
 #[repr(u64)]
 pub enum PageAttr {
     NotPresent = 0,
+    ReadOnlyKernel = ATTR_PRESENT,
     ReadWriteKernel = ATTR_PRESENT | ATTR_WRITABLE,
     ReadWriteIo = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE,
+    // Selects the PAT entry which is programmed as WC by init_pat()
+    ReadWriteWriteCombining = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH,
+}
+/// Access permissions given to PML4::protect(). Present pages are always
+/// readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    pub writable: bool,
+    /// Ignored if IA32_EFER.NXE is not set
+    pub executable: bool,
+    pub user: bool,
+}
+impl Protection {
+    pub const READ_ONLY: Self = Self {
+        writable: false,
+        executable: false,
+        user: false,
+    };
+    pub const READ_WRITE: Self = Self {
+        writable: true,
+        executable: false,
+        user: false,
+    };
+    pub const READ_EXECUTE: Self = Self {
+        writable: false,
+        executable: true,
+        user: false,
+    };
+    // Returns the value of a leaf entry with the permissions replaced
+    fn apply(&self, value: u64, nx_enabled: bool) -> u64 {
+        let mut value = value & !(ATTR_WRITABLE | ATTR_USER | ATTR_NO_EXECUTE);
+        if self.writable {
+            value |= ATTR_WRITABLE;
+        }
+        if self.user {
+            value |= ATTR_USER;
+        }
+        if !self.executable && nx_enabled {
+            value |= ATTR_NO_EXECUTE;
+        }
+        value
+    }
+}
+
+/// Virtual address range whose page table entries have been changed. The
+/// TLB entries for it should be flushed before relying on the new entries.
+#[must_use = "The TLB entries for the range should be flushed"]
+#[derive(Debug)]
+pub struct TlbFlush {
+    range: Range<u64>,
 }
+impl TlbFlush {
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+    /// Flushes the TLB entries for the range in all the address spaces.
+    pub fn flush(self) {
+        flush_tlb_range_all_address_spaces(self.range);
+    }
+    /// Drops the token without flushing, e.g. when the page table has never
+    /// been loaded.
+    pub fn ignore(self) {}
+}
+
+fn is_user_accessible_entry(value: u64, write: bool) -> bool {
+    value & ATTR_PRESENT != 0 && value & ATTR_USER != 0 && (!write || value & ATTR_WRITABLE != 0)
+}
+
+// Fails if any page in range is not present or is mapped by a 1GiB page,
+// which protect() does not split.
+fn check_protectable(table: &dyn PageMapper, range: &Range<u64>, bits: u32) -> Result<()> {
+    if range.start % PAGE_SIZE as u64 != 0 || range.end % PAGE_SIZE as u64 != 0 {
+        return Err("Range is not aligned to the page size");
+    }
+    if !is_canonical_range(range.start, range.end, bits) {
+        return Err("Non-canonical address");
+    }
+    let mut addr = range.start;
+    while addr < range.end {
+        let size = match table.translate(addr) {
+            Ok(TranslationResult::PageMapped4K { .. }) => PAGE_SIZE as u64,
+            Ok(TranslationResult::PageMapped2M { .. }) => HUGE_PAGE_SIZE,
+            Ok(TranslationResult::PageMapped1G { .. }) => return Err("Mapped by a 1GiB page"),
+            Err(_) => return Err("Range is not fully mapped"),
+        };
+        addr = (addr & !(size - 1)) + size;
+    }
+    Ok(())
+}
+
+/// Result of PML4::translate(). phys is the physical address that
+/// corresponds to the given virtual address (not the base of the page).
 #[derive(Debug, Eq, PartialEq)]
 pub enum TranslationResult {
     PageMapped4K { phys: u64 },
@@ -79,14 +633,38 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
     fn read_value(&self) -> u64 {
         self.value
     }
-    fn is_present(&self) -> bool {
-        (self.read_value() & (1 << 0)) != 0
+    pub fn value(&self) -> u64 {
+        self.read_value()
+    }
+    pub fn is_present(&self) -> bool {
+        (self.read_value() & ATTR_PRESENT) != 0
+    }
+    pub fn is_writable(&self) -> bool {
+        (self.read_value() & ATTR_WRITABLE) != 0
+    }
+    pub fn is_user(&self) -> bool {
+        (self.read_value() & ATTR_USER) != 0
+    }
+    pub fn is_no_execute(&self) -> bool {
+        (self.read_value() & ATTR_NO_EXECUTE) != 0
     }
-    fn is_writable(&self) -> bool {
-        (self.read_value() & (1 << 1)) != 0
+    pub fn is_cache_disabled(&self) -> bool {
+        (self.read_value() & ATTR_CACHE_DISABLE) != 0
     }
-    fn is_user(&self) -> bool {
-        (self.read_value() & (1 << 2)) != 0
+    pub fn is_accessed(&self) -> bool {
+        (self.read_value() & ATTR_ACCESSED) != 0
+    }
+    /// Only meaningful for leaf entries.
+    pub fn is_dirty(&self) -> bool {
+        (self.read_value() & ATTR_DIRTY) != 0
+    }
+    /// Returns true if this entry maps a page directly (i.e. it does not
+    /// point to the next level table). Always true for PT entries.
+    pub fn is_leaf(&self) -> bool {
+        LEVEL == 1 || (LEVEL <= 3 && (self.read_value() & ATTR_PAGE_SIZE) != 0)
+    }
+    pub fn addr(&self) -> u64 {
+        self.read_value() & ENTRY_ADDR_MASK
     }
     fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -101,16 +679,16 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
         )?;
         write!(f, " }}")
     }
-    fn table(&self) -> Result<&NEXT> {
+    pub fn table(&self) -> Result<&NEXT> {
         if self.is_present() {
-            Ok(unsafe { &*((self.value & !ATTR_MASK) as *const NEXT) })
+            Ok(unsafe { &*(self.addr() as *const NEXT) })
         } else {
             Err("Page Not Found")
         }
     }
     fn table_mut(&mut self) -> Result<&mut NEXT> {
         if self.is_present() {
-            Ok(unsafe { &mut *((self.value & !ATTR_MASK) as *mut NEXT) })
+            Ok(unsafe { &mut *(self.addr() as *mut NEXT) })
         } else {
             Err("Page Not Found")
         }
@@ -128,7 +706,9 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
             Err("Page is already populated")
         } else {
             let next: Box<NEXT> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
-            self.value = Box::into_raw(next) as u64 | PageAttr::ReadWriteKernel as u64;
+            let next = Box::into_raw(next) as u64;
+            FRAME_ALLOCATOR.set_frame_state(next, FrameState::PageTable);
+            self.value = next | PageAttr::ReadWriteKernel as u64;
             Ok(self)
         }
     }
@@ -152,6 +732,61 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Debug for Entry<LEVEL, S
     }
 }
 
+impl PDEntry {
+    // Replaces the 2MiB page mapping with a PT which maps the same range with
+    // 4KiB pages. virt is an address in the page.
+    fn split(&mut self, root: u64, virt: u64) -> Result<()> {
+        let value = self.value;
+        let base = virt & !(HUGE_PAGE_SIZE - 1);
+        let phys = value & ENTRY_ADDR_MASK & !(HUGE_PAGE_SIZE - 1);
+        let mut attr = value & (ATTR_MASK | ATTR_NO_EXECUTE) & !ATTR_PAGE_SIZE;
+        if value & ATTR_PAT_LARGE != 0 {
+            attr |= ATTR_PAT_4K;
+        }
+        // This is safe since entries filled with 0 is valid.
+        let mut pt: Box<PT> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
+        for (i, pte) in pt.entry.iter_mut().enumerate() {
+            let page = phys + (i * PAGE_SIZE) as u64;
+            pte.value = page | attr;
+            FRAME_ALLOCATOR.dec_map_count(page);
+            FRAME_ALLOCATOR.set_frame_flags(page, FRAME_FLAG_HUGE, false);
+            account_pte_change(root, base + (i * PAGE_SIZE) as u64, 0, pte.value);
+        }
+        let pt = Box::into_raw(pt) as u64;
+        FRAME_ALLOCATOR.set_frame_state(pt, FrameState::PageTable);
+        self.value = pt | PageAttr::ReadWriteKernel as u64 | (value & ATTR_USER);
+        huge_page_unmapped(root);
+        Ok(())
+    }
+    // Replaces the PT with a 2MiB page mapping of phys. attr is in the 4KiB
+    // page entry format. The PT is freed.
+    fn collapse(&mut self, root: u64, virt: u64, phys: u64, attr: u64) -> Result<()> {
+        if phys & (HUGE_PAGE_SIZE - 1) != 0 {
+            return Err("Phys is not aligned to 2MiB");
+        }
+        let base = virt & !(HUGE_PAGE_SIZE - 1);
+        let pt = self.table_mut()? as *mut PT;
+        for (i, pte) in unsafe { &*pt }.entry.iter().enumerate() {
+            account_pte_change(root, base + (i * PAGE_SIZE) as u64, pte.value, 0);
+        }
+        let mut value =
+            phys | (attr & (ATTR_MASK | ATTR_NO_EXECUTE) & !ATTR_PAT_4K) | ATTR_PAGE_SIZE;
+        if attr & ATTR_PAT_4K != 0 {
+            value |= ATTR_PAT_LARGE;
+        }
+        self.value = value;
+        for i in 0..512 {
+            let page = phys + (i * PAGE_SIZE) as u64;
+            FRAME_ALLOCATOR.inc_map_count(page);
+            FRAME_ALLOCATOR.set_frame_flags(page, FRAME_FLAG_HUGE, true);
+        }
+        FRAME_ALLOCATOR.set_frame_state(pt as u64, FrameState::Heap);
+        drop(unsafe { Box::from_raw(pt) });
+        huge_page_mapped(root);
+        Ok(())
+    }
+}
+
 #[repr(align(4096))]
 pub struct Table<const LEVEL: usize, const SHIFT: usize, NEXT> {
     entry: [Entry<LEVEL, SHIFT, NEXT>; 512],
@@ -164,41 +799,586 @@ impl<const LEVEL: usize, const SHIFT: usize, NEXT: core::fmt::Debug> Table<LEVEL
             if !e.is_present() {
                 continue;
             }
-            writeln!(f, "  entry[{:3}] = {:?}", i, e)?;
+            writeln!(f, "  entry[{:3}] = {:?}", i, e)?;
+        }
+        writeln!(f, "}}")
+    }
+    pub fn entries(&self) -> &[Entry<LEVEL, SHIFT, NEXT>; 512] {
+        &self.entry
+    }
+    pub fn next_level(&self, index: usize) -> Option<&NEXT> {
+        self.entry.get(index).and_then(|e| e.table().ok())
+    }
+    fn calc_index(&self, addr: u64) -> usize {
+        ((addr >> SHIFT) & 0b1_1111_1111) as usize
+    }
+    /// Creates a new table which shares all the lower level tables with self.
+    pub fn clone_top_level(&self) -> Box<Self> {
+        // This is safe since entries filled with 0 is valid.
+        let mut table: Box<Self> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
+        for (dst, src) in table.entry.iter_mut().zip(self.entry.iter()) {
+            dst.value = src.value;
+        }
+        FRAME_ALLOCATOR
+            .set_frame_state(table.as_ref() as *const Self as u64, FrameState::PageTable);
+        table
+    }
+    // Makes the entries self[dst..dst + n] the same as src[0..n]. src may
+    // be self.
+    fn alias_entries(&mut self, src: *const Self, n: usize, dst: usize) -> Result<()> {
+        if dst + n > self.entry.len() {
+            return Err("Alias is out of the table");
+        }
+        for i in 0..n {
+            self.entry[dst + i].value = unsafe { (*src).entry[i].value };
+        }
+        Ok(())
+    }
+    /// Allocates the lower level tables for the empty entries in indices.
+    /// Tables cloned by clone_top_level() afterwards share them, so the
+    /// mappings created under them later are visible from all the clones.
+    pub fn populate_entries(&mut self, indices: Range<usize>) -> Result<()> {
+        for e in &mut self.entry[indices] {
+            e.ensure_populated()?;
+        }
+        Ok(())
+    }
+    // Returns true if any present entry has the user bit
+    fn has_user_entries(&self) -> bool {
+        self.entry
+            .iter()
+            .any(|e| e.value & (ATTR_PRESENT | ATTR_USER) == ATTR_PRESENT | ATTR_USER)
+    }
+    /// Clears the entries in indices without freeing the lower level tables,
+    /// e.g. the ones shared with the table cloned by clone_top_level().
+    pub fn clear_entries(&mut self, indices: Range<usize>) {
+        for e in &mut self.entry[indices] {
+            e.value = 0;
+        }
+    }
+    /// Makes the entries in indices the same as the ones in src, so that
+    /// they share the lower level tables.
+    pub fn share_entries(&mut self, src: &Self, indices: Range<usize>) {
+        for i in indices {
+            self.entry[i].value = src.entry[i].value;
+        }
+    }
+}
+
+// Hand-built tables for the host tests. They live in the host heap, which
+// the tables treat as physical memory as the kernel does with the identity
+// mapping.
+#[cfg(test)]
+impl<const LEVEL: usize, const SHIFT: usize, NEXT> Table<LEVEL, SHIFT, NEXT> {
+    pub(crate) fn new_for_test() -> Box<Self> {
+        // This is safe since entries filled with 0 is valid.
+        Box::new(unsafe { MaybeUninit::zeroed().assume_init() })
+    }
+    pub(crate) fn set_entry_for_test(&mut self, index: usize, value: u64) {
+        self.entry[index].value = value;
+    }
+    pub(crate) fn phys_for_test(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+// Frees a lower level table allocated by Entry::populate()
+fn free_table<T>(table: &mut T) {
+    let table = table as *mut T;
+    FRAME_ALLOCATOR.set_frame_state(table as u64, FrameState::Heap);
+    drop(unsafe { Box::from_raw(table) });
+}
+
+impl<const LEVEL: usize, const SHIFT: usize, NEXT: fmt::Debug> fmt::Debug
+    for Table<LEVEL, SHIFT, NEXT>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.format(f)
+    }
+}
+
+pub type PT = Table<1, 12, [u8; PAGE_SIZE]>;
+type PTEntry = Entry<1, 12, [u8; PAGE_SIZE]>;
+pub type PD = Table<2, 21, PT>;
+type PDEntry = Entry<2, 21, PT>;
+pub type PDPT = Table<3, 30, PD>;
+pub type PML4 = Table<4, 39, PDPT>;
+pub type PML5 = Table<5, 48, PML4>;
+
+/// A present leaf entry visited by PageMapper::for_each_leaf()
+pub struct LeafEntry<'a> {
+    virt: u64,
+    level: usize,
+    value: &'a mut u64,
+}
+impl LeafEntry<'_> {
+    // The processor may set A/D bits concurrently, so updates should be atomic
+    fn atomic_value(&self) -> &AtomicU64 {
+        unsafe { AtomicU64::from_ptr(self.value as *const u64 as *mut u64) }
+    }
+    /// Virtual address of the start of the page
+    pub fn virt(&self) -> u64 {
+        self.virt
+    }
+    /// 1: 4KiB page, 2: 2MiB page, 3: 1GiB page
+    pub fn level(&self) -> usize {
+        self.level
+    }
+    pub fn page_size(&self) -> u64 {
+        1 << (12 + 9 * (self.level - 1))
+    }
+    pub fn value(&self) -> u64 {
+        self.atomic_value().load(Ordering::Relaxed)
+    }
+    pub fn phys(&self) -> u64 {
+        self.value() & ENTRY_ADDR_MASK & !(self.page_size() - 1)
+    }
+    pub fn is_writable(&self) -> bool {
+        self.value() & ATTR_WRITABLE != 0
+    }
+    pub fn is_accessed(&self) -> bool {
+        self.value() & ATTR_ACCESSED != 0
+    }
+    pub fn is_dirty(&self) -> bool {
+        self.value() & ATTR_DIRTY != 0
+    }
+    /// Clears the accessed bit and returns its previous state. The caller
+    /// should flush the TLB entry for virt() if this returns true, otherwise
+    /// further accesses may not set the bit again.
+    pub fn test_and_clear_accessed(&mut self) -> bool {
+        self.atomic_value()
+            .fetch_and(!ATTR_ACCESSED, Ordering::SeqCst)
+            & ATTR_ACCESSED
+            != 0
+    }
+    /// Same as test_and_clear_accessed() but for the dirty bit.
+    pub fn test_and_clear_dirty(&mut self) -> bool {
+        self.atomic_value().fetch_and(!ATTR_DIRTY, Ordering::SeqCst) & ATTR_DIRTY != 0
+    }
+}
+
+trait LeafWalker {
+    // Visits present leaves which map a part of [first, last]. bits is the
+    // width of virtual addresses of the root table.
+    fn walk_leaves(&mut self, first: u64, last: u64, bits: u32, f: &mut dyn FnMut(LeafEntry));
+}
+impl LeafWalker for PT {
+    fn walk_leaves(&mut self, first: u64, last: u64, _bits: u32, f: &mut dyn FnMut(LeafEntry)) {
+        let mut addr = first & !ATTR_MASK;
+        loop {
+            let e = &mut self.entry[self.calc_index(addr)];
+            if e.is_present() {
+                f(LeafEntry {
+                    virt: addr,
+                    level: 1,
+                    value: &mut e.value,
+                });
+            }
+            if addr >= (last & !ATTR_MASK) {
+                break;
+            }
+            addr += PAGE_SIZE as u64;
+        }
+    }
+}
+impl<const LEVEL: usize, const SHIFT: usize, NEXT: LeafWalker + fmt::Debug> LeafWalker
+    for Table<LEVEL, SHIFT, NEXT>
+{
+    fn walk_leaves(&mut self, first: u64, last: u64, bits: u32, f: &mut dyn FnMut(LeafEntry)) {
+        let mask = (1u64 << SHIFT) - 1;
+        let mut addr = first;
+        loop {
+            let last_in_entry = core::cmp::min(addr | mask, last);
+            let e = &mut self.entry[self.calc_index(addr)];
+            if e.is_present() {
+                if e.is_leaf() {
+                    f(LeafEntry {
+                        virt: addr & !mask,
+                        level: LEVEL,
+                        value: &mut e.value,
+                    });
+                } else if let Ok(next) = e.table_mut() {
+                    next.walk_leaves(addr, last_in_entry, bits, f);
+                }
+            }
+            if last_in_entry >= last {
+                break;
+            }
+            // Skips the non-canonical hole after the lower half
+            addr = canonicalize_addr_with_bits(last_in_entry + 1, bits);
+        }
+    }
+}
+
+// Updates the map counts and the reverse mappings of the frames when the 4KiB
+// page entry for virt in the table rooted at root is changed from old to new.
+// The identity mapping is not counted.
+fn account_pte_change(root: u64, virt: u64, old: u64, new: u64) {
+    let mapped = |value: u64| value & ATTR_PRESENT != 0 && value & ENTRY_ADDR_MASK != virt;
+    if (old ^ new) & ENTRY_ADDR_MASK == 0 && mapped(old) == mapped(new) {
+        return;
+    }
+    if mapped(old) {
+        FRAME_ALLOCATOR.dec_map_count(old & ENTRY_ADDR_MASK);
+        rmap_remove(old & ENTRY_ADDR_MASK, root, virt);
+    }
+    if mapped(new) {
+        FRAME_ALLOCATOR.inc_map_count(new & ENTRY_ADDR_MASK);
+        rmap_add(new & ENTRY_ADDR_MASK, root, virt);
+    }
+}
+
+/// Operations on a root page table, which work on both 4-level and 5-level
+/// paging.
+pub trait PageMapper {
+    fn create_mapping(
+        &mut self,
+        virt_start: u64,
+        virt_end: u64,
+        phys: u64,
+        attr: PageAttr,
+    ) -> Result<()>;
+    fn translate(&self, virt: u64) -> Result<TranslationResult>;
+    /// Calls f for each present leaf entry which maps a part of range.
+    fn for_each_leaf(&mut self, range: Range<u64>, f: &mut dyn FnMut(LeafEntry));
+    /// Returns the raw value of the 4KiB page entry for virt, which may be
+    /// non-present. Fails if the page tables for virt are not populated.
+    fn read_pte(&self, virt: u64) -> Result<u64>;
+    /// Overwrites the raw value of the 4KiB page entry for virt. The caller
+    /// is responsible for flushing the TLB.
+    fn write_pte(&mut self, virt: u64, value: u64) -> Result<()>;
+    /// Returns the raw value of the page directory entry for virt.
+    fn read_pde(&self, virt: u64) -> Result<u64>;
+    /// Maps the 2MiB-aligned range containing virt with a 2MiB page of phys
+    /// instead of a PT, which is freed. attr is in the 4KiB page entry
+    /// format. The caller is responsible for flushing the TLB.
+    fn collapse_huge_page(&mut self, virt: u64, phys: u64, attr: u64) -> Result<()>;
+    /// Splits the 2MiB page which maps virt into 4KiB pages. Returns false if
+    /// virt is not mapped by a 2MiB page.
+    fn split_huge_page(&mut self, virt: u64) -> Result<bool>;
+    /// Changes the permissions of the present pages in range without
+    /// changing the frames. 2MiB pages are split if they are partially in
+    /// range. Nothing is changed if a part of range is not mapped.
+    fn protect(&mut self, range: Range<u64>, prot: Protection) -> Result<TlbFlush>;
+    /// Maps [dst, dst + size) to the same frames as the identity mapping of
+    /// [0, size), sharing the tables. dst should be aligned to 512GiB.
+    fn alias_identity_map(&mut self, dst: u64, size: u64) -> Result<()>;
+    /// Returns true if the paging structures allow a user mode access (a
+    /// write if write is true) to virt.
+    fn is_user_accessible(&self, virt: u64, write: bool) -> bool;
+    /// Populates all the top level entries for the kernel half (the higher
+    /// half of the canonical addresses).
+    fn populate_kernel_half(&mut self) -> Result<()>;
+}
+
+/// Top level entries which map the kernel half in both PML4 and PML5
+pub const KERNEL_HALF_INDICES: Range<usize> = 256..512;
+
+/// Returns the root table loaded in CR3 as a PML4 or PML5 depending on the
+/// paging mode.
+pub fn current_page_table() -> *mut dyn PageMapper {
+    page_table_at(read_cr3() as u64)
+}
+
+/// Returns the root table at the physical address root (e.g. a value of CR3)
+/// as a PML4 or PML5 depending on the paging mode.
+pub fn page_table_at(root: u64) -> *mut dyn PageMapper {
+    if is_la57_enabled() {
+        root as *mut PML5
+    } else {
+        root as *mut PML4
+    }
+}
+
+impl PML5 {
+    pub fn new() -> Box<Self> {
+        // This is safe since entries filled with 0 is valid.
+        let table: Box<Self> = Box::new(unsafe { MaybeUninit::zeroed().assume_init() });
+        FRAME_ALLOCATOR
+            .set_frame_state(table.as_ref() as *const Self as u64, FrameState::PageTable);
+        table
+    }
+    /// Replaces the PML4 of the entry at index with a copy made by
+    /// clone_top_level() and returns it, so that its entries can be changed
+    /// without affecting the other tables sharing the original PML4.
+    pub fn clone_pml4(&mut self, index: usize) -> Result<&mut PML4> {
+        let e = &mut self.entry[index];
+        let pml4 = Box::into_raw(e.table()?.clone_top_level());
+        e.value = pml4 as u64 | (e.value & !ENTRY_ADDR_MASK);
+        e.table_mut()
+    }
+    pub fn pml4_mut(&mut self, index: usize) -> Result<&mut PML4> {
+        self.entry[index].table_mut()
+    }
+    /// Same as PML4::free_lower_tables(). The PML4s are freed as well.
+    pub fn free_lower_tables(&mut self, indices: Range<usize>) {
+        for e in &mut self.entry[indices] {
+            let Ok(pml4) = e.table_mut() else {
+                continue;
+            };
+            pml4.free_lower_tables(0..512);
+            free_table(pml4);
+            e.value = 0;
+        }
+    }
+    // Same as PML4::trim_user_walk() for the PML5 entry at index
+    fn trim_user_entry(&mut self, index: usize) {
+        let e = &mut self.entry[index];
+        if e.value & ATTR_USER != 0 && e.table().is_ok_and(|pml4| !pml4.has_user_entries()) {
+            e.value &= !ATTR_USER;
+        }
+    }
+}
+
+// The levels of a root table above the PML4, which are none if the root is
+// a PML4. PageMapper is implemented on top of this for both paging modes.
+trait RootTable: LeafWalker {
+    // Width of the virtual addresses translated by the table
+    const ADDR_BITS: u32;
+    // Returns the PML4 which translates virt
+    fn pml4_for(&self, virt: u64) -> Result<&PML4>;
+    fn pml4_for_mut(&mut self, virt: u64) -> Result<&mut PML4>;
+    // Calls f for each part of range translated by a single PML4. Missing
+    // PML4s are populated if populate is true. The entries pointing to the
+    // PML4s get the user bit if user is true, and lose it if no user entries
+    // are left below them.
+    fn for_each_pml4(
+        &mut self,
+        range: Range<u64>,
+        populate: bool,
+        user: bool,
+        f: &mut dyn FnMut(&mut PML4, Range<u64>) -> Result<()>,
+    ) -> Result<()>;
+    // Returns false if an entry above the PML4 denies the user access to virt
+    fn allows_user_access(&self, virt: u64, write: bool) -> bool;
+    fn alias_identity_map_root(&mut self, dst: u64, size: u64) -> Result<()>;
+}
+
+impl RootTable for PML4 {
+    const ADDR_BITS: u32 = VIRT_ADDR_BITS;
+    fn pml4_for(&self, _virt: u64) -> Result<&PML4> {
+        Ok(self)
+    }
+    fn pml4_for_mut(&mut self, _virt: u64) -> Result<&mut PML4> {
+        Ok(self)
+    }
+    fn for_each_pml4(
+        &mut self,
+        range: Range<u64>,
+        _populate: bool,
+        _user: bool,
+        f: &mut dyn FnMut(&mut PML4, Range<u64>) -> Result<()>,
+    ) -> Result<()> {
+        f(self, range)
+    }
+    fn allows_user_access(&self, _virt: u64, _write: bool) -> bool {
+        true
+    }
+    fn alias_identity_map_root(&mut self, dst: u64, size: u64) -> Result<()> {
+        self.alias_identity_map(dst, size)
+    }
+}
+
+impl RootTable for PML5 {
+    const ADDR_BITS: u32 = VIRT_ADDR_BITS_LA57;
+    fn pml4_for(&self, virt: u64) -> Result<&PML4> {
+        self.entry[self.calc_index(virt)].table()
+    }
+    fn pml4_for_mut(&mut self, virt: u64) -> Result<&mut PML4> {
+        let index = self.calc_index(virt);
+        self.entry[index].table_mut()
+    }
+    fn for_each_pml4(
+        &mut self,
+        range: Range<u64>,
+        populate: bool,
+        user: bool,
+        f: &mut dyn FnMut(&mut PML4, Range<u64>) -> Result<()>,
+    ) -> Result<()> {
+        let mut addr = range.start;
+        while addr < range.end {
+            let index = self.calc_index(addr);
+            // Split the range at the boundary of PML5 entries (256 TiB)
+            let next = (addr | ((1 << 48) - 1)).wrapping_add(1);
+            let end = if next == 0 || next > range.end {
+                range.end
+            } else {
+                next
+            };
+            let e = &mut self.entry[index];
+            if populate {
+                e.ensure_populated()?;
+            }
+            if user && e.is_present() {
+                e.value |= ATTR_USER;
+            }
+            f(e.table_mut()?, addr..end)?;
+            self.trim_user_entry(index);
+            if next == 0 {
+                break;
+            }
+            addr = end;
         }
-        writeln!(f, "}}")
+        Ok(())
     }
-    pub fn next_level(&self, index: usize) -> Option<&NEXT> {
-        self.entry.get(index).and_then(|e| e.table().ok())
+    fn allows_user_access(&self, virt: u64, write: bool) -> bool {
+        is_user_accessible_entry(self.entry[self.calc_index(virt)].value(), write)
     }
-    fn calc_index(&self, addr: u64) -> usize {
-        ((addr >> SHIFT) & 0b1_1111_1111) as usize
+    // The identity mapping is under the PML5 entry 0
+    fn alias_identity_map_root(&mut self, dst: u64, size: u64) -> Result<()> {
+        let index = self.calc_index(dst);
+        if index == 0 {
+            return self.entry[0].table_mut()?.alias_identity_map(dst, size);
+        }
+        if dst % PML4_ENTRY_SIZE != 0 {
+            return Err("Alias is not aligned to 512GiB");
+        }
+        let src = self.entry[0].table()? as *const PML4;
+        let n = size.div_ceil(PML4_ENTRY_SIZE) as usize;
+        let pml4 = self.entry[index].ensure_populated()?.table_mut()?;
+        let index = pml4.calc_index(dst);
+        pml4.alias_entries(src, n, index)
     }
 }
 
-impl<const LEVEL: usize, const SHIFT: usize, NEXT: fmt::Debug> fmt::Debug
+impl<const LEVEL: usize, const SHIFT: usize, NEXT: fmt::Debug> PageMapper
     for Table<LEVEL, SHIFT, NEXT>
+where
+    Self: RootTable,
 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.format(f)
+    fn create_mapping(
+        &mut self,
+        virt_start: u64,
+        virt_end: u64,
+        phys: u64,
+        attr: PageAttr,
+    ) -> Result<()> {
+        // The table may be built before LA57 is enabled
+        if !is_canonical_range(virt_start, virt_end, Self::ADDR_BITS) {
+            return Err("Non-canonical address");
+        }
+        let root = self as *const Self as u64;
+        self.for_each_pml4(virt_start..virt_end, true, false, &mut |pml4, range| {
+            let phys = phys + (range.start - virt_start);
+            pml4.create_mapping_in(root, range.start, range.end, phys, attr)
+        })
+    }
+    fn translate(&self, virt: u64) -> Result<TranslationResult> {
+        self.pml4_for(virt)?.translate(virt)
+    }
+    fn for_each_leaf(&mut self, range: Range<u64>, f: &mut dyn FnMut(LeafEntry)) {
+        if !range.is_empty() {
+            self.walk_leaves(range.start, range.end - 1, Self::ADDR_BITS, f)
+        }
+    }
+    fn read_pte(&self, virt: u64) -> Result<u64> {
+        Ok(self.pml4_for(virt)?.pte(virt)?.value)
+    }
+    fn write_pte(&mut self, virt: u64, value: u64) -> Result<()> {
+        let root = self as *const Self as u64;
+        let pte = self.pml4_for_mut(virt)?.pte_mut(virt)?;
+        account_pte_change(root, virt, pte.value, value);
+        pte.value = value;
+        Ok(())
+    }
+    fn read_pde(&self, virt: u64) -> Result<u64> {
+        Ok(self.pml4_for(virt)?.pde(virt)?.value)
+    }
+    fn collapse_huge_page(&mut self, virt: u64, phys: u64, attr: u64) -> Result<()> {
+        let root = self as *const Self as u64;
+        let pde = self.pml4_for_mut(virt)?.pde_mut(virt)?;
+        if !pde.is_present() || pde.is_leaf() {
+            return Err("Not mapped by a PT");
+        }
+        pde.collapse(root, virt, phys, attr)
+    }
+    fn split_huge_page(&mut self, virt: u64) -> Result<bool> {
+        let root = self as *const Self as u64;
+        let pde = self.pml4_for_mut(virt)?.pde_mut(virt)?;
+        if !pde.is_present() || !pde.is_leaf() {
+            return Ok(false);
+        }
+        pde.split(root, virt)?;
+        Ok(true)
+    }
+    fn protect(&mut self, range: Range<u64>, prot: Protection) -> Result<TlbFlush> {
+        check_protectable(self, &range, Self::ADDR_BITS)?;
+        let root = self as *const Self as u64;
+        let nx_enabled = is_nx_enabled();
+        self.for_each_pml4(range.clone(), false, prot.user, &mut |pml4, range| {
+            pml4.protect_in(root, range, prot, nx_enabled)
+        })?;
+        Ok(TlbFlush { range })
+    }
+    fn alias_identity_map(&mut self, dst: u64, size: u64) -> Result<()> {
+        self.alias_identity_map_root(dst, size)
+    }
+    fn is_user_accessible(&self, virt: u64, write: bool) -> bool {
+        self.allows_user_access(virt, write)
+            && self
+                .pml4_for(virt)
+                .is_ok_and(|pml4| pml4.is_user_accessible(virt, write))
+    }
+    fn populate_kernel_half(&mut self) -> Result<()> {
+        self.populate_entries(KERNEL_HALF_INDICES)
     }
 }
 
-pub type PT = Table<1, 12, [u8; PAGE_SIZE]>;
-pub type PD = Table<2, 21, PT>;
-pub type PDPT = Table<3, 30, PD>;
-pub type PML4 = Table<4, 39, PDPT>;
-
 impl PML4 {
     pub fn new() -> Box<Self> {
-        Box::new(Self::default())
+        let table = Box::new(Self::default());
+        FRAME_ALLOCATOR
+            .set_frame_state(table.as_ref() as *const Self as u64, FrameState::PageTable);
+        table
     }
     fn default() -> Self {
         // This is safe since entries filled with 0 is valid.
         unsafe { MaybeUninit::zeroed().assume_init() }
     }
-    pub fn create_mapping(
+    /// Maps [dst, dst + size) to the same physical range as the identity
+    /// mapping [0, size) by sharing its lower level tables. dst should be
+    /// aligned to 512GiB, the range covered by a PML4 entry.
+    pub fn alias_identity_map(&mut self, dst: u64, size: u64) -> Result<()> {
+        if dst % PML4_ENTRY_SIZE != 0 {
+            return Err("Alias is not aligned to 512GiB");
+        }
+        let n = size.div_ceil(PML4_ENTRY_SIZE) as usize;
+        let index = self.calc_index(dst);
+        if index < n {
+            return Err("Alias overlaps with the identity mapping");
+        }
+        let src = self as *const Self;
+        self.alias_entries(src, n, index)
+    }
+    /// Frees the lower level tables reached from the entries in indices, and
+    /// clears the entries. The tables should not be shared with other root
+    /// tables. The mapped frames are not freed.
+    pub fn free_lower_tables(&mut self, indices: Range<usize>) {
+        for e in &mut self.entry[indices] {
+            let Ok(pdpt) = e.table_mut() else {
+                continue;
+            };
+            for e in pdpt.entry.iter_mut().filter(|e| !e.is_leaf()) {
+                let Ok(pd) = e.table_mut() else {
+                    continue;
+                };
+                for e in pd.entry.iter_mut().filter(|e| !e.is_leaf()) {
+                    if let Ok(pt) = e.table_mut() {
+                        free_table(pt);
+                    }
+                }
+                free_table(pd);
+            }
+            free_table(pdpt);
+            e.value = 0;
+        }
+    }
+    // root is the table loaded into CR3, which is a PML5 under 5-level paging.
+    // The range should be canonical and should not cross the entries of the
+    // root.
+    fn create_mapping_in(
         &mut self,
+        root: u64,
         virt_start: u64,
         virt_end: u64,
         phys: u64,
@@ -214,12 +1394,19 @@ impl PML4 {
                 let table = table.entry[index].ensure_populated()?.table_mut()?;
                 loop {
                     let index = table.calc_index(addr);
-                    let table = table.entry[index].ensure_populated()?.table_mut()?;
+                    let pde = &mut table.entry[index];
+                    if pde.is_present() && pde.is_leaf() {
+                        // Only a part of the 2MiB page may be changed
+                        pde.split(root, addr)?;
+                    }
+                    let table = pde.ensure_populated()?.table_mut()?;
                     loop {
                         let index = table.calc_index(addr);
                         let pte = &mut table.entry[index];
                         let phys_addr = phys + addr - virt_start;
+                        let old = pte.value;
                         pte.set_page(phys_addr, attr)?;
+                        account_pte_change(root, addr, old, pte.value);
                         addr = addr.wrapping_add(PAGE_SIZE as u64);
                         if index + 1 >= (1 << 9) || addr >= virt_end {
                             break;
@@ -237,8 +1424,196 @@ impl PML4 {
                 break;
             }
         }
+        // The new entries are not user pages
+        table.trim_user_walk(virt_start, virt_end);
+        Ok(())
+    }
+    // range should be checked by check_protectable()
+    fn protect_in(
+        &mut self,
+        root: u64,
+        range: Range<u64>,
+        prot: Protection,
+        nx_enabled: bool,
+    ) -> Result<()> {
+        let mut addr = range.start;
+        while addr < range.end {
+            let pde = self.pde_mut(addr)?;
+            if pde.is_leaf() {
+                let base = addr & !(HUGE_PAGE_SIZE - 1);
+                if base >= range.start && base + HUGE_PAGE_SIZE <= range.end {
+                    pde.value = prot.apply(pde.value, nx_enabled);
+                    if prot.user {
+                        self.allow_user_walk(addr)?;
+                    }
+                    addr = base + HUGE_PAGE_SIZE;
+                    continue;
+                }
+                pde.split(root, addr)?;
+            }
+            // After the split, so that the PDE pointing to the new PT is marked
+            if prot.user {
+                self.allow_user_walk(addr)?;
+            }
+            let pte = self.pte_mut(addr)?;
+            pte.value = prot.apply(pte.value, nx_enabled);
+            addr += PAGE_SIZE as u64;
+        }
+        if !prot.user {
+            self.trim_user_walk(range.start, range.end);
+        }
+        Ok(())
+    }
+    // The processor allows a user mode access only if all the paging
+    // structure entries for it have the user bit, so the non-leaf entries
+    // are marked as well. The leaf entries still decide the permissions.
+    fn allow_user_walk(&mut self, virt: u64) -> Result<()> {
+        let index = self.calc_index(virt);
+        let pml4e = &mut self.entry[index];
+        pml4e.value |= ATTR_USER;
+        let pdpt = pml4e.table_mut()?;
+        let index = pdpt.calc_index(virt);
+        pdpt.entry[index].value |= ATTR_USER;
+        let pde = self.pde_mut(virt)?;
+        if !pde.is_leaf() {
+            pde.value |= ATTR_USER;
+        }
         Ok(())
     }
+    // Clears the user bits set by allow_user_walk() on the non-leaf entries
+    // for [start, end) which have no user entries below anymore, so that the
+    // pages mapped there later are not reachable from user mode by accident.
+    fn trim_user_walk(&mut self, start: u64, end: u64) {
+        let mut addr = start & !(HUGE_PAGE_SIZE - 1);
+        while addr < end {
+            self.trim_user_walk_at(addr);
+            let Some(next) = addr.checked_add(HUGE_PAGE_SIZE) else {
+                break;
+            };
+            addr = next;
+        }
+    }
+    fn trim_user_walk_at(&mut self, virt: u64) {
+        let index = self.calc_index(virt);
+        if self.entry[index].value & ATTR_USER == 0 {
+            return;
+        }
+        let Ok(pdpt) = self.entry[index].table_mut() else {
+            return;
+        };
+        let pdpte = &mut pdpt.entry[pdpt.calc_index(virt)];
+        if pdpte.value & ATTR_USER != 0 && !pdpte.is_leaf() {
+            if let Ok(pd) = pdpte.table_mut() {
+                let pde = &mut pd.entry[pd.calc_index(virt)];
+                if pde.value & ATTR_USER != 0
+                    && !pde.is_leaf()
+                    && pde.table().is_ok_and(|pt| !pt.has_user_entries())
+                {
+                    pde.value &= !ATTR_USER;
+                }
+                if !pd.has_user_entries() {
+                    pdpte.value &= !ATTR_USER;
+                }
+            }
+        }
+        if !pdpt.has_user_entries() {
+            self.entry[index].value &= !ATTR_USER;
+        }
+    }
+    pub fn is_user_accessible(&self, virt: u64, write: bool) -> bool {
+        let pml4e = &self.entry[self.calc_index(virt)];
+        if !is_user_accessible_entry(pml4e.value(), write) {
+            return false;
+        }
+        let Ok(pdpt) = pml4e.table() else {
+            return false;
+        };
+        let pdpte = &pdpt.entry[pdpt.calc_index(virt)];
+        if !is_user_accessible_entry(pdpte.value(), write) {
+            return false;
+        }
+        if pdpte.is_leaf() {
+            return true;
+        }
+        let Ok(pd) = pdpte.table() else {
+            return false;
+        };
+        let pde = &pd.entry[pd.calc_index(virt)];
+        if !is_user_accessible_entry(pde.value(), write) {
+            return false;
+        }
+        if pde.is_leaf() {
+            return true;
+        }
+        let Ok(pt) = pde.table() else {
+            return false;
+        };
+        is_user_accessible_entry(pt.entry[pt.calc_index(virt)].value(), write)
+    }
+    fn pde(&self, virt: u64) -> Result<&PDEntry> {
+        let pdpt = self.entry[self.calc_index(virt)].table()?;
+        let pdpte = &pdpt.entry[pdpt.calc_index(virt)];
+        if pdpte.is_leaf() {
+            return Err("Mapped by a 1GiB page");
+        }
+        let pd = pdpte.table()?;
+        Ok(&pd.entry[pd.calc_index(virt)])
+    }
+    fn pde_mut(&mut self, virt: u64) -> Result<&mut PDEntry> {
+        let index = self.calc_index(virt);
+        let pdpt = self.entry[index].table_mut()?;
+        let index = pdpt.calc_index(virt);
+        let pdpte = &mut pdpt.entry[index];
+        if pdpte.is_leaf() {
+            return Err("Mapped by a 1GiB page");
+        }
+        let pd = pdpte.table_mut()?;
+        let index = pd.calc_index(virt);
+        Ok(&mut pd.entry[index])
+    }
+    fn pte(&self, virt: u64) -> Result<&PTEntry> {
+        let pde = self.pde(virt)?;
+        if pde.is_leaf() {
+            return Err("Mapped by a 2MiB page");
+        }
+        let pt = pde.table()?;
+        Ok(&pt.entry[pt.calc_index(virt)])
+    }
+    fn pte_mut(&mut self, virt: u64) -> Result<&mut PTEntry> {
+        let pde = self.pde_mut(virt)?;
+        if pde.is_leaf() {
+            return Err("Mapped by a 2MiB page");
+        }
+        let pt = pde.table_mut()?;
+        let index = pt.calc_index(virt);
+        Ok(&mut pt.entry[index])
+    }
+    pub fn translate(&self, virt: u64) -> Result<TranslationResult> {
+        let pml4e = &self.entry[self.calc_index(virt)];
+        let pdpt = pml4e.table()?;
+        let pdpte = &pdpt.entry[pdpt.calc_index(virt)];
+        if pdpte.is_present() && pdpte.is_leaf() {
+            return Ok(TranslationResult::PageMapped1G {
+                phys: (pdpte.addr() & !((1 << 30) - 1)) | (virt & ((1 << 30) - 1)),
+            });
+        }
+        let pd = pdpte.table()?;
+        let pde = &pd.entry[pd.calc_index(virt)];
+        if pde.is_present() && pde.is_leaf() {
+            return Ok(TranslationResult::PageMapped2M {
+                phys: (pde.addr() & !((1 << 21) - 1)) | (virt & ((1 << 21) - 1)),
+            });
+        }
+        let pt = pde.table()?;
+        let pte = &pt.entry[pt.calc_index(virt)];
+        if pte.is_present() {
+            Ok(TranslationResult::PageMapped4K {
+                phys: pte.addr() | (virt & ATTR_MASK),
+            })
+        } else {
+            Err("Page Not Found")
+        }
+    }
 }
 
 /// # Safety
@@ -318,15 +1693,16 @@ struct GeneralRegisterContext {
     rcx: u64,
 }
 const _: () = assert!(size_of::<GeneralRegisterContext>() == (16 - 1) * 8);
-#[allow(dead_code)]
+/// Stack frame pushed by the processor on an interrupt. Changes to this are
+/// reflected on return from the handler.
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
-struct InterruptContext {
-    rip: u64,
-    cs: u64,
-    rflags: u64,
-    rsp: u64,
-    ss: u64,
+pub struct InterruptContext {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
 const _: () = assert!(size_of::<InterruptContext>() == 8 * 5);
 #[allow(dead_code)]
@@ -442,6 +1818,7 @@ macro_rules! interrupt_entrypoint_with_ecode {
     };
 }
 
+interrupt_entrypoint!(1);
 interrupt_entrypoint!(3);
 interrupt_entrypoint!(6);
 interrupt_entrypoint_with_ecode!(8);
@@ -450,6 +1827,8 @@ interrupt_entrypoint_with_ecode!(14);
 interrupt_entrypoint!(32);
 
 extern "sysv64" {
+    fn inthandler_common();
+    fn interrupt_entrypoint1();
     fn interrupt_entrypoint3();
     fn interrupt_entrypoint6();
     fn interrupt_entrypoint8();
@@ -462,6 +1841,24 @@ global_asm!(
     r#"
 .global inthandler_common
 inthandler_common:
+    // Switch to the kernel page table if KPTI is enabled and interrupted in
+    // user mode. [rsp + 24] is the saved CS.
+    test qword ptr [rsp + 24], 3
+    jz 2f
+    push rax
+    mov rax, [rip + KPTI_CR3]
+    test rax, rax
+    jz 3f
+    mov cr3, rax
+3:
+    pop rax
+2:
+    // Clear EFLAGS.AC so that the handler does not run with the user access
+    // allowed by stac(). clac is not used since it is #UD without SMAP. iretq
+    // restores the interrupted value.
+    pushfq
+    and qword ptr [rsp], -0x40001 // ~(1 << 18)
+    popfq
     // General purpose registers (except rsp and rcx)
     push r15
     push r14
@@ -512,6 +1909,17 @@ inthandler_common:
     //
     pop rcx
     add rsp, 8 // for Error Code
+    // Switch back to the user page table if returning to user mode
+    test qword ptr [rsp + 8], 3
+    jz 4f
+    push rax
+    mov rax, [rip + KPTI_CR3 + 8]
+    test rax, rax
+    jz 5f
+    mov cr3, rax
+5:
+    pop rax
+4:
     iretq
 "#
 );
@@ -525,11 +1933,451 @@ pub fn read_cr2() -> u64 {
     cr2
 }
 
+// Bits of the error code of #PF
+pub const PF_ERROR_PRESENT: u64 = 1 << 0;
+pub const PF_ERROR_WRITE: u64 = 1 << 1;
+pub const PF_ERROR_USER: u64 = 1 << 2;
+pub const PF_ERROR_RESERVED: u64 = 1 << 3;
+pub const PF_ERROR_FETCH: u64 = 1 << 4;
+
+/// Describes the error code of #PF, e.g. "A supervisor mode data read on a
+/// present page, page structures are valid"
+pub struct PageFaultError(pub u64);
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let e = self.0;
+        write!(
+            f,
+            "A {} mode {} on a {} page, page structures are {}",
+            if e & PF_ERROR_USER != 0 {
+                "user"
+            } else {
+                "supervisor"
+            },
+            if e & PF_ERROR_FETCH != 0 {
+                "instruction fetch"
+            } else if e & PF_ERROR_WRITE != 0 {
+                "data write"
+            } else {
+                "data read"
+            },
+            if e & PF_ERROR_PRESENT != 0 {
+                "present"
+            } else {
+                "non-present"
+            },
+            if e & PF_ERROR_RESERVED != 0 {
+                "invalid"
+            } else {
+                "valid"
+            },
+        )
+    }
+}
+
+pub const RFLAGS_TF: u64 = 1 << 8;
+
+/// An exception passed to the handlers registered by
+/// register_exception_handler()
+pub struct ExceptionInfo<'a> {
+    pub vector: usize,
+    pub error_code: u64,
+    /// CR2 for #PF, 0 otherwise
+    pub fault_addr: u64,
+    pub ctx: &'a mut InterruptContext,
+}
+
+/// Returns true if the exception is resolved and the execution can continue
+/// with the (possibly modified) context.
+pub type ExceptionHandler = fn(&mut ExceptionInfo) -> bool;
+const MAX_EXCEPTION_HANDLERS: usize = 16;
+
+struct ExceptionHandlers {
+    handlers: RefCell<[Option<(usize, ExceptionHandler)>; MAX_EXCEPTION_HANDLERS]>,
+}
+unsafe impl Sync for ExceptionHandlers {}
+static EXCEPTION_HANDLERS: ExceptionHandlers = ExceptionHandlers {
+    handlers: RefCell::new([None; MAX_EXCEPTION_HANDLERS]),
+};
+
+/// Registers a handler which is called on the exception of the vector before
+/// it is treated as fatal. Handlers are tried in the registration order.
+/// Only #DB (1) and #PF (14) are supported for now.
+pub fn register_exception_handler(vector: usize, handler: ExceptionHandler) -> Result<()> {
+    if vector != 1 && vector != 14 {
+        return Err("Unsupported exception vector");
+    }
+    let mut handlers = EXCEPTION_HANDLERS.handlers.borrow_mut();
+    let slot = handlers
+        .iter_mut()
+        .find(|h| h.is_none())
+        .ok_or("Too many exception handlers")?;
+    *slot = Some((vector, handler));
+    Ok(())
+}
+
+fn try_exception_handlers(info: &mut InterruptInfo, vector: usize) -> bool {
+    // Copy the list so that handlers can fault (and register) recursively
+    let Ok(handlers) = EXCEPTION_HANDLERS.handlers.try_borrow().map(|h| *h) else {
+        return false;
+    };
+    let mut e = ExceptionInfo {
+        vector,
+        error_code: info.error_code,
+        fault_addr: if vector == 14 { read_cr2() } else { 0 },
+        ctx: &mut info.ctx,
+    };
+    handlers
+        .iter()
+        .flatten()
+        .filter(|(v, _)| *v == vector)
+        .any(|(_, h)| h(&mut e))
+}
+
+// State of an access done by probe_*(), shared with the #PF handler
+const PROBE_INACTIVE: u64 = 0;
+const PROBE_DATA: u64 = 1;
+const PROBE_FETCH: u64 = 2;
+const PROBE_STACK: u64 = 3;
+#[repr(C)]
+struct ProbeContext {
+    mode: AtomicU64,
+    // Where to continue after a faulting data access or a stack overflow
+    resume_rip: AtomicU64,
+    faulted: AtomicU64,
+    // Error code of #PF, or the address in the guard page for PROBE_STACK
+    error_code: AtomicU64,
+    resume_rsp: AtomicU64,
+}
+static PROBE_CONTEXT: ProbeContext = ProbeContext {
+    mode: AtomicU64::new(PROBE_INACTIVE),
+    resume_rip: AtomicU64::new(0),
+    faulted: AtomicU64::new(0),
+    error_code: AtomicU64::new(0),
+    resume_rsp: AtomicU64::new(0),
+};
+
+fn catch_probe_fault(info: &mut InterruptInfo) -> bool {
+    match PROBE_CONTEXT.mode.load(Ordering::SeqCst) {
+        PROBE_DATA => {
+            info.ctx.rip = PROBE_CONTEXT.resume_rip.load(Ordering::SeqCst);
+        }
+        PROBE_FETCH => {
+            // The fault happened at the call target, so emulate a ret
+            let rsp = info.ctx.rsp;
+            info.ctx.rip = unsafe { *(rsp as *const u64) };
+            info.ctx.rsp = rsp + 8;
+        }
+        _ => return false,
+    }
+    PROBE_CONTEXT.mode.store(PROBE_INACTIVE, Ordering::SeqCst);
+    PROBE_CONTEXT.faulted.store(1, Ordering::SeqCst);
+    PROBE_CONTEXT
+        .error_code
+        .store(info.error_code, Ordering::SeqCst);
+    true
+}
+
+// Returns the name of the guarded stack overflowed and the address in its
+// guard page, if the double fault was caused by a stack overflow. A push onto
+// a guard page causes a page fault which cannot be delivered on the same
+// stack, resulting in a double fault.
+fn find_overflowed_stack(info: &InterruptInfo) -> Option<(&'static str, u64)> {
+    [info.ctx.rsp, read_cr2()]
+        .into_iter()
+        .find_map(|addr| find_stack_by_guard_page(addr).map(|name| (name, addr)))
+}
+
+fn catch_stack_overflow(info: &mut InterruptInfo, guard_addr: u64) -> bool {
+    if PROBE_CONTEXT.mode.load(Ordering::SeqCst) != PROBE_STACK {
+        return false;
+    }
+    info.ctx.rip = PROBE_CONTEXT.resume_rip.load(Ordering::SeqCst);
+    info.ctx.rsp = PROBE_CONTEXT.resume_rsp.load(Ordering::SeqCst);
+    PROBE_CONTEXT.mode.store(PROBE_INACTIVE, Ordering::SeqCst);
+    PROBE_CONTEXT.faulted.store(1, Ordering::SeqCst);
+    PROBE_CONTEXT.error_code.store(guard_addr, Ordering::SeqCst);
+    true
+}
+
+fn probe_result() -> core::result::Result<(), u64> {
+    if PROBE_CONTEXT.faulted.swap(0, Ordering::SeqCst) != 0 {
+        Err(PROBE_CONTEXT.error_code.load(Ordering::SeqCst))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads a byte at addr and returns the error code of #PF if it faulted.
+/// Other page fault handlers are not invoked for the access.
+pub fn probe_read(addr: u64) -> core::result::Result<(), u64> {
+    unsafe {
+        asm!(
+            "lea {tmp}, [rip + 2f]",
+            "mov [{ctx} + 8], {tmp}",
+            "mov qword ptr [{ctx}], {mode}",
+            "mov {tmp:l}, byte ptr [{addr}]",
+            "2:",
+            "mov qword ptr [{ctx}], 0",
+            ctx = in(reg) &PROBE_CONTEXT,
+            addr = in(reg) addr,
+            mode = in(reg) PROBE_DATA,
+            tmp = out(reg) _,
+        )
+    }
+    probe_result()
+}
+
+/// Does a read-modify-write on a byte at addr without changing its value,
+/// and returns the error code of #PF if it faulted.
+pub fn probe_write(addr: u64) -> core::result::Result<(), u64> {
+    unsafe {
+        asm!(
+            "lea {tmp}, [rip + 2f]",
+            "mov [{ctx} + 8], {tmp}",
+            "mov qword ptr [{ctx}], {mode}",
+            "lock or byte ptr [{addr}], 0",
+            "2:",
+            "mov qword ptr [{ctx}], 0",
+            ctx = in(reg) &PROBE_CONTEXT,
+            addr = in(reg) addr,
+            mode = in(reg) PROBE_DATA,
+            tmp = out(reg) _,
+        )
+    }
+    probe_result()
+}
+
+/// Switches to the stack whose top is stack_top and keeps pushing onto it.
+/// Returns the faulting address in the guard page if the double fault
+/// handler detected the overflow, which is the only way to come back.
+///
+/// # Safety
+/// stack_top should be the top of a stack allocated by alloc_kernel_stack().
+pub unsafe fn probe_stack_overflow(stack_top: u64) -> core::result::Result<(), u64> {
+    asm!(
+        "lea {tmp}, [rip + 3f]",
+        "mov [{ctx} + 8], {tmp}",
+        "mov [{ctx} + 32], rsp",
+        "mov qword ptr [{ctx}], {mode}",
+        "mov rsp, {top}",
+        "2:",
+        "push {tmp}",
+        "jmp 2b",
+        "3:",
+        "mov qword ptr [{ctx}], 0",
+        ctx = in(reg) &PROBE_CONTEXT,
+        top = in(reg) stack_top,
+        mode = in(reg) PROBE_STACK,
+        tmp = out(reg) _,
+    );
+    probe_result()
+}
+
+/// Calls addr and returns the error code of #PF if the fetch faulted.
+///
+/// # Safety
+/// addr should point to a ret instruction (0xC3) if it is executable.
+pub unsafe fn probe_execute(addr: u64) -> core::result::Result<(), u64> {
+    asm!(
+        "mov qword ptr [{ctx}], {mode}",
+        "call {addr}",
+        "mov qword ptr [{ctx}], 0",
+        ctx = in(reg) &PROBE_CONTEXT,
+        addr = in(reg) addr,
+        mode = in(reg) PROBE_FETCH,
+    );
+    probe_result()
+}
+
+/// Exception which ended the user code run by run_user_code()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserExit {
+    pub vector: usize,
+    pub error_code: u64,
+    /// CR2 for #PF, 0 otherwise
+    pub fault_addr: u64,
+    /// CR3 seen by the handler of the exception
+    pub cr3: u64,
+    /// Number of breakpoints (int3) resumed before the exit
+    pub num_breakpoints: u64,
+}
+
+// State of run_user_code(), shared with the exception handler
+#[repr(C)]
+struct UserModeContext {
+    // RSP saved by enter_user_mode to return to
+    kernel_rsp: AtomicU64,
+    active: AtomicBool,
+    vector: AtomicU64,
+    error_code: AtomicU64,
+    fault_addr: AtomicU64,
+    cr3: AtomicU64,
+    num_breakpoints: AtomicU64,
+}
+static USER_MODE_CONTEXT: UserModeContext = UserModeContext {
+    kernel_rsp: AtomicU64::new(0),
+    active: AtomicBool::new(false),
+    vector: AtomicU64::new(0),
+    error_code: AtomicU64::new(0),
+    fault_addr: AtomicU64::new(0),
+    cr3: AtomicU64::new(0),
+    num_breakpoints: AtomicU64::new(0),
+};
+
+extern "sysv64" {
+    fn enter_user_mode(rip: u64, rsp: u64, frame_stack: u64);
+    fn exit_user_mode();
+}
+
+// enter_user_mode(rip, rsp, frame_stack) builds the iretq frame on
+// frame_stack, which should be mapped in the user page table, and returns
+// when handle_user_exception() redirects an exception to exit_user_mode.
+global_asm!(
+    ".global enter_user_mode",
+    "enter_user_mode:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [rip + {ctx}], rsp",
+    "cli",
+    "mov rsp, rdx",
+    "push {user_ds}",
+    "push rsi",
+    // RFLAGS: interrupts are disabled in the user mode
+    "push 2",
+    "push {user_cs}",
+    "push rdi",
+    // Switch to the user page table if KPTI is enabled
+    "mov rax, [rip + KPTI_CR3 + 8]",
+    "test rax, rax",
+    "jz 2f",
+    "mov cr3, rax",
+    "2:",
+    // Do not leak the kernel values to the user
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+    ".global exit_user_mode",
+    "exit_user_mode:",
+    // iretq to ring 3 cleared the data segment registers, which had the DPL
+    // of 0
+    "mov ax, {kernel_ds}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    ctx = sym USER_MODE_CONTEXT,
+    user_ds = const USER_DS,
+    user_cs = const USER_CS,
+    kernel_ds = const KERNEL_DS,
+);
+
+/// Runs the code at rip in ring 3 with the stack at rsp, until it causes an
+/// exception other than a breakpoint (int3). Breakpoints are counted and
+/// resumed. Interrupts are disabled while the user code runs.
+///
+/// # Safety
+/// rip and rsp should be in user pages of the active address space.
+pub unsafe fn run_user_code(rip: u64, rsp: u64) -> UserExit {
+    let ctx = &USER_MODE_CONTEXT;
+    ctx.num_breakpoints.store(0, Ordering::SeqCst);
+    ctx.active.store(true, Ordering::SeqCst);
+    // The stack for the ring 0 entry is also mapped with KPTI
+    let rsp0 = read_tss()._rsp;
+    enter_user_mode(rip, rsp, rsp0[0]);
+    UserExit {
+        vector: ctx.vector.load(Ordering::SeqCst) as usize,
+        error_code: ctx.error_code.load(Ordering::SeqCst),
+        fault_addr: ctx.fault_addr.load(Ordering::SeqCst),
+        cr3: ctx.cr3.load(Ordering::SeqCst),
+        num_breakpoints: ctx.num_breakpoints.load(Ordering::SeqCst),
+    }
+}
+
+// Ends the user code run by run_user_code() by returning to exit_user_mode,
+// unless the exception is a breakpoint.
+fn handle_user_exception(info: &mut InterruptInfo, vector: usize) -> bool {
+    let ctx = &USER_MODE_CONTEXT;
+    if info.ctx.cs & 3 != 3 || !ctx.active.load(Ordering::SeqCst) {
+        return false;
+    }
+    ctx.cr3.store(read_cr3_value(), Ordering::SeqCst);
+    if vector == 3 {
+        // int3 is a trap, so the saved RIP points to the next instruction
+        ctx.num_breakpoints.fetch_add(1, Ordering::SeqCst);
+        return true;
+    }
+    ctx.vector.store(vector as u64, Ordering::SeqCst);
+    ctx.error_code.store(info.error_code, Ordering::SeqCst);
+    let fault_addr = if vector == 14 { read_cr2() } else { 0 };
+    ctx.fault_addr.store(fault_addr, Ordering::SeqCst);
+    ctx.active.store(false, Ordering::SeqCst);
+    info.ctx.rip = exit_user_mode as usize as u64;
+    info.ctx.cs = KERNEL_CS as u64;
+    info.ctx.rflags = 2;
+    info.ctx.rsp = ctx.kernel_rsp.load(Ordering::SeqCst);
+    info.ctx.ss = KERNEL_DS as u64;
+    true
+}
+
 #[no_mangle]
-extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
+extern "sysv64" fn inthandler(info: &mut InterruptInfo, index: usize) {
+    if index == 14 && catch_probe_fault(info) {
+        return;
+    }
+    if handle_user_exception(info, index) {
+        return;
+    }
+    if index == 8 {
+        // Logged here so that the cause is known even if it is recovered
+        if let Some((name, addr)) = find_overflowed_stack(info) {
+            error!("stack overflow in {name}");
+            if catch_stack_overflow(info, addr) {
+                return;
+            }
+        }
+    }
+    if try_exception_handlers(info, index) {
+        return;
+    }
+    if index == 13 || index == 14 {
+        if let Some(fixup) = search_exception_table(info.ctx.rip) {
+            info.ctx.rip = fixup;
+            return;
+        }
+    }
     error!("Interrupt Info: {:?}", info);
     error!("Exception {index:#04X}: ");
     match index {
+        1 => {
+            error!("Debug");
+        }
         3 => {
             error!("Breakpoint");
             return;
@@ -551,31 +2399,7 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
         14 => {
             error!("Page Fault");
             error!("CR2={:#018X}", read_cr2());
-            error!(
-                "Caused by: A {} mode {} on a {} page, page structures are {}",
-                if info.error_code & 0b0000_0100 != 0 {
-                    "user"
-                } else {
-                    "supervisor"
-                },
-                if info.error_code & 0b0001_0000 != 0 {
-                    "instruction fetch"
-                } else if info.error_code & 0b0010 != 0 {
-                    "data write"
-                } else {
-                    "data read"
-                },
-                if info.error_code & 0b0001 != 0 {
-                    "present"
-                } else {
-                    "non-present"
-                },
-                if info.error_code & 0b1000 != 0 {
-                    "invalid"
-                } else {
-                    "valid"
-                },
-            );
+            error!("Caused by: {}", PageFaultError(info.error_code));
         }
         _ => {
             error!("Not handled");
@@ -650,7 +2474,7 @@ const _: () = assert!(offset_of!(IdtrParameters, base) == 2);
 
 pub struct Idt {
     #[allow(dead_code)]
-    entries: Pin<Box<[IdtDescriptor; 0x100]>>,
+    entries: &'static [IdtDescriptor; 0x100],
 }
 impl Idt {
     pub fn new(segment_selector: u16) -> Self {
@@ -660,6 +2484,12 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             int_handler_unimplemented,
         ); 0x100];
+        entries[1] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint1,
+        );
         entries[3] = IdtDescriptor::new(
             segment_selector,
             1,
@@ -688,7 +2518,14 @@ impl Idt {
         );
         entries[14] = IdtDescriptor::new(
             segment_selector,
-            1,
+            // No IST for #PF, unlike the other exceptions:
+            // - A stack overflow into a guard page must escalate to a double
+            //   fault (on IST2). With an IST, the #PF would be delivered and
+            //   the overflow would look like an ordinary fault.
+            // - #PF can nest (e.g. a fault in copy_from_user() during the
+            //   handling of another fault). An IST would restart at the same
+            //   RSP and overwrite the frame of the outer fault.
+            0,
             IdtAttr::IntGateDPL0,
             interrupt_entrypoint14,
         );
@@ -699,7 +2536,7 @@ impl Idt {
             interrupt_entrypoint32,
         );
         let limit = size_of_val(&entries) as u16;
-        let entries = Box::pin(entries);
+        let entries = alloc_cpu_table(entries);
         let params = IdtrParameters {
             limit,
             base: entries.as_ptr(),
@@ -726,26 +2563,23 @@ struct TaskStateSegment64Inner {
 const _: () = assert!(size_of::<TaskStateSegment64Inner>() == 104);
 
 pub struct TaskStateSegment64 {
-    inner: Pin<Box<TaskStateSegment64Inner>>,
+    inner: &'static TaskStateSegment64Inner,
 }
 impl TaskStateSegment64 {
-    pub fn phys_addr(&self) -> u64 {
-        self.inner.as_ref().get_ref() as *const TaskStateSegment64Inner as u64
-    }
-    unsafe fn alloc_interrupt_stack() -> u64 {
-        const HANDLER_STACK_SIZE: usize = 64 * 1024;
-        let stack = Box::new([0u8; HANDLER_STACK_SIZE]);
-        let rsp = unsafe { stack.as_ptr().add(HANDLER_STACK_SIZE) as u64 };
-        core::mem::forget(stack);
-        // now, no one except us own the region since it is forgotten by the
-        // allocator ;)
-        rsp
+    pub fn addr(&self) -> u64 {
+        self.inner as *const TaskStateSegment64Inner as u64
+    }
+    fn alloc_interrupt_stack(name: &'static str) -> u64 {
+        // Each stack has an unmapped guard page below it, so an overflow
+        // causes a page fault instead of corrupting the neighbors.
+        alloc_kernel_stack(name).expect("Failed to allocate an interrupt stack")
     }
     pub fn new() -> Self {
-        let rsp0 = unsafe { Self::alloc_interrupt_stack() };
+        const IST_NAMES: [&str; 7] = ["IST1", "IST2", "IST3", "IST4", "IST5", "IST6", "IST7"];
+        let rsp0 = Self::alloc_interrupt_stack("RSP0");
         let mut ist = [0u64; 8];
-        for ist in ist[1..=7].iter_mut() {
-            *ist = unsafe { Self::alloc_interrupt_stack() };
+        for (ist, name) in ist[1..=7].iter_mut().zip(IST_NAMES) {
+            *ist = Self::alloc_interrupt_stack(name);
         }
         let tss64 = TaskStateSegment64Inner {
             _reserved0: 0,
@@ -755,18 +2589,113 @@ impl TaskStateSegment64 {
             _io_map_base_addr: 0,
         };
         let this = Self {
-            inner: Box::pin(tss64),
+            inner: alloc_cpu_table(tss64),
         };
-        info!("TSS64 created @ {:#X}", this.phys_addr(),);
+        info!("TSS64 created @ {:#X}", this.addr(),);
         this
     }
 }
+impl Default for TaskStateSegment64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Drop for TaskStateSegment64 {
     fn drop(&mut self) {
         panic!("TSS64 being dropped!");
     }
 }
 
+// Places a table referred to by the CPU at a random address chosen by KASLR
+fn alloc_cpu_table<T>(value: T) -> &'static mut T {
+    let addr = alloc_at_random_addr(size_of::<T>()).expect("Failed to allocate a CPU table");
+    let table = addr as *mut T;
+    unsafe {
+        table.write(value);
+        &mut *table
+    }
+}
+
+/// Reads the base address of the table loaded by LGDT (sgdt).
+pub fn read_gdtr_base() -> u64 {
+    let mut params = [0u8; 10];
+    unsafe {
+        asm!("sgdt [rax]",
+            in("rax") params.as_mut_ptr())
+    }
+    u64::from_le_bytes(params[2..10].try_into().unwrap())
+}
+/// Reads the base address of the table loaded by LIDT (sidt).
+pub fn read_idtr_base() -> u64 {
+    let mut params = [0u8; 10];
+    unsafe {
+        asm!("sidt [rax]",
+            in("rax") params.as_mut_ptr())
+    }
+    u64::from_le_bytes(params[2..10].try_into().unwrap())
+}
+
+/// Returns the virtual address ranges which should stay mapped while user
+/// code runs with KPTI, with the permissions they need: the interrupt entry
+/// code, the GDT, the IDT, the TSS and the stacks in the TSS. Should be
+/// called after init_exceptions().
+pub fn interrupt_entry_ranges() -> Vec<(Range<u64>, Protection)> {
+    let entrypoints: [unsafe extern "sysv64" fn(); 8] = [
+        inthandler_common,
+        interrupt_entrypoint1,
+        interrupt_entrypoint3,
+        interrupt_entrypoint6,
+        interrupt_entrypoint8,
+        interrupt_entrypoint13,
+        interrupt_entrypoint14,
+        interrupt_entrypoint32,
+    ];
+    // Each entry point is much smaller than a page
+    let mut ranges: Vec<(Range<u64>, Protection)> = entrypoints
+        .iter()
+        .map(|f| *f as usize as u64)
+        .chain([enter_user_mode as usize as u64])
+        .map(|addr| (addr..addr + PAGE_SIZE as u64, Protection::READ_EXECUTE))
+        .collect();
+    // The CPU does not write to the tables, since all the GDT entries have
+    // the accessed bit and the TSS is already loaded.
+    let gdt = read_gdtr_base();
+    ranges.push((gdt..gdt + size_of::<Gdt>() as u64, Protection::READ_ONLY));
+    let idt = read_idtr_base();
+    ranges.push((
+        idt..idt + size_of::<[IdtDescriptor; 0x100]>() as u64,
+        Protection::READ_ONLY,
+    ));
+    let tss = read_tss_base();
+    ranges.push((
+        tss..tss + size_of::<TaskStateSegment64Inner>() as u64,
+        Protection::READ_ONLY,
+    ));
+    let tss = read_tss();
+    let (rsp, ist) = (tss._rsp, tss._ist);
+    for top in rsp[..1].iter().chain(ist[1..].iter()) {
+        if *top != 0 {
+            ranges.push((top - KERNEL_STACK_SIZE as u64..*top, Protection::READ_WRITE));
+        }
+    }
+    ranges
+}
+
+/// Returns the base address of the TSS in the loaded GDT.
+pub fn read_tss_base() -> u64 {
+    let gdt = read_gdtr_base();
+    let desc = unsafe {
+        core::ptr::read_unaligned((gdt + TSS64_SEL as u64) as *const TaskStateSegment64Descriptor)
+    };
+    desc.base_low as u64
+        | (desc.base_mid_low as u64) << 16
+        | (desc.base_mid_high as u64) << 24
+        | (desc.base_high as u64) << 32
+}
+fn read_tss() -> TaskStateSegment64Inner {
+    unsafe { core::ptr::read_unaligned(read_tss_base() as *const TaskStateSegment64Inner) }
+}
+
 pub fn init_exceptions() -> (GdtWrapper, Idt) {
     let gdt = GdtWrapper::default();
     gdt.load();
@@ -785,6 +2714,8 @@ pub fn init_exceptions() -> (GdtWrapper, Idt) {
 pub const BIT_TYPE_DATA: u64 = 0b10u64 << 43;
 pub const BIT_TYPE_CODE: u64 = 0b11u64 << 43;
 
+// Set in advance so that the CPU does not write it on loading a selector
+pub const BIT_ACCESSED: u64 = 1u64 << 40;
 pub const BIT_PRESENT: u64 = 1u64 << 47;
 pub const BIT_CS_LONG_MODE: u64 = 1u64 << 53;
 pub const BIT_CS_READABLE: u64 = 1u64 << 53;
@@ -794,8 +2725,10 @@ pub const BIT_DPL3: u64 = 3u64 << 45;
 
 #[repr(u64)]
 enum GdtAttr {
-    KernelCode = BIT_TYPE_CODE | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_CS_READABLE,
-    KernelData = BIT_TYPE_DATA | BIT_PRESENT | BIT_DS_WRITABLE,
+    KernelCode = BIT_TYPE_CODE | BIT_ACCESSED | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_CS_READABLE,
+    KernelData = BIT_TYPE_DATA | BIT_ACCESSED | BIT_PRESENT | BIT_DS_WRITABLE,
+    UserCode = GdtAttr::KernelCode as u64 | BIT_DPL3,
+    UserData = GdtAttr::KernelData as u64 | BIT_DPL3,
 }
 
 #[allow(dead_code)]
@@ -808,6 +2741,9 @@ struct GdtrParameters {
 pub const KERNEL_CS: u16 = 1 << 3;
 pub const KERNEL_DS: u16 = 2 << 3;
 pub const TSS64_SEL: u16 = 3 << 3;
+// The TSS descriptor takes 2 entries. RPL is 3.
+pub const USER_DS: u16 = 5 << 3 | 3;
+pub const USER_CS: u16 = 6 << 3 | 3;
 
 #[allow(dead_code)]
 #[repr(C, packed)]
@@ -816,12 +2752,14 @@ pub struct Gdt {
     kernel_code_segment: GdtSegmentDescriptor,
     kernel_data_segment: GdtSegmentDescriptor,
     task_state_segment: TaskStateSegment64Descriptor,
+    user_data_segment: GdtSegmentDescriptor,
+    user_code_segment: GdtSegmentDescriptor,
 }
-const _: () = assert!(size_of::<Gdt>() == 40);
+const _: () = assert!(size_of::<Gdt>() == 56);
 
 #[allow(dead_code)]
 pub struct GdtWrapper {
-    inner: Pin<Box<Gdt>>,
+    inner: &'static Gdt,
     tss64: TaskStateSegment64,
 }
 
@@ -829,7 +2767,7 @@ impl GdtWrapper {
     pub fn load(&self) {
         let params = GdtrParameters {
             limit: (size_of::<Gdt>() - 1) as u16,
-            base: self.inner.as_ref().get_ref() as *const Gdt,
+            base: self.inner as *const Gdt,
         };
         info!("Loading GDT @ {:#018X}", params.base as u64);
         // SAFETY: This is safe since it is loading a valid GDT just constructed
@@ -852,10 +2790,14 @@ impl Default for GdtWrapper {
             null_segment: GdtSegmentDescriptor::null(),
             kernel_code_segment: GdtSegmentDescriptor::new(GdtAttr::KernelCode),
             kernel_data_segment: GdtSegmentDescriptor::new(GdtAttr::KernelData),
-            task_state_segment: TaskStateSegment64Descriptor::new(tss64.phys_addr()),
+            task_state_segment: TaskStateSegment64Descriptor::new(tss64.addr()),
+            user_data_segment: GdtSegmentDescriptor::new(GdtAttr::UserData),
+            user_code_segment: GdtSegmentDescriptor::new(GdtAttr::UserCode),
         };
-        let gdt = Box::pin(gdt);
-        GdtWrapper { inner: gdt, tss64 }
+        GdtWrapper {
+            inner: alloc_cpu_table(gdt),
+            tss64,
+        }
     }
 }
 
@@ -915,8 +2857,203 @@ pub unsafe fn write_cr3(table: *const PML4) {
             in("rax") table)
 }
 
+/// Switches to the table with the given PCID. If preserve_tlb is true, TLB
+/// entries tagged with the PCID are kept (requires PCID to be enabled).
+///
+/// # Safety
+/// Same as write_cr3().
+pub unsafe fn write_cr3_with_pcid(table: *const PML4, pcid: u16, preserve_tlb: bool) {
+    asm!("mov cr3, rax",
+            in("rax") cr3_value_with_pcid(table, pcid, preserve_tlb))
+}
+
+/// Returns the value written to CR3 by write_cr3_with_pcid(). The PCID and
+/// preserve_tlb are ignored if PCID is not enabled.
+pub fn cr3_value_with_pcid(table: *const PML4, pcid: u16, preserve_tlb: bool) -> u64 {
+    if !is_pcid_enabled() {
+        return table as u64;
+    }
+    let mut value = table as u64 | (pcid as u64 & PCID_MASK);
+    if preserve_tlb {
+        value |= CR3_NO_FLUSH;
+    }
+    value
+}
+
+/// Flushes non-global TLB entries of the current PCID.
 pub fn flush_tlb() {
     unsafe {
-        write_cr3(read_cr3());
+        // Reloading CR3 (with the current PCID) flushes the TLB
+        asm!("mov cr3, rax",
+            in("rax") read_cr3_value())
+    }
+}
+
+pub fn flush_tlb_page(virt: u64) {
+    unsafe {
+        asm!("invlpg [rax]",
+            in("rax") virt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Translated addresses are never dereferenced
+    const PAGE_PHYS: u64 = 0x0000_0012_3450_0000;
+    const HUGE_PAGE_PHYS: u64 = 0x0000_0012_3460_0000;
+    const USER_READ_WRITE: Protection = Protection {
+        writable: true,
+        executable: false,
+        user: true,
+    };
+
+    fn mapped_4k(phys: u64) -> Result<TranslationResult> {
+        Ok(TranslationResult::PageMapped4K { phys })
+    }
+
+    #[test]
+    fn pml5_maps_beyond_the_first_entry() {
+        let mut pml5 = PML5::new_for_test();
+        // Canonical only with 5-level paging: PML5 entries 1 and 510
+        for virt in [1 << 48, 0xFFFE_0000_0000_0000] {
+            pml5.create_mapping(virt, virt + 0x2000, PAGE_PHYS, PageAttr::ReadWriteKernel)
+                .expect("create_mapping failed");
+            assert_eq!(pml5.translate(virt + 0x1234), mapped_4k(PAGE_PHYS + 0x1234));
+        }
+        assert!(!pml5.entries()[0].is_present());
+        assert!(pml5.entries()[1].is_present());
+        assert!(pml5.entries()[510].is_present());
+        // Crosses the boundary of PML5 entries 2 and 3
+        let virt = (3 << 48) - 0x1000;
+        pml5.create_mapping(virt, virt + 0x2000, PAGE_PHYS, PageAttr::ReadWriteKernel)
+            .expect("create_mapping failed");
+        assert_eq!(pml5.translate(virt), mapped_4k(PAGE_PHYS));
+        assert_eq!(pml5.translate(3 << 48), mapped_4k(PAGE_PHYS + 0x1000));
+        // Bits 63:57 should be the copies of bit 56
+        assert!(pml5
+            .create_mapping(
+                1 << 56,
+                (1 << 56) + 0x1000,
+                PAGE_PHYS,
+                PageAttr::ReadWriteKernel
+            )
+            .is_err());
+        // A PML4 root is still limited to 48 bits
+        let mut pml4 = PML4::new_for_test();
+        assert!(pml4
+            .create_mapping(
+                1 << 47,
+                (1 << 47) + 0x1000,
+                PAGE_PHYS,
+                PageAttr::ReadWriteKernel
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn pml5_walks_leaves_in_both_halves() {
+        let mut pml5 = PML5::new_for_test();
+        let lower = 0x0000_8000_0000_0000;
+        let upper = 0xFF00_0000_0000_0000;
+        for virt in [lower, upper] {
+            pml5.create_mapping(virt, virt + 0x1000, PAGE_PHYS, PageAttr::ReadWriteKernel)
+                .expect("create_mapping failed");
+        }
+        let mut leaves = Vec::new();
+        PageMapper::for_each_leaf(pml5.as_mut(), 0..u64::MAX, &mut |leaf| {
+            leaves.push(leaf.virt)
+        });
+        assert_eq!(leaves, [lower, upper]);
+    }
+
+    #[test]
+    fn cloned_pml4_does_not_share_cleared_entries() {
+        let mut kernel = PML5::new_for_test();
+        let shared = 0x1000;
+        let private = 0x0000_4000_0000_0000;
+        kernel
+            .create_mapping(
+                shared,
+                shared + 0x1000,
+                PAGE_PHYS,
+                PageAttr::ReadWriteKernel,
+            )
+            .expect("create_mapping failed");
+        let mut space = kernel.clone_top_level();
+        space
+            .clone_pml4(0)
+            .expect("clone_pml4 failed")
+            .clear_entries(1..512);
+        space
+            .create_mapping(
+                private,
+                private + 0x1000,
+                PAGE_PHYS,
+                PageAttr::ReadWriteKernel,
+            )
+            .expect("create_mapping failed");
+        assert_eq!(space.translate(shared), mapped_4k(PAGE_PHYS));
+        assert_eq!(space.translate(private), mapped_4k(PAGE_PHYS));
+        assert!(kernel.translate(private).is_err());
+        assert_ne!(space.entries()[0].addr(), kernel.entries()[0].addr());
+        // Frees the private tables, leaving the shared ones to the kernel
+        space.pml4_mut(0).expect("No PML4").clear_entries(0..1);
+        space.free_lower_tables(0..1);
+        assert_eq!(kernel.translate(shared), mapped_4k(PAGE_PHYS));
+    }
+
+    #[test]
+    fn protect_clears_user_bits_of_unused_walks() {
+        let mut pml4 = PML4::new_for_test();
+        let root = pml4.as_ref() as *const PML4 as u64;
+        let virt = 0x4000_0000_0000;
+        let index = virt as usize >> 39 & 0x1FF;
+        pml4.create_mapping(virt, virt + 0x2000, PAGE_PHYS, PageAttr::ReadWriteKernel)
+            .expect("create_mapping failed");
+        assert!(!pml4.is_user_accessible(virt, false));
+        pml4.protect_in(root, virt..virt + 0x1000, USER_READ_WRITE, true)
+            .expect("protect failed");
+        assert!(pml4.is_user_accessible(virt, true));
+        assert!(!pml4.is_user_accessible(virt + 0x1000, false));
+        assert!(pml4.entries()[index].is_user());
+        // A kernel page mapped later keeps the walk for the user page
+        pml4.create_mapping(
+            virt + 0x2000,
+            virt + 0x3000,
+            PAGE_PHYS,
+            PageAttr::ReadWriteKernel,
+        )
+        .expect("create_mapping failed");
+        assert!(pml4.is_user_accessible(virt, true));
+        assert!(!pml4.is_user_accessible(virt + 0x2000, false));
+        // The last user page is gone, so is the walk
+        pml4.protect_in(root, virt..virt + 0x1000, Protection::READ_WRITE, true)
+            .expect("protect failed");
+        assert!(!pml4.is_user_accessible(virt, false));
+        assert!(!pml4.entries()[index].is_user());
+    }
+
+    #[test]
+    fn protect_allows_user_access_to_a_page_in_a_huge_page() {
+        let mut pml4 = PML4::new_for_test();
+        let root = pml4.as_ref() as *const PML4 as u64;
+        let virt = 0x4000_0000_0000;
+        pml4.create_mapping(
+            virt,
+            virt + HUGE_PAGE_SIZE,
+            HUGE_PAGE_PHYS,
+            PageAttr::ReadWriteKernel,
+        )
+        .expect("create_mapping failed");
+        pml4.collapse_huge_page(virt, HUGE_PAGE_PHYS, PageAttr::ReadWriteKernel as u64)
+            .expect("collapse_huge_page failed");
+        let page = virt + 0x1000;
+        pml4.protect_in(root, page..page + 0x1000, USER_READ_WRITE, true)
+            .expect("protect failed");
+        assert!(pml4.is_user_accessible(page, true));
+        assert!(!pml4.is_user_accessible(virt, false));
+        assert!(!pml4.is_user_accessible(page + 0x1000, false));
     }
 }